@@ -1,3 +1,7 @@
 pub const LOGIN_INVALID: usize = 300;
+pub const REGISTER_INVALID: usize = 301;
+pub const NETWORK_INVALID: usize = 302;
+pub const UPGRADE_INVALID: usize = 303;
+pub const PEERS_INVALID: usize = 304;
 pub const DAEMON_ERROR: usize = 400;
 pub const CLOSED_CHANNEL: usize = 600;
@@ -1,13 +1,34 @@
 use zbus::Result;
 
+/// A peer as the daemon lists it: `(username, address, state, connected_secs)`, where `state` is
+/// `online` or `idle`
+pub type Peer = (String, String, String, u64);
+
 #[zbus::proxy(
     interface = "me.piguy.lanshare.daemon1",
     default_service = "me.piguy.lanshare.daemon",
     default_path = "/me/piguy/lanshare/daemon"
 )]
 pub trait Daemon {
-    async fn login(&self, username: &str) -> Result<u64>;
+    async fn register(&self, username: &str, password: &str, invite: &str) -> Result<u64>;
+    async fn login(
+        &self,
+        username: &str,
+        password: &str,
+        network: &str,
+        address: &str,
+    ) -> Result<u64>;
+    async fn refresh(&self) -> Result<u64>;
+    async fn logout(&self) -> Result<u64>;
     async fn int_up(&self) -> Result<u64>;
     async fn int_down(&self) -> Result<u64>;
     async fn upgrade(&self) -> Result<u64>;
+    async fn create_network(&self, name: &str, subnet: &str) -> Result<u64>;
+    async fn invite_to_network(&self, name: &str, username: &str) -> Result<u64>;
+    async fn join_network(&self, name: &str) -> Result<u64>;
+    async fn leave_network(&self, name: &str) -> Result<u64>;
+    async fn list_peers(&self) -> Result<(u64, Vec<Peer>)>;
+    /// The other peers as `(username, address)`, as the daemon last heard from the relay
+    #[zbus(property)]
+    fn peers(&self) -> Result<Vec<(String, String)>>;
 }
@@ -35,17 +35,36 @@ async fn repl(proxy: DaemonProxy<'_>) {
             error!("error when reading stdin: {error}");
         }
 
-        let res = match buf.as_str().trim() {
-            "up" => proxy.int_up().await,
-            "down" => proxy.int_down().await,
-            "upgrade" => proxy.upgrade().await,
-            name if name.starts_with("name") => {
-                let name = &name[5..];
-                proxy.login(name).await
+        let args: Vec<&str> = buf.split_whitespace().collect();
+
+        let res = match args.as_slice() {
+            ["up"] => proxy.int_up().await,
+            ["down"] => proxy.int_down().await,
+            ["upgrade"] => proxy.upgrade().await,
+            ["register", name, password] => proxy.register(name, password, "").await,
+            ["register", name, password, invite] => proxy.register(name, password, invite).await,
+            ["login", name, password] => proxy.login(name, password, "", "").await,
+            ["login", name, password, network] => proxy.login(name, password, network, "").await,
+            ["login", name, password, network, address] => {
+                proxy.login(name, password, network, address).await
             }
-            "quit" => break,
+            ["create", name] => proxy.create_network(name, "").await,
+            ["create", name, subnet] => proxy.create_network(name, subnet).await,
+            ["invite", name, username] => proxy.invite_to_network(name, username).await,
+            ["join", name] => proxy.join_network(name).await,
+            ["leave", name] => proxy.leave_network(name).await,
+            ["refresh"] => proxy.refresh().await,
+            ["logout"] => proxy.logout().await,
+            ["peers"] => list_peers(&proxy).await,
+            ["known"] => known_peers(&proxy).await,
+            ["quit"] => break,
             _ => {
-                println!("enter command 'up', 'down', 'name <name>', 'upgrade' or 'quit'");
+                println!(
+                    "enter command 'up', 'down', 'register <name> <password> [invite]', \
+                     'login <name> <password> [network [address]]', 'create <network> [subnet]', \
+                     'invite <network> <user>', 'join <network>', 'leave <network>', 'refresh', \
+                     'logout', 'upgrade', 'peers', 'known' or 'quit'"
+                );
                 continue;
             }
         };
@@ -57,3 +76,23 @@ async fn repl(proxy: DaemonProxy<'_>) {
         };
     }
 }
+
+/// Prints the peers the daemon knows of from the relay's events, without it asking the relay
+async fn known_peers(proxy: &DaemonProxy<'_>) -> Result<u64> {
+    for (username, address) in proxy.peers().await? {
+        println!("{address:<15} {username}");
+    }
+
+    Ok(0)
+}
+
+/// Prints everyone in our network, returning the daemon's code like the other commands
+async fn list_peers(proxy: &DaemonProxy<'_>) -> Result<u64> {
+    let (code, peers) = proxy.list_peers().await?;
+
+    for (username, address, state, connected_secs) in peers {
+        println!("{address:<15} {username:<16} {state:<6} connected {connected_secs}s");
+    }
+
+    Ok(code)
+}
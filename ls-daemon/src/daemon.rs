@@ -2,7 +2,7 @@ use std::net::Ipv4Addr;
 
 use tokio::sync::mpsc;
 
-use crate::SERVER_ADDR;
+use crate::{SERVER_ADDR, SERVER_CERT, SERVER_NAME};
 use errors::*;
 use relay_server::client::*;
 
@@ -16,21 +16,50 @@ pub enum DaemonEvent {
         netmask: Ipv4Addr,
     },
     Down,
+    /// an operator moved our address, the interface follows if it is up
+    Readdressed {
+        address: Ipv4Addr,
+        netmask: Ipv4Addr,
+    },
     RemoteAdd {
-        bi: BidirectionalStream,
+        tunnel: Tunnel,
     },
     #[allow(unused)]
     RemoteDel,
 }
 
 pub trait Daemon {
+    async fn register(&self, username: &str, password: &str, invite: &str) -> usize;
+
     // this function is expected to modify the login state
-    async fn login(&mut self, username: &str) -> usize;
+    async fn login(
+        &mut self,
+        username: &str,
+        password: &str,
+        network: &str,
+        address: &str,
+    ) -> usize;
+    async fn refresh(&mut self) -> usize;
+    async fn logout(&mut self) -> usize;
 
     async fn int_up(&self) -> usize;
     async fn int_down(&self) -> usize;
 
-    async fn upgrade(&self) -> usize;
+    // this function is expected to start watching the network's peers
+    async fn upgrade(&mut self) -> usize;
+
+    async fn create_network(&self, name: &str, subnet: &str) -> usize;
+    async fn invite_to_network(&self, name: &str, username: &str) -> usize;
+    async fn join_network(&self, name: &str) -> usize;
+    async fn leave_network(&self, name: &str) -> usize;
+
+    /// Everyone connected to our network as `(username, address, state, connected_secs)`,
+    /// where `state` is `online` or `idle`
+    async fn list_peers(&self) -> (usize, Vec<(String, String, String, u64)>);
+
+    /// The other peers in our network as `(username, address)`, as far as the relay's peer
+    /// events tell. Kept up to date without asking the relay, empty until `upgrade`.
+    async fn peers(&self) -> Vec<(String, String)>;
 
     #[instrument(skip(tx))]
     async fn send_event(tx: &mpsc::Sender<DaemonEvent>, event: DaemonEvent) -> usize {
@@ -46,11 +75,20 @@ pub trait Daemon {
 #[cfg(target_os = "linux")]
 mod dbus {
     use std::{
+        borrow::Cow,
+        collections::HashMap,
         net::{Ipv4Addr, SocketAddr},
+        path::PathBuf,
         str::FromStr,
+        sync::{Arc, Mutex},
     };
 
-    use zbus::interface;
+    use tokio::task::AbortHandle;
+    use zbus::{
+        fdo, interface,
+        object_server::{Interface, SignalEmitter},
+        zvariant::Value,
+    };
 
     use crate::error::Result;
 
@@ -58,7 +96,8 @@ mod dbus {
 
     #[derive(Debug)]
     pub struct LoginCfg {
-        address: Ipv4Addr,
+        /// shared with `watcher`, which hears when the relay moves us
+        address: Arc<Mutex<Ipv4Addr>>,
         netmask: Ipv4Addr,
         token: String,
     }
@@ -68,30 +107,173 @@ mod dbus {
         tx: mpsc::Sender<DaemonEvent>,
         relay_client: Client,
         login_cfg: Option<LoginCfg>,
+        /// the other peers in our network, kept up to date by `watcher`
+        peers: Arc<Mutex<PeerTable>>,
+        watcher: Option<AbortHandle>,
+        /// where the daemon is served, for announcing changes to `Peers`
+        emitter: SignalEmitter<'static>,
     }
 
     impl DbusDaemon {
-        pub async fn try_new(tx: mpsc::Sender<DaemonEvent>) -> Result<Self> {
+        pub async fn try_new(
+            tx: mpsc::Sender<DaemonEvent>,
+            emitter: SignalEmitter<'static>,
+        ) -> Result<Self> {
             let server_addr = SocketAddr::from_str(SERVER_ADDR).expect("infailable");
-            let relay_client = Client::try_new(server_addr).await?;
+            let relay_client = Client::try_new(ClientConfig {
+                server_addr,
+                server_name: SERVER_NAME.to_string(),
+                trust_anchor: PathBuf::from(SERVER_CERT),
+                datagrams: true,
+                broadcast: true,
+            })
+            .await?;
             Ok(Self {
                 tx,
                 relay_client,
                 login_cfg: None,
+                peers: Arc::default(),
+                watcher: None,
+                emitter,
             })
         }
+
+        /// Follows the peers of the session's network from scratch, instead of whatever the
+        /// last session watched
+        async fn watch_peers(&mut self, token: &str) {
+            self.stop_watching().await;
+            let Some(LoginCfg {
+                address, netmask, ..
+            }) = &self.login_cfg
+            else {
+                return;
+            };
+            let us = Us {
+                address: address.clone(),
+                netmask: *netmask,
+                tx: self.tx.clone(),
+            };
+
+            let events = match self.relay_client.watch_peers(token).await {
+                Ok(value) => value,
+                Err(error) => return error!("could not watch peers: {error}"),
+            };
+
+            let task = tokio::spawn(follow_peers(
+                events,
+                us,
+                self.peers.clone(),
+                self.emitter.clone(),
+            ));
+            self.watcher = Some(task.abort_handle());
+        }
+
+        async fn stop_watching(&mut self) {
+            if let Some(watcher) = self.watcher.take() {
+                watcher.abort();
+            }
+            self.peers.lock().expect("peer table lock poisoned").clear();
+            announce_peers(&self.emitter, &self.peers).await;
+        }
+    }
+
+    /// `peers` the way the `Peers` property hands them out, ordered by address
+    fn peer_list(peers: &Mutex<PeerTable>) -> Vec<(String, String)> {
+        let peers = peers.lock().expect("peer table lock poisoned");
+        let mut list: Vec<_> = peers.iter().collect();
+        list.sort();
+
+        list.into_iter()
+            .map(|(address, username)| (username.to_string(), address.to_string()))
+            .collect()
+    }
+
+    /// Tells D-Bus clients the `Peers` property is now `peers`
+    async fn announce_peers(emitter: &SignalEmitter<'_>, peers: &Mutex<PeerTable>) {
+        let changed = HashMap::from([("Peers", Value::from(peer_list(peers)))]);
+        let res = fdo::Properties::properties_changed(
+            emitter,
+            DbusDaemon::name(),
+            changed,
+            Cow::Borrowed(&[]),
+        )
+        .await;
+
+        if let Err(error) = res {
+            warn!("could not announce the new peers: {error}");
+        }
+    }
+
+    /// What `follow_peers` needs to follow us when the relay moves our address
+    #[derive(Debug)]
+    struct Us {
+        address: Arc<Mutex<Ipv4Addr>>,
+        netmask: Ipv4Addr,
+        tx: mpsc::Sender<DaemonEvent>,
+    }
+
+    /// Applies every event to `peers`, until the relay ends the stream. A move of our own
+    /// address goes to `us` instead.
+    #[instrument(skip_all)]
+    async fn follow_peers(
+        mut events: PeerEvents,
+        us: Us,
+        peers: Arc<Mutex<PeerTable>>,
+        emitter: SignalEmitter<'static>,
+    ) {
+        loop {
+            let event = match events.next().await {
+                Ok(Some(value)) => value,
+                Ok(None) => return debug!("relay stopped sending peer events"),
+                Err(error) => return error!("lost the peer events: {error}"),
+            };
+
+            match &event {
+                PeerEvent::Joined { username, address } => info!("{username} joined at {address}"),
+                PeerEvent::Left { username, address } => info!("{username} at {address} left"),
+                PeerEvent::Moved { from, to, .. }
+                    if *from == *us.address.lock().expect("address lock poisoned") =>
+                {
+                    info!("the relay moved us from {from} to {to}");
+                    *us.address.lock().expect("address lock poisoned") = *to;
+                    let event = DaemonEvent::Readdressed {
+                        address: *to,
+                        netmask: us.netmask,
+                    };
+                    DbusDaemon::send_event(&us.tx, event).await;
+                    continue;
+                }
+                PeerEvent::Moved { username, from, to } => {
+                    info!("{username} moved from {from} to {to}")
+                }
+            }
+            peers
+                .lock()
+                .expect("peer table lock poisoned")
+                .apply(&event);
+            announce_peers(&emitter, &peers).await;
+        }
     }
 
     #[interface(name = "me.piguy.lanshare.daemon1")]
     impl Daemon for DbusDaemon {
         #[instrument(skip(self))]
-        async fn upgrade(&self) -> usize {
+        async fn upgrade(&mut self) -> usize {
             if let Some(LoginCfg { token, .. }) = &self.login_cfg {
+                let token = token.clone();
                 let client = &self.relay_client;
                 // TODO: send this to the tun controller
-                let bi = client.upgrade_conn(token).await.unwrap();
-                debug!(?bi);
-                Self::send_event(&self.tx, DaemonEvent::RemoteAdd { bi }).await;
+                let tunnel = match client.upgrade_conn(&token).await {
+                    Ok(value) => value,
+                    Err(error) => {
+                        error!("could not open tunnel: {error}");
+                        return UPGRADE_INVALID;
+                    }
+                };
+                debug!(?tunnel);
+                Self::send_event(&self.tx, DaemonEvent::RemoteAdd { tunnel }).await;
+
+                self.watch_peers(&token).await;
             } else {
                 return 1;
             }
@@ -99,11 +281,43 @@ mod dbus {
             0
         }
 
-        #[instrument(skip(self))]
-        async fn login(&mut self, username: &str) -> usize {
+        /// `invite` may be empty on relays that do not require one
+        #[instrument(skip(self, password))]
+        async fn register(&self, username: &str, password: &str, invite: &str) -> usize {
+            let client = &self.relay_client;
+            let invite = Some(invite).filter(|invite| !invite.is_empty());
+
+            if let Err(error) = client.register(username, password, invite).await {
+                error!("could not register user: {error}");
+                return REGISTER_INVALID;
+            }
+
+            0
+        }
+
+        /// `network` may be empty for the default network, `address` to let the relay pick one
+        #[instrument(skip(self, password))]
+        async fn login(
+            &mut self,
+            username: &str,
+            password: &str,
+            network: &str,
+            address: &str,
+        ) -> usize {
             let client = &self.relay_client;
+            let network = Some(network).filter(|network| !network.is_empty());
+            let address = match address {
+                "" => None,
+                address => match address.parse() {
+                    Ok(address) => Some(address),
+                    Err(error) => {
+                        error!("invalid address: {error}");
+                        return LOGIN_INVALID;
+                    }
+                },
+            };
 
-            let login_cfg = match client.login(username).await {
+            let login_cfg = match client.login(username, password, network, address).await {
                 Ok(value) => value,
                 Err(error) => {
                     error!("could not login user: {error}");
@@ -111,8 +325,14 @@ mod dbus {
                 }
             };
 
+            if let Some(gateway) = login_cfg.gateway {
+                info!(
+                    "logged in as {}, the relay answers ping at {gateway}",
+                    login_cfg.address
+                );
+            }
             self.login_cfg = Some(LoginCfg {
-                address: login_cfg.address,
+                address: Arc::new(Mutex::new(login_cfg.address)),
                 netmask: login_cfg.netmask,
                 token: login_cfg.token,
             });
@@ -120,13 +340,156 @@ mod dbus {
             0
         }
 
+        #[instrument(skip(self))]
+        async fn refresh(&mut self) -> usize {
+            let Some(login_cfg) = &mut self.login_cfg else {
+                return LOGIN_INVALID;
+            };
+
+            match self.relay_client.refresh(&login_cfg.token).await {
+                Ok(value) => login_cfg.token = value.token,
+                Err(error) => {
+                    error!("could not refresh token: {error}");
+                    return LOGIN_INVALID;
+                }
+            }
+
+            0
+        }
+
+        #[instrument(skip(self))]
+        async fn logout(&mut self) -> usize {
+            let Some(login_cfg) = self.login_cfg.take() else {
+                return LOGIN_INVALID;
+            };
+            self.stop_watching().await;
+
+            // the relay tears down our route itself, so only the interface is left to us
+            if let Err(error) = self.relay_client.logout(&login_cfg.token).await {
+                error!("could not log out: {error}");
+            }
+
+            Self::send_event(&self.tx, DaemonEvent::Down).await
+        }
+
+        /// `subnet` may be empty to lease from the relay's own subnet
+        #[instrument(skip(self))]
+        async fn create_network(&self, name: &str, subnet: &str) -> usize {
+            let Some(LoginCfg { token, .. }) = &self.login_cfg else {
+                return LOGIN_INVALID;
+            };
+
+            let subnet = match subnet {
+                "" => None,
+                subnet => match subnet.parse() {
+                    Ok(subnet) => Some(subnet),
+                    Err(error) => {
+                        error!("invalid subnet: {error}");
+                        return NETWORK_INVALID;
+                    }
+                },
+            };
+
+            if let Err(error) = self.relay_client.create_network(token, name, subnet).await {
+                error!("could not create network: {error}");
+                return NETWORK_INVALID;
+            }
+
+            0
+        }
+
+        #[instrument(skip(self))]
+        async fn invite_to_network(&self, name: &str, username: &str) -> usize {
+            let Some(LoginCfg { token, .. }) = &self.login_cfg else {
+                return LOGIN_INVALID;
+            };
+
+            if let Err(error) = self
+                .relay_client
+                .invite_to_network(token, name, username)
+                .await
+            {
+                error!("could not invite to network: {error}");
+                return NETWORK_INVALID;
+            }
+
+            0
+        }
+
+        #[instrument(skip(self))]
+        async fn join_network(&self, name: &str) -> usize {
+            let Some(LoginCfg { token, .. }) = &self.login_cfg else {
+                return LOGIN_INVALID;
+            };
+
+            if let Err(error) = self.relay_client.join_network(token, name).await {
+                error!("could not join network: {error}");
+                return NETWORK_INVALID;
+            }
+
+            0
+        }
+
+        #[instrument(skip(self))]
+        async fn leave_network(&self, name: &str) -> usize {
+            let Some(LoginCfg { token, .. }) = &self.login_cfg else {
+                return LOGIN_INVALID;
+            };
+
+            if let Err(error) = self.relay_client.leave_network(token, name).await {
+                error!("could not leave network: {error}");
+                return NETWORK_INVALID;
+            }
+
+            0
+        }
+
+        #[instrument(skip(self))]
+        async fn list_peers(&self) -> (usize, Vec<(String, String, String, u64)>) {
+            let Some(LoginCfg { token, .. }) = &self.login_cfg else {
+                return (LOGIN_INVALID, Vec::new());
+            };
+
+            let peers = match self.relay_client.list_peers(token).await {
+                Ok(value) => value,
+                Err(error) => {
+                    error!("could not list peers: {error}");
+                    return (PEERS_INVALID, Vec::new());
+                }
+            };
+
+            let peers = peers
+                .into_iter()
+                .map(|peer| {
+                    let state = match peer.state {
+                        PeerState::Online => "online",
+                        PeerState::Idle => "idle",
+                    };
+                    let address = peer.address.to_string();
+                    (
+                        peer.username,
+                        address,
+                        state.to_string(),
+                        peer.connected_secs,
+                    )
+                })
+                .collect();
+
+            (0, peers)
+        }
+
+        #[zbus(property)]
+        async fn peers(&self) -> Vec<(String, String)> {
+            peer_list(&self.peers)
+        }
+
         #[instrument(skip(self))]
         async fn int_up(&self) -> usize {
             if let Some(LoginCfg {
                 address, netmask, ..
             }) = &self.login_cfg
             {
-                let address = *address;
+                let address = *address.lock().expect("address lock poisoned");
                 let netmask = *netmask;
                 Self::send_event(&self.tx, DaemonEvent::Up { address, netmask }).await
             } else {
@@ -10,15 +10,12 @@ use std::{
     sync::Arc,
 };
 
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    sync::{
-        Mutex,
-        mpsc::{self, error::TryRecvError},
-    },
+use tokio::sync::{
+    Mutex,
+    mpsc::{self, error::TryRecvError},
 };
 use tun::TunEvent;
-use zbus::connection;
+use zbus::{connection, object_server::SignalEmitter};
 
 use crate::{
     daemon::{DaemonEvent, DbusDaemon},
@@ -26,6 +23,12 @@ use crate::{
 };
 
 pub const SERVER_ADDR: &str = "192.168.0.26:4433";
+/// Name the relay's certificate was issued for
+pub const SERVER_NAME: &str = "localhost";
+/// Copy of the relay's certificate, see `cert_path` in the relay's config
+pub const SERVER_CERT: &str = "/etc/lanshare/relay.crt";
+/// Where the daemon is served on the system bus
+const DAEMON_PATH: &str = "/me/piguy/lanshare/daemon";
 
 #[tokio::main]
 async fn main() -> error::Result {
@@ -45,14 +48,16 @@ async fn main() -> error::Result {
     //   - XPC for SoyOS
     #[cfg(target_os = "linux")]
     let _conn = {
-        let daemon = DbusDaemon::try_new(tx).await?;
-
         let conn = connection::Builder::system()?
             .name("me.piguy.lanshare.daemon")?
-            .serve_at("/me/piguy/lanshare/daemon", daemon)?
             .build()
             .await?;
 
+        // the daemon announces changes on its own, so it needs to know where it lives
+        let emitter = SignalEmitter::new(&conn, DAEMON_PATH)?.into_owned();
+        let daemon = DbusDaemon::try_new(tx, emitter).await?;
+        conn.object_server().at(DAEMON_PATH, daemon).await?;
+
         info!("listening on dbus");
 
         conn
@@ -109,11 +114,9 @@ async fn device_task(mut rx: mpsc::Receiver<TunEvent>) {
         match recv.clone() {
             Some(recv) => {
                 tokio::spawn(async move {
-                    let mut buf = [0; 4096];
                     let mut recv = recv.lock().await;
-                    while let Ok(amount) = recv.read(&mut buf).await {
-                        let pkt = &buf[..amount];
-                        if let Err(error) = device_write.write_all(pkt) {
+                    while let Ok(Some(pkt)) = recv.recv().await {
+                        if let Err(error) = device_write.write_all(&pkt) {
                             error!(?error, "error when writing to tun device: {error}");
                         }
                     }
@@ -130,17 +133,11 @@ async fn device_task(mut rx: mpsc::Receiver<TunEvent>) {
 
             if let Some(send) = send.clone() {
                 let mut send = send.lock().await;
-                let mut pkt = &buf[..amount];
+                let pkt = &buf[..amount];
 
                 debug!(?pkt, "maybe sending packet");
-                match send.write_buf(&mut pkt).await {
-                    Ok(sent_amount) if sent_amount != amount => {
-                        warn!(?sent_amount, ?amount, "packet of incorrect len sent")
-                    }
-                    Err(error) => {
-                        error!(?error, "{error}");
-                    }
-                    Ok(_) => (),
+                if let Err(error) = send.send(pkt).await {
+                    error!(?error, "{error}");
                 }
             }
 
@@ -1,4 +1,4 @@
-use relay_server::client::BidirectionalStream;
+use relay_server::client::Tunnel;
 use tokio::sync::mpsc::{self, error::SendError};
 use tun::Configuration as TunConfig;
 
@@ -8,7 +8,7 @@ pub const DEFAULT_MTU: u16 = 1500;
 
 #[derive(Debug)]
 pub enum TunEvent {
-    SetRemote(Option<BidirectionalStream>),
+    SetRemote(Option<Tunnel>),
     Up(TunConfig),
     Down,
 }
@@ -16,6 +16,8 @@ pub enum TunEvent {
 #[derive(Debug)]
 pub struct TunController {
     config: TunConfig,
+    /// whether the interface was last brought up or down
+    up: bool,
 }
 
 impl TunController {
@@ -24,7 +26,7 @@ impl TunController {
 
         config.tun_name("lanshare0").mtu(DEFAULT_MTU).up();
 
-        TunController { config }
+        TunController { config, up: false }
     }
 
     #[instrument(skip(self, rx, tun_tx))]
@@ -47,8 +49,8 @@ impl TunController {
     async fn handle_event(&mut self, event: DaemonEvent, tun_tx: &mut mpsc::Sender<TunEvent>) {
         trace!("TunController recieved event");
         match event {
-            DaemonEvent::RemoteAdd { bi } => {
-                handle_send_res(tun_tx.send(TunEvent::SetRemote(Some(bi))).await);
+            DaemonEvent::RemoteAdd { tunnel } => {
+                handle_send_res(tun_tx.send(TunEvent::SetRemote(Some(tunnel))).await);
             }
             DaemonEvent::RemoteDel => {
                 handle_send_res(tun_tx.send(TunEvent::SetRemote(None)).await);
@@ -56,11 +58,21 @@ impl TunController {
             DaemonEvent::Up { address, netmask } => {
                 let mut config = self.config.clone();
                 config.address(address).netmask(netmask);
+                self.up = true;
                 handle_send_res(tun_tx.send(TunEvent::Up(config)).await);
             }
             DaemonEvent::Down => {
+                self.up = false;
                 handle_send_res(tun_tx.send(TunEvent::Down).await);
             }
+            // a down interface picks the new address up from the login the next time it goes up
+            DaemonEvent::Readdressed { address, netmask } if self.up => {
+                let mut config = self.config.clone();
+                config.address(address).netmask(netmask);
+                handle_send_res(tun_tx.send(TunEvent::Down).await);
+                handle_send_res(tun_tx.send(TunEvent::Up(config)).await);
+            }
+            DaemonEvent::Readdressed { .. } => (),
         }
 
         trace!("TunController event handeled");
@@ -0,0 +1,11 @@
+//! Generates the control protocol's and the admin socket's types from `proto/`. The schemas are
+//! compiled in Rust, so building does not need `protoc`.
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto");
+
+    let descriptors = protox::compile(["lanshare.proto", "admin.proto"], ["proto"])?;
+    prost_build::Config::new().compile_fds(descriptors)?;
+
+    Ok(())
+}
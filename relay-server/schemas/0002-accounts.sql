@@ -0,0 +1,7 @@
+alter table users add column password_hash varchar;
+
+create table invites (
+    code varchar primary key,
+    created_at integer not null,
+    used_by integer references users(id)
+);
@@ -0,0 +1,26 @@
+-- tokens move out of the users table, so a user can hold sessions that expire on their own.
+-- Only a hash of each token is stored. Plaintext tokens from before this migration are dropped,
+-- which means everyone has to log in once more.
+create table sessions (
+    id integer primary key autoincrement,
+    user_id integer not null references users(id) on delete cascade,
+    token_hash blob not null unique,
+    created_at integer not null,
+    expires_at integer not null
+);
+
+create index sessions_user_id on sessions(user_id);
+
+-- sqlite cannot drop a unique column, so the table has to be rebuilt without it
+create table users_new (
+    id integer primary key autoincrement,
+    username varchar unique,
+    ip integer unique,
+    password_hash varchar
+);
+
+insert into users_new (id, username, ip, password_hash)
+    select id, username, ip, password_hash from users;
+
+drop table users;
+alter table users_new rename to users;
@@ -0,0 +1,44 @@
+-- one relay hosts any number of isolated networks, each with its own members and leases.
+-- Everyone keeps the address they had, in a network called "default" that follows the relay's
+-- configured subnet.
+create table networks (
+    id integer primary key autoincrement,
+    name varchar not null unique,
+    -- CIDR this network leases addresses from, null means the relay's configured subnet
+    subnet varchar,
+    owner integer references users(id) on delete set null,
+    created_at integer not null
+);
+
+insert into networks (id, name, subnet, owner, created_at)
+    values (1, 'default', null, null, unixepoch());
+
+create table memberships (
+    network_id integer not null references networks(id) on delete cascade,
+    user_id integer not null references users(id) on delete cascade,
+    ip integer,
+    primary key (network_id, user_id),
+    unique (network_id, ip)
+);
+
+insert into memberships (network_id, user_id, ip)
+    select 1, id, ip from users;
+
+-- every session so far was in the default network
+alter table sessions add column network_id integer not null default 1
+    references networks(id) on delete cascade;
+
+create index sessions_network_id on sessions(network_id);
+
+-- leases live in memberships now, and sqlite cannot drop a unique column
+create table users_new (
+    id integer primary key autoincrement,
+    username varchar unique,
+    password_hash varchar
+);
+
+insert into users_new (id, username, password_hash)
+    select id, username, password_hash from users;
+
+drop table users;
+alter table users_new rename to users;
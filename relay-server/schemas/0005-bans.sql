@@ -0,0 +1,2 @@
+-- banned users cannot log in until they are unbanned, see relay-admin
+alter table users add column banned_at integer;
@@ -0,0 +1,8 @@
+-- joining a network other than the default one takes an invitation from its owner, which is
+-- used up by joining
+create table network_invites (
+    network_id integer not null references networks(id) on delete cascade,
+    user_id integer not null references users(id) on delete cascade,
+    created_at integer not null,
+    primary key (network_id, user_id)
+);
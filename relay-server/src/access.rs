@@ -1,20 +1,58 @@
-use std::net::Ipv4Addr;
+use std::{
+    net::Ipv4Addr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use rand::Rng as _;
-use rusqlite::{params, ErrorCode};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::{rngs::OsRng, RngCore as _};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, ToSql};
+use sha2::{Digest as _, Sha256};
 
-use crate::{action::response::*, db::Db, error::*};
+use crate::{
+    action::response::*,
+    config::{Config, RegistrationPolicy, SessionPolicy},
+    db::Db,
+    error::*,
+    network::{self, DEFAULT_NETWORK},
+};
 
 impl Db {
-    #[instrument(skip(self))]
-    pub async fn login(&self, username: &str) -> Result<LoginResp> {
-        let ip_address = new_ip();
-        let token = gen_token::<16>();
+    #[instrument(skip(self, password, config))]
+    pub async fn register(
+        &self,
+        username: &str,
+        password: &str,
+        invite: Option<&str>,
+        config: &Config,
+    ) -> Result {
+        match (config.registration, invite) {
+            (RegistrationPolicy::Closed, _) => return Err(Error::RegistrationClosed),
+            (RegistrationPolicy::InviteOnly, None) => return Err(Error::InvalidInvite),
+            _ => (),
+        }
 
-        let db = self.db_conn.lock().await;
+        // argon2 is slow on purpose, so keep it away from both the executor and the db lock
+        let password_hash = hash_password(password.to_string()).await?;
 
-        let mut stmt = db.prepare("insert into users (username, ip, token) values (?1, ?2, ?3)")?;
-        let res = stmt.execute(params![username, ip_address, token]);
+        let mut db = self.db_conn.lock().await;
+        let tx = db.transaction()?;
+
+        let max_users = config.limits.max_users;
+        if max_users != 0 {
+            let users: u32 = tx.query_row("select count(*) from users", [], |row| row.get(0))?;
+            if users >= max_users {
+                warn!("refusing registration, {users} users already registered");
+                return Err(Error::ServerFull);
+            }
+        }
+
+        let res = tx.execute(
+            "insert into users (username, password_hash) values (?1, ?2)",
+            params![username, password_hash],
+        );
 
         if let Err(error) = &res
             && error.sqlite_error_code() == Some(ErrorCode::ConstraintViolation)
@@ -22,44 +60,671 @@ impl Db {
             warn!("user already exists");
             return Err(Error::UserAlreadyExists);
         }
+        res?;
 
-        let rows_changed = res?;
+        if config.registration == RegistrationPolicy::InviteOnly
+            && let Some(code) = invite
+        {
+            let user_id = tx.last_insert_rowid();
+            let rows_changed = tx.execute(
+                "update invites set used_by = ?1 where code = ?2 and used_by is null",
+                params![user_id, code],
+            )?;
 
-        if rows_changed != 1 {
-            warn!("expected 1 but changed {rows_changed} rows");
+            if rows_changed != 1 {
+                warn!("invite code is unknown or already used");
+                return Err(Error::InvalidInvite);
+            }
+        }
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Starts a new session for an existing user in `network`, or the default network when
+    /// there is none. Also returns the ids of any sessions this one replaced, so their routes can
+    /// be torn down.
+    ///
+    /// The user keeps the address they already hold there, unless they `requested` a different
+    /// one.
+    #[instrument(skip(self, password, config))]
+    pub async fn login(
+        &self,
+        username: &str,
+        password: &str,
+        network: Option<&str>,
+        requested: Option<Ipv4Addr>,
+        config: &Config,
+    ) -> Result<(LoginResp, Vec<i64>)> {
+        let db = self.db_conn.lock().await;
+        let user = db
+            .query_row(
+                "select id, password_hash, banned_at is not null from users where username = ?1",
+                [username],
+                |row| {
+                    Ok(UserRow {
+                        id: row.get(0)?,
+                        password_hash: row.get(1)?,
+                        banned: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?;
+        drop(db);
+
+        // unknown users, wrong passwords and bans look the same from the outside. A password is
+        // checked either way, so they also take as long.
+        let (id, password_hash, banned) = match user {
+            Some(UserRow {
+                id,
+                password_hash: Some(password_hash),
+                banned,
+            }) => (Some(id), password_hash, banned),
+            _ => (None, DUMMY_HASH.to_string(), false),
+        };
+        let verified = verify_password(password.to_string(), password_hash).await?;
+
+        let Some(id) = id else {
+            warn!("no such user");
+            return Err(Error::InvalidCredentials);
+        };
+
+        if !verified {
+            warn!("wrong password");
+            return Err(Error::InvalidCredentials);
         }
 
-        Ok(LoginResp {
+        if banned {
+            warn!("user is banned");
+            return Err(Error::InvalidCredentials);
+        }
+
+        let mut db = self.db_conn.lock().await;
+        let tx = db.transaction()?;
+        let now = unix_now();
+
+        let network = network::find(&tx, network.unwrap_or(DEFAULT_NETWORK), config)?;
+        if network.name == DEFAULT_NETWORK {
+            tx.execute(
+                "insert or ignore into memberships (network_id, user_id) values (?1, ?2)",
+                params![network.id, id],
+            )?;
+        }
+
+        tx.execute(
+            "delete from sessions where user_id = ?1 and expires_at <= ?2",
+            params![id, now],
+        )?;
+
+        // whoever proves they are this user gets the user's address back, the only question is
+        // what happens to the sessions they had before
+        let revoked = match config.session_policy {
+            SessionPolicy::Replace => tx
+                .prepare(
+                    "delete from sessions where user_id = ?1 and network_id = ?2 returning id",
+                )?
+                .query_map([id, network.id], |row| row.get(0))?
+                .collect::<Result<Vec<i64>, _>>()?,
+            SessionPolicy::Keep => Vec::new(),
+        };
+
+        if !revoked.is_empty() {
+            info!("replacing {} existing sessions", revoked.len());
+        }
+
+        let ip_address = network::lease(&tx, &*self.allocator, &network, id, requested, config)?;
+
+        let (token, expires_at) = create_session(&tx, id, network.id, config)?;
+        tx.commit()?;
+
+        let resp = LoginResp {
             token,
-            address: ip_address.into(),
-            netmask: Ipv4Addr::new(255, 0, 0, 0),
+            expires_at,
+            address: ip_address,
+            netmask: network.subnet.netmask(),
+            gateway: crate::ipalloc::gateway(network.subnet),
+        };
+
+        Ok((resp, revoked))
+    }
+
+    /// Swaps a still valid token for a new one, pushing the session's expiry back. Routes that
+    /// were set up with the old token stay up.
+    #[instrument(skip(self, token, config))]
+    pub async fn refresh(&self, token: &str, config: &Config) -> Result<TokenResp> {
+        let new_token = gen_token();
+        let expires_at = unix_now() + config.token_ttl_secs;
+
+        let db = self.db_conn.lock().await;
+        let rows_changed = db.execute(
+            "update sessions set token_hash = ?1, expires_at = ?2
+                where token_hash = ?3 and expires_at > ?4",
+            params![
+                hash_token(&new_token),
+                expires_at,
+                hash_token(token),
+                unix_now()
+            ],
+        )?;
+
+        if rows_changed != 1 {
+            warn!("token is unknown or expired");
+            return Err(Error::InvalidToken);
+        }
+
+        Ok(TokenResp {
+            token: new_token,
+            expires_at,
+        })
+    }
+
+    /// Deletes the session behind `token`, returning its id so its routes can be torn down.
+    /// Logging out of a user's last session in a network also gives up their lease there.
+    #[instrument(skip(self, token))]
+    pub async fn logout(&self, token: &str) -> Result<i64> {
+        let mut db = self.db_conn.lock().await;
+        let tx = db.transaction()?;
+
+        let session = end_session(&tx, "token_hash = ?1", hash_token(token))?;
+        tx.commit()?;
+
+        session.ok_or(Error::InvalidToken)
+    }
+
+    /// Ends a session whose peer disconnected and did not come back in time, the same way
+    /// logging out would. A session that is already gone is not an error.
+    #[instrument(skip(self))]
+    pub async fn expire_session(&self, session: i64) -> Result {
+        let mut db = self.db_conn.lock().await;
+        let tx = db.transaction()?;
+
+        if end_session(&tx, "id = ?1", session)?.is_some() {
+            info!("session expired after its peer disconnected");
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Looks up the session that belongs to an unexpired token
+    #[instrument(skip(self, token))]
+    pub async fn session(&self, token: &str) -> Result<Session> {
+        let db = self.db_conn.lock().await;
+        let session = db
+            .query_row(
+                "select sessions.id, sessions.user_id, sessions.network_id, memberships.ip,
+                        users.username
+                    from sessions
+                    join memberships on memberships.user_id = sessions.user_id
+                        and memberships.network_id = sessions.network_id
+                    join users on users.id = sessions.user_id
+                    where sessions.token_hash = ?1 and sessions.expires_at > ?2
+                        and memberships.ip is not null",
+                params![hash_token(token), unix_now()],
+                |row| {
+                    Ok(Session {
+                        id: row.get(0)?,
+                        user_id: row.get(1)?,
+                        network_id: row.get(2)?,
+                        address: row.get(3).map(Ipv4Addr::from_bits)?,
+                        username: row.get(4)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        session.ok_or_else(|| {
+            warn!("token is unknown or expired");
+            Error::InvalidToken
         })
     }
+
+    /// Creates a single use code for registering on an invite-only relay
+    #[instrument(skip(self))]
+    pub async fn create_invite(&self) -> Result<String> {
+        let code = gen_token();
+
+        let db = self.db_conn.lock().await;
+        db.execute(
+            "insert into invites (code, created_at) values (?1, unixepoch())",
+            [&code],
+        )?;
+
+        Ok(code)
+    }
 }
 
-//{{{ random generators
-fn new_ip() -> u32 {
-    let mut rng = rand::thread_rng();
+/// What an unexpired token is good for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub id: i64,
+    pub user_id: i64,
+    pub network_id: i64,
+    /// the user's address inside `network_id`
+    pub address: Ipv4Addr,
+    pub username: String,
+}
 
-    // The first octet is fixed as 25. The rest are random.
-    let first_octet = 25u32 << 24; // Shift 25 to the most significant byte.
-    let second_octet = (rng.gen_range(0..=255) as u32) << 16;
-    let third_octet = (rng.gen_range(0..=255) as u32) << 8;
-    let fourth_octet = rng.gen_range(0..=255) as u32;
+struct UserRow {
+    id: i64,
+    password_hash: Option<String>,
+    banned: bool,
+}
+
+/// Starts a new session for `user_id` in `network_id`, returning the only copy of its token in
+/// plaintext
+fn create_session(
+    db: &Connection,
+    user_id: i64,
+    network_id: i64,
+    config: &Config,
+) -> Result<(String, u64)> {
+    let token = gen_token();
+    let now = unix_now();
+    let expires_at = now + config.token_ttl_secs;
 
-    // Combine the octets into a single u32.
-    first_octet | second_octet | third_octet | fourth_octet
+    db.execute(
+        "insert into sessions (user_id, network_id, token_hash, created_at, expires_at)
+            values (?1, ?2, ?3, ?4, ?5)",
+        params![user_id, network_id, hash_token(&token), now, expires_at],
+    )?;
+
+    Ok((token, expires_at))
 }
 
-fn gen_token<const N: usize>() -> String {
-    let mut rng = rand::thread_rng();
-    let mut hex_string = String::new();
+/// Deletes the session matching `filter`, releasing the lease if it was the user's last one in
+/// its network
+pub(crate) fn end_session(tx: &Connection, filter: &str, key: impl ToSql) -> Result<Option<i64>> {
+    let session: Option<(i64, i64, i64)> = tx
+        .query_row(
+            &format!("delete from sessions where {filter} returning id, user_id, network_id"),
+            [key],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+
+    let Some((session, user_id, network_id)) = session else {
+        return Ok(None);
+    };
 
-    for _ in 0..N {
-        let random_byte: u8 = rng.gen_range(0..16);
-        hex_string.push_str(&format!("{:x}", random_byte));
+    let released = tx.execute(
+        "update memberships set ip = null
+            where user_id = ?1 and network_id = ?2 and not exists (
+                select 1 from sessions where user_id = ?1 and network_id = ?2
+            )",
+        [user_id, network_id],
+    )?;
+    if released != 0 {
+        debug!("released address lease");
     }
 
-    hex_string
+    Ok(Some(session))
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is set before 1970")
+        .as_secs()
+}
+
+async fn hash_password(password: String) -> Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+        Ok(hash.to_string())
+    })
+    .await
+    .expect("password hashing task panicked")
+}
+
+/// Checked against when a login has no hash of its own, an unknown user or one without a
+/// password. Made by [`hash_password`] from a random password, so it costs as much to verify.
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$cNoLmYi1iS6Yc/kBPG7SJg$I1KAmgxs9hBwETpooPBsONXWmUoqzGB/PF3vYiPJq5o";
+
+async fn verify_password(password: String, hash: String) -> Result<bool> {
+    tokio::task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&hash)?;
+        let res = Argon2::default().verify_password(password.as_bytes(), &hash);
+
+        match res {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    })
+    .await
+    .expect("password verification task panicked")
+}
+
+//{{{ random generators
+/// 256 bits straight from the OS, hex encoded
+fn gen_token() -> String {
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Tokens are long and random, so a plain hash is enough to keep a leaked db from being a list
+/// of working credentials. No salt or slow hash needed, unlike passwords.
+fn hash_token(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
 }
 //}}}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    async fn db_with(registration: RegistrationPolicy) -> (Db, Config) {
+        let db = Db::try_new_in_memory().await.unwrap();
+        let config = Config {
+            registration,
+            ..Default::default()
+        };
+        (db, config)
+    }
+
+    #[tokio::test]
+    async fn test_register_then_login() {
+        let (db, config) = db_with(RegistrationPolicy::Open).await;
+        db.register("alice", "hunter2", None, &config)
+            .await
+            .unwrap();
+
+        let (resp, _) = db
+            .login("alice", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+        assert!(config.subnet.contains(&resp.address));
+    }
+
+    #[tokio::test]
+    async fn test_wrong_password_and_unknown_user_look_alike() {
+        let (db, config) = db_with(RegistrationPolicy::Open).await;
+        db.register("alice", "hunter2", None, &config)
+            .await
+            .unwrap();
+
+        let wrong_password = db.login("alice", "hunter3", None, None, &config).await;
+        let unknown_user = db.login("mallory", "hunter2", None, None, &config).await;
+        assert!(matches!(wrong_password, Err(Error::InvalidCredentials)));
+        assert!(matches!(unknown_user, Err(Error::InvalidCredentials)));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_users_cost_a_password_check() {
+        // the dummy has to be a hash argon2 verifies, with the same parameters as a real one
+        let real = hash_password("hunter2".to_string()).await.unwrap();
+        let real = PasswordHash::new(&real).unwrap();
+        let dummy = PasswordHash::new(DUMMY_HASH).unwrap();
+        assert_eq!(dummy.algorithm, real.algorithm);
+        assert_eq!(dummy.version, real.version);
+        assert_eq!(dummy.params, real.params);
+
+        let res = verify_password("hunter2".to_string(), DUMMY_HASH.to_string()).await;
+        assert!(matches!(res, Ok(false)));
+    }
+
+    #[tokio::test]
+    async fn test_password_is_not_stored_in_plaintext() {
+        let (db, config) = db_with(RegistrationPolicy::Open).await;
+        db.register("alice", "hunter2", None, &config)
+            .await
+            .unwrap();
+
+        let conn = db.db_conn.lock().await;
+        let hash: String = conn
+            .query_row("select password_hash from users", [], |row| row.get(0))
+            .unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(!hash.contains("hunter2"));
+    }
+
+    #[test]
+    fn test_tokens_are_long_and_unique() {
+        let token = gen_token();
+        assert_eq!(token.len(), 64);
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(token, gen_token());
+    }
+
+    #[tokio::test]
+    async fn test_tokens_are_stored_hashed() {
+        let (db, config) = db_with(RegistrationPolicy::Open).await;
+        db.register("alice", "hunter2", None, &config)
+            .await
+            .unwrap();
+        let (resp, _) = db
+            .login("alice", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+
+        let conn = db.db_conn.lock().await;
+        let stored: Vec<u8> = conn
+            .query_row("select token_hash from sessions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, hash_token(&resp.token));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_rotates_token() {
+        let (db, config) = db_with(RegistrationPolicy::Open).await;
+        db.register("alice", "hunter2", None, &config)
+            .await
+            .unwrap();
+        let (resp, _) = db
+            .login("alice", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+        let session = db.session(&resp.token).await.unwrap();
+
+        let refreshed = db.refresh(&resp.token, &config).await.unwrap();
+        assert!(matches!(
+            db.session(&resp.token).await,
+            Err(Error::InvalidToken)
+        ));
+        assert_eq!(db.session(&refreshed.token).await.unwrap(), session);
+    }
+
+    #[tokio::test]
+    async fn test_expired_tokens_are_rejected() {
+        let (db, mut config) = db_with(RegistrationPolicy::Open).await;
+        config.token_ttl_secs = 0;
+        db.register("alice", "hunter2", None, &config)
+            .await
+            .unwrap();
+        let (resp, _) = db
+            .login("alice", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            db.session(&resp.token).await,
+            Err(Error::InvalidToken)
+        ));
+        assert!(matches!(
+            db.refresh(&resp.token, &config).await,
+            Err(Error::InvalidToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_logout_deletes_session() {
+        let (db, config) = db_with(RegistrationPolicy::Open).await;
+        db.register("alice", "hunter2", None, &config)
+            .await
+            .unwrap();
+        let (resp, _) = db
+            .login("alice", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+
+        db.logout(&resp.token).await.unwrap();
+        assert!(matches!(
+            db.session(&resp.token).await,
+            Err(Error::InvalidToken)
+        ));
+        assert!(matches!(
+            db.logout(&resp.token).await,
+            Err(Error::InvalidToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expired_session_releases_lease() {
+        let (db, config) = db_with(RegistrationPolicy::Open).await;
+        db.register("alice", "hunter2", None, &config)
+            .await
+            .unwrap();
+        db.register("bob", "hunter2", None, &config).await.unwrap();
+        let (alice, _) = db
+            .login("alice", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+        let session = db.session(&alice.token).await.unwrap();
+
+        db.expire_session(session.id).await.unwrap();
+        // expiring twice, e.g. after a logout, does nothing
+        db.expire_session(session.id).await.unwrap();
+
+        assert!(matches!(
+            db.session(&alice.token).await,
+            Err(Error::InvalidToken)
+        ));
+        let (bob, _) = db
+            .login("bob", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+        assert_eq!(bob.address, alice.address);
+    }
+
+    #[tokio::test]
+    async fn test_relogin_keeps_address_and_replaces_session() {
+        let (db, config) = db_with(RegistrationPolicy::Open).await;
+        db.register("alice", "hunter2", None, &config)
+            .await
+            .unwrap();
+
+        let (first, _) = db
+            .login("alice", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+        let first_session = db.session(&first.token).await.unwrap().id;
+        let (second, revoked) = db
+            .login("alice", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+
+        assert_eq!(first.address, second.address);
+        assert_ne!(first.token, second.token);
+        assert_eq!(revoked, vec![first_session]);
+        assert!(matches!(
+            db.session(&first.token).await,
+            Err(Error::InvalidToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_relogin_can_keep_old_sessions() {
+        let (db, mut config) = db_with(RegistrationPolicy::Open).await;
+        config.session_policy = SessionPolicy::Keep;
+        db.register("alice", "hunter2", None, &config)
+            .await
+            .unwrap();
+
+        let (first, _) = db
+            .login("alice", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+        let (second, revoked) = db
+            .login("alice", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+
+        assert!(revoked.is_empty());
+        assert_eq!(first.address, second.address);
+        db.session(&first.token).await.unwrap();
+        db.session(&second.token).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_leases_are_sequential_and_released_on_logout() {
+        let (db, config) = db_with(RegistrationPolicy::Open).await;
+        db.register("alice", "hunter2", None, &config)
+            .await
+            .unwrap();
+        db.register("bob", "hunter2", None, &config).await.unwrap();
+
+        let (alice, _) = db
+            .login("alice", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+        let (bob, _) = db
+            .login("bob", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+        // the first host address is the relay's own
+        assert_eq!(alice.address, Ipv4Addr::new(100, 64, 0, 2));
+        assert_eq!(bob.address, Ipv4Addr::new(100, 64, 0, 3));
+        assert_eq!(alice.netmask, Ipv4Addr::new(255, 192, 0, 0));
+        assert_eq!(alice.gateway, Some(Ipv4Addr::new(100, 64, 0, 1)));
+
+        db.logout(&alice.token).await.unwrap();
+        db.register("carol", "hunter2", None, &config)
+            .await
+            .unwrap();
+        let (carol, _) = db
+            .login("carol", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+        assert_eq!(carol.address, alice.address);
+    }
+
+    #[tokio::test]
+    async fn test_requested_address() {
+        let (db, config) = db_with(RegistrationPolicy::Open).await;
+        db.register("alice", "hunter2", None, &config)
+            .await
+            .unwrap();
+        db.register("bob", "hunter2", None, &config).await.unwrap();
+
+        let wanted = Ipv4Addr::new(100, 64, 1, 1);
+        let (alice, _) = db
+            .login("alice", "hunter2", None, Some(wanted), &config)
+            .await
+            .unwrap();
+        assert_eq!(alice.address, wanted);
+
+        let res = db
+            .login("bob", "hunter2", None, Some(wanted), &config)
+            .await;
+        assert!(matches!(res, Err(Error::AddressUnavailable)));
+    }
+
+    #[tokio::test]
+    async fn test_closed_registration() {
+        let (db, config) = db_with(RegistrationPolicy::Closed).await;
+        let res = db.register("alice", "hunter2", None, &config).await;
+        assert!(matches!(res, Err(Error::RegistrationClosed)));
+    }
+
+    #[tokio::test]
+    async fn test_invites_are_single_use() {
+        let (db, config) = db_with(RegistrationPolicy::InviteOnly).await;
+        let invite = db.create_invite().await.unwrap();
+
+        let no_invite = db.register("alice", "hunter2", None, &config).await;
+        assert!(matches!(no_invite, Err(Error::InvalidInvite)));
+
+        db.register("alice", "hunter2", Some(&invite), &config)
+            .await
+            .unwrap();
+
+        let reused = db.register("bob", "hunter2", Some(&invite), &config).await;
+        assert!(matches!(reused, Err(Error::InvalidInvite)));
+    }
+}
@@ -1,36 +1,28 @@
-use std::net::Ipv4Addr;
-
-use s2n_quic::stream::BidirectionalStream;
-use s2n_quic::Connection;
+use ipnet::Ipv4Net;
 
+use crate::access::Session;
+use crate::config::Config;
 use crate::db::Db;
 use crate::error::*;
+use crate::ipalloc::is_assignable;
 
 pub struct ServerHandler {
     pub(super) db: Db,
-    pub(super) connection: Connection,
 }
 
 impl ServerHandler {
-    pub async fn upgrade(&mut self, token: &str) -> Result<(Ipv4Addr, BidirectionalStream)> {
-        let db = self.db.db_conn.lock().await;
-
-        let ip_bits: u32 = db
-            .query_row("select ip from users where token = ?1", [token], |e| {
-                debug!(?e);
-                e.get(0)
-            })
-            .inspect_err(|e| error!(?e))?;
-        drop(db);
-
-        let ip = Ipv4Addr::from_bits(ip_bits);
-
-        let bi = self
-            .connection
-            .open_bidirectional_stream()
-            .await
-            .map_err(QuicError::from)?;
-
-        Ok((ip, bi))
+    /// Returns the session behind `token` and the subnet of its network
+    pub async fn upgrade(&self, token: &str, config: &Config) -> Result<(Session, Ipv4Net)> {
+        let session = self.db.session(token).await?;
+        let subnet = self.db.subnet_of(session.network_id, config).await?;
+
+        // leased before the subnet changed, or before its address became the gateway. Logging in
+        // again leases a new one.
+        if !is_assignable(subnet, session.address) {
+            warn!(address = %session.address, "session's address is not assignable anymore");
+            return Err(Error::InvalidToken);
+        }
+
+        Ok((session, subnet))
     }
 }
@@ -1,71 +1,309 @@
 pub mod handler;
 pub mod response;
 
-use s2n_quic::{stream::BidirectionalStream, Connection};
-use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
+use std::{
+    net::Ipv4Addr,
+    sync::{atomic::Ordering, Arc},
+};
 
-use crate::{db::Db, error::*, wire, RoutingInfo};
+use ipnet::Ipv4Net;
+use s2n_quic::{connection, stream::BidirectionalStream};
+use tokio::{
+    io::AsyncWrite,
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    config::Config, db::Db, error::*, events, events::PeerEvents, hello::Capabilities,
+    metrics::Metrics, proto::Payload, tunnel::Tunnel, wire, wire::Message, PeerConnection,
+    RouteUpdate, RoutingInfo,
+};
 use handler::ServerHandler;
 use response::*;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
 pub enum Action {
-    UpgradeConn { token: String },
-    Login { name: String },
+    UpgradeConn {
+        token: String,
+    },
+    Login {
+        name: String,
+        password: String,
+        /// network to log in to, the default one if `None`
+        network: Option<String>,
+        /// static address to lease instead of the one the relay would pick
+        address: Option<Ipv4Addr>,
+    },
+    Register {
+        name: String,
+        password: String,
+        invite: Option<String>,
+    },
+    Refresh {
+        token: String,
+    },
+    Logout {
+        token: String,
+    },
+    CreateNetwork {
+        token: String,
+        name: String,
+        /// leases come from the relay's configured subnet if `None`
+        subnet: Option<Ipv4Net>,
+    },
+    InviteToNetwork {
+        token: String,
+        name: String,
+        username: String,
+    },
+    JoinNetwork {
+        token: String,
+        name: String,
+    },
+    LeaveNetwork {
+        token: String,
+        name: String,
+    },
+    WatchPeers {
+        token: String,
+    },
+    ListPeers {
+        token: String,
+    },
 }
 
 impl Action {
-    #[instrument(skip(connection, db), fields(remote_addr = ?connection.remote_addr()))]
+    /// Answers the action on `stream`, the one it arrived on. An accepted `UpgradeConn` keeps
+    /// the stream for the peer's packets instead, an accepted `WatchPeers` for its events.
+    // actions carry passwords and tokens, so they are kept out of the span
+    #[instrument(skip_all, fields(remote_addr = ?connection.handle.remote_addr()))]
     pub async fn handle_action(
         self,
-        connection: Connection,
+        mut stream: BidirectionalStream,
+        connection: PeerConnection,
         db: Db,
-        tx: mpsc::Sender<RoutingInfo>,
+        config: Arc<Config>,
+        tx: mpsc::Sender<RouteUpdate>,
+        metrics: Arc<Metrics>,
     ) {
         match self {
             Action::UpgradeConn { token } => {
-                let mut handler = ServerHandler { db, connection };
-                let (ip, bi) = match handler.upgrade(&token).await {
+                let handler = ServerHandler { db: db.clone() };
+                let (session, subnet) = match handler.upgrade(&token, &config).await {
                     Ok(value) => value,
-                    Err(error) => return error!("{error}"),
+                    Err(error) => return respond::<()>(&mut stream, Err(error)).await,
                 };
 
-                let (recv, send) = bi.split();
-                let ri = RoutingInfo { ip, send, recv };
-                if let Err(error) = tx.send(ri).await {
+                // from here on the stream carries the peer's packets
+                respond(&mut stream, Ok(())).await;
+
+                let ri = RoutingInfo {
+                    ip: session.address,
+                    network: session.network_id,
+                    subnet,
+                    session: session.id,
+                    username: session.username,
+                    connection: connection.handle.clone(),
+                    broadcast: connection.capabilities.contains(Capabilities::BROADCAST),
+                    tunnel: Tunnel::new(connection.handle, stream, connection.capabilities),
+                };
+                if let Err(error) = tx.send(RouteUpdate::Add(Box::new(ri))).await {
                     error!("could not send routing info: {error}");
                 }
             }
-            Action::Login { name } => {
-                let data = match db.login(&name).await {
+            Action::Refresh { token } => {
+                let res = db.refresh(&token, &config).await;
+                respond(&mut stream, res).await;
+            }
+            Action::Logout { token } => {
+                let res = db.logout(&token).await;
+
+                if let Ok(session) = res {
+                    let update = RouteUpdate::Revoke {
+                        sessions: vec![session],
+                    };
+                    if let Err(error) = tx.send(update).await {
+                        error!("could not revoke routes: {error}");
+                    }
+                }
+
+                respond(&mut stream, res.map(|_| ())).await;
+            }
+            Action::Login {
+                name,
+                password,
+                network,
+                address,
+            } => {
+                let res = db
+                    .login(&name, &password, network.as_deref(), address, &config)
+                    .await;
+                let counter = match res {
+                    Ok(_) => &metrics.logins,
+                    Err(_) => &metrics.failed_logins,
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+
+                let res = match res {
+                    Ok((resp, revoked)) if !revoked.is_empty() => {
+                        let update = RouteUpdate::Revoke { sessions: revoked };
+                        if let Err(error) = tx.send(update).await {
+                            error!("could not revoke replaced sessions: {error}");
+                        }
+                        Ok(resp)
+                    }
+                    res => res.map(|(resp, _)| resp),
+                };
+
+                respond(&mut stream, res).await;
+            }
+            Action::Register {
+                name,
+                password,
+                invite,
+            } => {
+                let res = db
+                    .register(&name, &password, invite.as_deref(), &config)
+                    .await;
+                respond(&mut stream, res).await;
+            }
+            Action::CreateNetwork {
+                token,
+                name,
+                subnet,
+            } => {
+                let res = db.create_network(&token, &name, subnet).await;
+                respond(&mut stream, res).await;
+            }
+            Action::InviteToNetwork {
+                token,
+                name,
+                username,
+            } => {
+                let res = db
+                    .invite_to_network(&token, &name, &username, &config)
+                    .await;
+                respond(&mut stream, res).await;
+            }
+            Action::JoinNetwork { token, name } => {
+                let res = db.join_network(&token, &name, &config).await;
+                respond(&mut stream, res).await;
+            }
+            Action::LeaveNetwork { token, name } => {
+                let res = db.leave_network(&token, &name, &config).await;
+
+                if let Ok(sessions) = &res
+                    && !sessions.is_empty()
+                {
+                    let update = RouteUpdate::Revoke {
+                        sessions: sessions.clone(),
+                    };
+                    if let Err(error) = tx.send(update).await {
+                        error!("could not revoke routes: {error}");
+                    }
+                }
+
+                respond(&mut stream, res.map(|_| ())).await;
+            }
+            Action::WatchPeers { token } => {
+                let session = match db.session(&token).await {
                     Ok(value) => value,
-                    Err(error) => return error!("{error}"),
+                    Err(error) => return respond::<()>(&mut stream, Err(error)).await,
                 };
 
-                if let Err(error) = Self::send(connection, &data).await {
-                    error!("error when sending handler response: {error}")
+                let (reply, subscription) = oneshot::channel();
+                let update = RouteUpdate::Watch {
+                    network: session.network_id,
+                    reply,
+                };
+                if let Err(error) = tx.send(update).await {
+                    error!("could not watch peers: {error}");
+                    return respond::<()>(&mut stream, Err(Error::InternalServerError)).await;
                 }
+                let Ok(subscription) = subscription.await else {
+                    return respond::<()>(&mut stream, Err(Error::InternalServerError)).await;
+                };
+
+                // from here on the stream carries the peer events
+                respond(&mut stream, Ok(())).await;
+
+                let (recv, send) = stream.split();
+                events::watch(recv, send, session.id, session.network_id, subscription).await;
+            }
+            Action::ListPeers { token } => {
+                let session = match db.session(&token).await {
+                    Ok(value) => value,
+                    Err(error) => return respond::<()>(&mut stream, Err(error)).await,
+                };
+
+                let (reply, peers) = oneshot::channel();
+                let update = RouteUpdate::List {
+                    network: session.network_id,
+                    reply,
+                };
+                if let Err(error) = tx.send(update).await {
+                    error!("could not list peers: {error}");
+                    return respond::<()>(&mut stream, Err(Error::InternalServerError)).await;
+                }
+
+                let res = peers.await.map_err(|_| Error::InternalServerError);
+                respond(&mut stream, res).await;
             }
         }
     }
+}
 
-    #[instrument(skip(connection, data))]
-    async fn send<T: Serialize>(mut connection: Connection, data: &T) -> Result<()> {
-        let mut send_stream = connection
-            .open_send_stream()
-            .await
-            .inspect_err(|error| error!(?error, "error when opening send stream"))
-            .map_err(QuicError::from)?;
-
-        wire::serialise_stream(&mut send_stream, data).await?;
+/// Answers the action that arrived on `stream` with `res`, see [`Response`]
+pub(crate) async fn respond<T: Payload>(stream: &mut (impl AsyncWrite + Unpin), res: Result<T>) {
+    let res: Response<T> = res.map_err(|error| {
+        warn!("refusing client: {error}");
+        ActionError::from(&error)
+    });
 
-        Ok(())
+    if let Err(error) = wire::serialise_stream(stream, &res).await {
+        error!("error when sending handler response: {error}")
     }
 }
 
+/// Sends `data` on a unidirectional stream of its own
+#[instrument(skip(connection, data))]
+pub(crate) async fn send<T: Message>(connection: &mut connection::Handle, data: &T) -> Result<()> {
+    let mut send_stream = connection
+        .open_send_stream()
+        .await
+        .inspect_err(|error| error!(?error, "error when opening send stream"))
+        .map_err(QuicError::from)?;
+
+    wire::serialise_stream(&mut send_stream, data).await?;
+
+    Ok(())
+}
+
 #[trait_variant::make(Send)]
 pub trait ServerApi {
-    async fn login(&self, username: &str) -> Result<LoginResp>;
-    async fn upgrade_conn(&self, token: &str) -> Result<BidirectionalStream>;
+    async fn register(&self, username: &str, password: &str, invite: Option<&str>) -> Result;
+    async fn login(
+        &self,
+        username: &str,
+        password: &str,
+        network: Option<&str>,
+        address: Option<Ipv4Addr>,
+    ) -> Result<LoginResp>;
+    async fn refresh(&self, token: &str) -> Result<TokenResp>;
+    async fn logout(&self, token: &str) -> Result;
+    /// Carries the session's packets from now on. There is one tunnel per connection, upgrading
+    /// again ends the previous one.
+    async fn upgrade_conn(&self, token: &str) -> Result<Tunnel>;
+    async fn create_network(&self, token: &str, name: &str, subnet: Option<Ipv4Net>) -> Result;
+    /// Lets `username` join a network the session's user owns
+    async fn invite_to_network(&self, token: &str, name: &str, username: &str) -> Result;
+    /// Needs an invitation for any network but the default one
+    async fn join_network(&self, token: &str, name: &str) -> Result;
+    async fn leave_network(&self, token: &str, name: &str) -> Result;
+    /// Who else is in the session's network: everyone already there, then whoever joins, leaves
+    /// or changes address, for as long as the session lasts. Also whenever the session's own
+    /// address changes.
+    async fn watch_peers(&self, token: &str) -> Result<PeerEvents>;
+    /// Who is connected to the session's network right now, the caller included
+    async fn list_peers(&self, token: &str) -> Result<Vec<PeerInfo>>;
 }
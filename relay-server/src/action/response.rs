@@ -2,9 +2,125 @@ use super::*;
 
 use std::net::Ipv4Addr;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
 pub struct LoginResp {
     pub token: String,
+    /// unix timestamp after which `token` stops working unless refreshed
+    pub expires_at: u64,
     pub address: Ipv4Addr,
     pub netmask: Ipv4Addr,
+    /// the relay's own address in the network, see [`crate::ipalloc::gateway`]
+    pub gateway: Option<Ipv4Addr>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenResp {
+    pub token: String,
+    pub expires_at: u64,
+}
+
+/// Someone connected to the caller's network, as `ListPeers` reports them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub username: String,
+    pub address: Ipv4Addr,
+    pub state: PeerState,
+    /// seconds since the peer's tunnel came up
+    pub connected_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    /// sent packets within `peer_idle_secs`
+    Online,
+    /// connected, but quiet for longer than that
+    Idle,
+}
+
+/// What the relay answers every action with, whether it went through or not. An action with
+/// nothing to report answers `Ok(())`.
+pub type Response<T = ()> = std::result::Result<T, ActionError>;
+
+/// Why the relay refused an action
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionError {
+    InvalidCredentials,
+    InvalidToken,
+    UserAlreadyExists,
+    RegistrationClosed,
+    InvalidInvite,
+    AddressUnavailable,
+    NoSuchNetwork,
+    NetworkAlreadyExists,
+    NotAMember,
+    /// joining a network takes an invitation from its owner
+    NotInvited,
+    NotTheOwner,
+    NoSuchUser,
+    /// a new network's subnet has no room for peers, or could clash with the internet
+    InvalidSubnet,
+    ServerFull,
+    /// too many attempts from the same address, try again later
+    RateLimited,
+    /// the client speaks a protocol version the relay does not
+    VersionMismatch,
+    /// the relay could not make sense of the action at all
+    BadRequest,
+    /// anything the client could not have caused, the details only go to the relay's log
+    Internal,
+}
+
+impl From<&Error> for ActionError {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::InvalidCredentials => Self::InvalidCredentials,
+            Error::InvalidToken => Self::InvalidToken,
+            Error::UserAlreadyExists => Self::UserAlreadyExists,
+            Error::RegistrationClosed => Self::RegistrationClosed,
+            Error::InvalidInvite => Self::InvalidInvite,
+            Error::AddressUnavailable => Self::AddressUnavailable,
+            Error::NoSuchNetwork => Self::NoSuchNetwork,
+            Error::NetworkAlreadyExists => Self::NetworkAlreadyExists,
+            Error::NotAMember => Self::NotAMember,
+            Error::NotInvited => Self::NotInvited,
+            Error::NotTheOwner => Self::NotTheOwner,
+            Error::NoSuchUser => Self::NoSuchUser,
+            Error::InvalidSubnet => Self::InvalidSubnet,
+            Error::ServerFull | Error::SubnetExhausted => Self::ServerFull,
+            Error::RateLimited => Self::RateLimited,
+            Error::VersionMismatch => Self::VersionMismatch,
+            Error::BadRequest
+            | Error::DecodeError(_)
+            | Error::MessageTooLarge { .. }
+            | Error::InvalidMessage(_)
+            | Error::InsufficientLenBytes
+            | Error::Timeout => Self::BadRequest,
+            _ => Self::Internal,
+        }
+    }
+}
+
+impl From<ActionError> for Error {
+    fn from(error: ActionError) -> Self {
+        match error {
+            ActionError::InvalidCredentials => Self::InvalidCredentials,
+            ActionError::InvalidToken => Self::InvalidToken,
+            ActionError::UserAlreadyExists => Self::UserAlreadyExists,
+            ActionError::RegistrationClosed => Self::RegistrationClosed,
+            ActionError::InvalidInvite => Self::InvalidInvite,
+            ActionError::AddressUnavailable => Self::AddressUnavailable,
+            ActionError::NoSuchNetwork => Self::NoSuchNetwork,
+            ActionError::NetworkAlreadyExists => Self::NetworkAlreadyExists,
+            ActionError::NotAMember => Self::NotAMember,
+            ActionError::NotInvited => Self::NotInvited,
+            ActionError::NotTheOwner => Self::NotTheOwner,
+            ActionError::NoSuchUser => Self::NoSuchUser,
+            ActionError::InvalidSubnet => Self::InvalidSubnet,
+            ActionError::ServerFull => Self::ServerFull,
+            ActionError::RateLimited => Self::RateLimited,
+            ActionError::VersionMismatch => Self::VersionMismatch,
+            ActionError::BadRequest => Self::BadRequest,
+            ActionError::Internal => Self::InternalServerError,
+        }
+    }
 }
@@ -0,0 +1,126 @@
+use std::{net::Ipv4Addr, path::Path};
+
+use tokio::net::UnixStream;
+
+use super::proto::*;
+use crate::{error::*, wire};
+
+/// Talks to a running relay over its admin socket, one command at a time
+#[derive(Debug)]
+pub struct AdminClient {
+    stream: UnixStream,
+}
+
+impl AdminClient {
+    #[instrument]
+    pub async fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .map_err(Error::AdminSocketError)?;
+
+        Ok(Self { stream })
+    }
+
+    pub async fn users(&mut self) -> Result<Vec<User>> {
+        match self
+            .request(request::Command::ListUsers(ListUsers {}))
+            .await?
+        {
+            response::Result::Users(UserList { users }) => Ok(users),
+            _ => Err(Error::InvalidMessage("result")),
+        }
+    }
+
+    /// Every unexpired session, or only those of `username`
+    pub async fn sessions(&mut self, username: Option<&str>) -> Result<Vec<Session>> {
+        let command = request::Command::ListSessions(ListSessions {
+            username: username.map(str::to_string),
+        });
+
+        match self.request(command).await? {
+            response::Result::Sessions(SessionList { sessions }) => Ok(sessions),
+            _ => Err(Error::InvalidMessage("result")),
+        }
+    }
+
+    /// Every live route, with its counters as of now
+    pub async fn routes(&mut self) -> Result<Vec<Route>> {
+        match self
+            .request(request::Command::ListRoutes(ListRoutes {}))
+            .await?
+        {
+            response::Result::Routes(RouteList { routes }) => Ok(routes),
+            _ => Err(Error::InvalidMessage("result")),
+        }
+    }
+
+    /// Ends every session of `username`. Returns the sessions that ended.
+    pub async fn kick(&mut self, username: &str) -> Result<Vec<i64>> {
+        let command = request::Command::Kick(Kick {
+            username: username.to_string(),
+        });
+        self.done(command).await
+    }
+
+    /// Kicks `username` and refuses their logins from now on
+    pub async fn ban(&mut self, username: &str) -> Result<Vec<i64>> {
+        let command = request::Command::Ban(Ban {
+            username: username.to_string(),
+        });
+        self.done(command).await
+    }
+
+    pub async fn unban(&mut self, username: &str) -> Result {
+        let command = request::Command::Unban(Unban {
+            username: username.to_string(),
+        });
+        self.done(command).await?;
+
+        Ok(())
+    }
+
+    /// Ends the given sessions. Returns those that were still there.
+    pub async fn revoke(&mut self, sessions: &[i64]) -> Result<Vec<i64>> {
+        let command = request::Command::Revoke(Revoke {
+            sessions: sessions.to_vec(),
+        });
+        self.done(command).await
+    }
+
+    /// Moves the lease of `username` in `network`, the default one if `None`, to `address`.
+    /// Returns the sessions whose tunnels ended because of it.
+    pub async fn reassign(
+        &mut self,
+        username: &str,
+        network: Option<&str>,
+        address: Ipv4Addr,
+    ) -> Result<Vec<i64>> {
+        let command = request::Command::Reassign(Reassign {
+            username: username.to_string(),
+            network: network.unwrap_or_default().to_string(),
+            address: address.to_bits(),
+        });
+        self.done(command).await
+    }
+
+    async fn done(&mut self, command: request::Command) -> Result<Vec<i64>> {
+        match self.request(command).await? {
+            response::Result::Done(Done { sessions }) => Ok(sessions),
+            _ => Err(Error::InvalidMessage("result")),
+        }
+    }
+
+    async fn request(&mut self, command: request::Command) -> Result<response::Result> {
+        let request = Request {
+            command: Some(command),
+        };
+        wire::serialise_stream(&mut self.stream, &request).await?;
+
+        let response: Response = wire::deserialise_stream(&mut self.stream).await?;
+        match response.result {
+            Some(response::Result::Error(error)) => Err(Error::AdminRefused(error)),
+            Some(result) => Ok(result),
+            None => Err(Error::InvalidMessage("result")),
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::Ipv4Addr,
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::proto::{Session, User};
+use crate::{
+    access::{end_session, unix_now},
+    config::Config,
+    db::Db,
+    error::*,
+    network::{self, DEFAULT_NETWORK},
+};
+
+impl Db {
+    /// Every registered user, by name
+    pub(crate) async fn users(&self) -> Result<Vec<User>> {
+        let db = self.db_conn.lock().await;
+        let users = db
+            .prepare(
+                "select id, username, banned_at is not null,
+                        (select count(*) from sessions
+                            where user_id = users.id and expires_at > ?1)
+                    from users order by username",
+            )?
+            .query_map([unix_now()], |row| {
+                Ok(User {
+                    id: row.get(0)?,
+                    username: row.get(1)?,
+                    banned: row.get(2)?,
+                    sessions: row.get(3)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        Ok(users)
+    }
+
+    /// Every unexpired session, or only those of `username`
+    pub(crate) async fn sessions(&self, username: Option<&str>) -> Result<Vec<Session>> {
+        let db = self.db_conn.lock().await;
+        let sessions = db
+            .prepare(
+                "select sessions.id, users.username, networks.name, memberships.ip,
+                        sessions.created_at, sessions.expires_at
+                    from sessions
+                    join users on users.id = sessions.user_id
+                    join networks on networks.id = sessions.network_id
+                    left join memberships on memberships.user_id = sessions.user_id
+                        and memberships.network_id = sessions.network_id
+                    where sessions.expires_at > ?1 and (?2 is null or users.username = ?2)
+                    order by sessions.id",
+            )?
+            .query_map(params![unix_now(), username], |row| {
+                Ok(Session {
+                    id: row.get(0)?,
+                    username: row.get(1)?,
+                    network: row.get(2)?,
+                    address: row.get(3)?,
+                    created_at: row.get(4)?,
+                    expires_at: row.get(5)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        Ok(sessions)
+    }
+
+    /// Ends every session of `username`, returning their ids so their routes can be torn down
+    #[instrument(skip(self))]
+    pub(crate) async fn kick(&self, username: &str) -> Result<Vec<i64>> {
+        let mut db = self.db_conn.lock().await;
+        let tx = db.transaction()?;
+
+        let user_id = user_id(&tx, username)?;
+        let sessions = end_sessions_of(&tx, user_id)?;
+        tx.commit()?;
+
+        Ok(sessions)
+    }
+
+    /// Bans or unbans `username`. Banning also ends every session they have, see [`Db::kick`].
+    #[instrument(skip(self))]
+    pub(crate) async fn ban(&self, username: &str, banned: bool) -> Result<Vec<i64>> {
+        let mut db = self.db_conn.lock().await;
+        let tx = db.transaction()?;
+
+        let user_id = user_id(&tx, username)?;
+        // banning again keeps the time of the first ban
+        tx.execute(
+            "update users set banned_at = case when ?2 then coalesce(banned_at, ?3) end
+                where id = ?1",
+            params![user_id, banned, unix_now()],
+        )?;
+
+        let sessions = match banned {
+            true => end_sessions_of(&tx, user_id)?,
+            false => Vec::new(),
+        };
+        tx.commit()?;
+
+        Ok(sessions)
+    }
+
+    /// Ends the given sessions, returning those that were still there
+    #[instrument(skip(self))]
+    pub(crate) async fn revoke(&self, sessions: &[i64]) -> Result<Vec<i64>> {
+        let mut db = self.db_conn.lock().await;
+        let tx = db.transaction()?;
+
+        let mut ended = Vec::new();
+        for session in sessions {
+            ended.extend(end_session(&tx, "id = ?1", session)?);
+        }
+        tx.commit()?;
+
+        Ok(ended)
+    }
+
+    /// Moves the lease of `username` in `network`, or the default network, to `address`. Returns
+    /// the network's id and the user's sessions there, whose routes are still at the old address.
+    #[instrument(skip(self, config))]
+    pub(crate) async fn reassign(
+        &self,
+        username: &str,
+        network: Option<&str>,
+        address: Ipv4Addr,
+        config: &Config,
+    ) -> Result<(i64, Vec<i64>)> {
+        let mut db = self.db_conn.lock().await;
+        let tx = db.transaction()?;
+
+        let user_id = user_id(&tx, username)?;
+        let network = network::find(&tx, network.unwrap_or(DEFAULT_NETWORK), config)?;
+
+        let is_member = tx
+            .query_row(
+                "select 1 from memberships where network_id = ?1 and user_id = ?2",
+                [network.id, user_id],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if !is_member {
+            return Err(Error::NotAMember);
+        }
+
+        let taken: HashSet<Ipv4Addr> = tx
+            .prepare(
+                "select ip from memberships
+                    where network_id = ?1 and user_id != ?2 and ip is not null",
+            )?
+            .query_map([network.id, user_id], |row| {
+                row.get(0).map(Ipv4Addr::from_bits)
+            })?
+            .collect::<Result<_, _>>()?;
+        // a requested address is honoured exactly or refused, so this only checks it
+        let address = self
+            .allocator
+            .allocate(network.subnet, &taken, Some(address))?;
+
+        tx.execute(
+            "update memberships set ip = ?1 where network_id = ?2 and user_id = ?3",
+            params![address.to_bits(), network.id, user_id],
+        )?;
+
+        let sessions = tx
+            .prepare("select id from sessions where network_id = ?1 and user_id = ?2")?
+            .query_map([network.id, user_id], |row| row.get(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+        tx.commit()?;
+
+        Ok((network.id, sessions))
+    }
+
+    /// Every network's name, by id
+    pub(crate) async fn network_names(&self) -> Result<HashMap<i64, String>> {
+        let db = self.db_conn.lock().await;
+        let names = db
+            .prepare("select id, name from networks")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(names)
+    }
+}
+
+fn user_id(db: &Connection, username: &str) -> Result<i64> {
+    let user_id = db
+        .query_row(
+            "select id from users where username = ?1",
+            [username],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    user_id.ok_or(Error::NoSuchUser)
+}
+
+/// Ends every session of `user_id` one by one, so each releases its lease the way logging out
+/// would
+fn end_sessions_of(tx: &Connection, user_id: i64) -> Result<Vec<i64>> {
+    let sessions = tx
+        .prepare("select id from sessions where user_id = ?1")?
+        .query_map([user_id], |row| row.get(0))?
+        .collect::<Result<Vec<i64>, _>>()?;
+
+    let mut ended = Vec::new();
+    for session in sessions {
+        ended.extend(end_session(tx, "id = ?1", session)?);
+    }
+
+    Ok(ended)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    async fn db_with_users(names: &[&str]) -> (Db, Config, Vec<Ipv4Addr>) {
+        let db = Db::try_new_in_memory().await.unwrap();
+        let config = Config::default();
+
+        let mut addresses = Vec::new();
+        for name in names {
+            db.register(name, "hunter2", None, &config).await.unwrap();
+            let (resp, _) = db
+                .login(name, "hunter2", None, None, &config)
+                .await
+                .unwrap();
+            addresses.push(resp.address);
+        }
+
+        (db, config, addresses)
+    }
+
+    #[tokio::test]
+    async fn test_kick_ends_sessions_and_releases_the_lease() {
+        let (db, config, addresses) = db_with_users(&["alice", "bob"]).await;
+
+        assert_eq!(db.kick("alice").await.unwrap().len(), 1);
+        let sessions = db.sessions(None).await.unwrap();
+        assert!(sessions.iter().all(|session| session.username == "bob"));
+
+        // released, so the next one to log in gets what alice had
+        db.register("carol", "hunter2", None, &config)
+            .await
+            .unwrap();
+        let (carol, _) = db
+            .login("carol", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+        assert_eq!(carol.address, addresses[0]);
+    }
+
+    #[tokio::test]
+    async fn test_banned_users_cannot_log_in() {
+        let (db, config, _) = db_with_users(&["alice"]).await;
+
+        assert_eq!(db.ban("alice", true).await.unwrap().len(), 1);
+        let res = db.login("alice", "hunter2", None, None, &config).await;
+        assert!(matches!(res, Err(Error::InvalidCredentials)));
+        assert!(db.users().await.unwrap()[0].banned);
+
+        db.ban("alice", false).await.unwrap();
+        db.login("alice", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_users_are_refused() {
+        let (db, _, _) = db_with_users(&[]).await;
+
+        assert!(matches!(db.kick("mallory").await, Err(Error::NoSuchUser)));
+        assert!(matches!(
+            db.ban("mallory", true).await,
+            Err(Error::NoSuchUser)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_revoke_skips_sessions_already_gone() {
+        let (db, _, _) = db_with_users(&["alice"]).await;
+        let session = db.sessions(Some("alice")).await.unwrap()[0].id;
+
+        assert_eq!(
+            db.revoke(&[session, session + 100]).await.unwrap(),
+            [session]
+        );
+        assert!(db.sessions(None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reassign() {
+        let (db, config, addresses) = db_with_users(&["alice", "bob"]).await;
+        let to = Ipv4Addr::new(100, 64, 0, 42);
+
+        let (_, sessions) = db.reassign("alice", None, to, &config).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        let alice = &db.sessions(Some("alice")).await.unwrap()[0];
+        assert_eq!(alice.address.map(Ipv4Addr::from_bits), Some(to));
+
+        // bob's address is his
+        let res = db.reassign("alice", None, addresses[1], &config).await;
+        assert!(matches!(res, Err(Error::AddressUnavailable)));
+    }
+}
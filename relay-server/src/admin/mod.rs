@@ -0,0 +1,199 @@
+//! The relay's admin socket, what `relay-admin` talks to. It lists users, sessions and live
+//! routes, and ends sessions the same way logging out would, so whatever it kicks also loses its
+//! tunnel. The schema is in `proto/admin.proto`.
+
+mod client;
+mod db;
+pub mod proto;
+
+use std::{collections::HashMap, os::unix::fs::PermissionsExt as _, path::Path, sync::Arc};
+
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::mpsc::Sender,
+};
+
+use crate::{accept_failed, config::Config, db::Db, error::*, wire, Networks, RouteUpdate};
+use proto::*;
+
+pub use client::AdminClient;
+
+/// Everything the commands work on, shared with the rest of the relay
+#[derive(Clone)]
+pub(crate) struct Admin {
+    pub db: Db,
+    pub networks: Networks,
+    pub tx: Sender<RouteUpdate>,
+    pub config: Arc<Config>,
+}
+
+/// Binds the admin socket at `path`, replacing whatever a relay that is gone left behind there
+#[instrument]
+pub(crate) async fn bind(path: &Path) -> Result<UnixListener> {
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(Error::AdminSocketInUse);
+    }
+
+    match std::fs::remove_file(path) {
+        Ok(()) => debug!("removed stale admin socket"),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => (),
+        Err(error) => return Err(Error::AdminSocketError(error)),
+    }
+
+    let listener = UnixListener::bind(path).map_err(Error::AdminSocketError)?;
+    // anyone who can connect can kick everyone, so only we may
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .map_err(Error::AdminSocketError)?;
+
+    Ok(listener)
+}
+
+#[instrument(skip_all)]
+pub(crate) async fn serve(listener: UnixListener, admin: Admin) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_client(stream, admin.clone()));
+            }
+            Err(error) => {
+                warn!("could not accept admin client: {error}");
+                if !accept_failed(&error).await {
+                    return error!("admin socket stopped accepting: {error}");
+                }
+            }
+        }
+    }
+}
+
+/// Answers the requests of a single `relay-admin`, until it hangs up
+#[instrument(skip_all)]
+async fn handle_client(mut stream: UnixStream, admin: Admin) {
+    loop {
+        let request: Request = match wire::deserialise_stream(&mut stream).await {
+            Ok(value) => value,
+            Err(Error::InsufficientLenBytes) => return debug!("admin client hung up"),
+            Err(error) => return warn!("could not read admin request: {error}"),
+        };
+
+        let result = admin.handle(request).await.unwrap_or_else(|error| {
+            warn!("refusing admin request: {error}");
+            response::Result::Error(error.to_string())
+        });
+
+        let response = Response {
+            result: Some(result),
+        };
+        if let Err(error) = wire::serialise_stream(&mut stream, &response).await {
+            return warn!("could not answer admin request: {error}");
+        }
+    }
+}
+
+impl Admin {
+    #[instrument(skip(self))]
+    async fn handle(&self, request: Request) -> Result<response::Result> {
+        let command = request.command.ok_or(Error::InvalidMessage("command"))?;
+
+        let result = match command {
+            request::Command::ListUsers(ListUsers {}) => {
+                let users = self.db.users().await?;
+                response::Result::Users(UserList { users })
+            }
+            request::Command::ListSessions(ListSessions { username }) => {
+                let sessions = self.db.sessions(username.as_deref()).await?;
+                response::Result::Sessions(SessionList { sessions })
+            }
+            request::Command::ListRoutes(ListRoutes {}) => {
+                let routes = self.routes().await?;
+                response::Result::Routes(RouteList { routes })
+            }
+            request::Command::Kick(Kick { username }) => {
+                let sessions = self.db.kick(&username).await?;
+                info!(?sessions, "kicked {username}");
+                self.revoke(sessions).await
+            }
+            request::Command::Ban(Ban { username }) => {
+                let sessions = self.db.ban(&username, true).await?;
+                info!(?sessions, "banned {username}");
+                self.revoke(sessions).await
+            }
+            request::Command::Unban(Unban { username }) => {
+                self.db.ban(&username, false).await?;
+                info!("unbanned {username}");
+                response::Result::Done(Done::default())
+            }
+            request::Command::Revoke(Revoke { sessions }) => {
+                let sessions = self.db.revoke(&sessions).await?;
+                info!(?sessions, "revoked sessions");
+                self.revoke(sessions).await
+            }
+            request::Command::Reassign(Reassign {
+                username,
+                network,
+                address,
+            }) => {
+                let address = address.into();
+                let network = Some(network.as_str()).filter(|network| !network.is_empty());
+                let (network, sessions) = self
+                    .db
+                    .reassign(&username, network, address, &self.config)
+                    .await?;
+                info!("reassigned {username} to {address}");
+
+                let update = RouteUpdate::Reassign {
+                    network,
+                    sessions: sessions.clone(),
+                    to: address,
+                };
+                if let Err(error) = self.tx.send(update).await {
+                    error!("could not move routes: {error}");
+                }
+                response::Result::Done(Done { sessions })
+            }
+        };
+
+        Ok(result)
+    }
+
+    /// Tears down the routes of sessions the db just ended
+    async fn revoke(&self, sessions: Vec<i64>) -> response::Result {
+        if !sessions.is_empty() {
+            let update = RouteUpdate::Revoke {
+                sessions: sessions.clone(),
+            };
+            if let Err(error) = self.tx.send(update).await {
+                error!("could not revoke routes: {error}");
+            }
+        }
+
+        response::Result::Done(Done { sessions })
+    }
+
+    async fn routes(&self) -> Result<Vec<Route>> {
+        let names: HashMap<i64, String> = self.db.network_names().await?;
+
+        let mut routes: Vec<Route> = self
+            .networks
+            .peer_stats()
+            .await
+            .into_iter()
+            .map(|peer| Route {
+                network: names.get(&peer.network).cloned().unwrap_or_default(),
+                address: peer.ip.to_bits(),
+                username: peer.username,
+                session: peer.session,
+                connected_secs: peer.connected_secs,
+                queued: peer.queued as u64,
+                dropped: peer.dropped,
+                spoofed: peer.spoofed,
+                received_packets: peer.received_packets,
+                received_bytes: peer.received_bytes,
+                sent_packets: peer.sent_packets,
+                sent_bytes: peer.sent_bytes,
+            })
+            .collect();
+        routes.sort_by(|a, b| (&a.network, a.address).cmp(&(&b.network, b.address)));
+
+        Ok(routes)
+    }
+}
@@ -0,0 +1,30 @@
+//! The admin socket's generated types, see `proto/admin.proto`. Both ends are built from the
+//! same tree, so unlike [`crate::proto`] these go on the wire as they are.
+
+use crate::{error::Result, wire::Message};
+
+include!(concat!(env!("OUT_DIR"), "/lanshare.admin.rs"));
+
+impl Message for Request {
+    type Proto = Self;
+
+    fn to_proto(&self) -> Self::Proto {
+        self.clone()
+    }
+
+    fn from_proto(proto: Self::Proto) -> Result<Self> {
+        Ok(proto)
+    }
+}
+
+impl Message for Response {
+    type Proto = Self;
+
+    fn to_proto(&self) -> Self::Proto {
+        self.clone()
+    }
+
+    fn from_proto(proto: Self::Proto) -> Result<Self> {
+        Ok(proto)
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand};
+
+use relay_server::{admin::AdminClient, config::Config, error::*};
+
+/// Inspects and manages a running LAN-Share relay through its admin socket
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// The relay's admin socket, taken from its config file if it has one
+    #[arg(short, long)]
+    socket: Option<PathBuf>,
+    /// The relay's TOML config file
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List every registered user
+    Users,
+    /// List unexpired sessions
+    Sessions {
+        /// only this user's
+        user: Option<String>,
+    },
+    /// List live routes with their counters
+    Routes {
+        /// Keep listing them every this many seconds
+        #[arg(short, long)]
+        watch: Option<u64>,
+    },
+    /// End every session of a user, disconnecting them
+    Kick {
+        user: String,
+    },
+    /// Kick a user and refuse their logins until they are unbanned
+    Ban {
+        user: String,
+    },
+    Unban {
+        user: String,
+    },
+    /// End sessions by id, as listed by `sessions`
+    Revoke {
+        #[arg(required = true)]
+        sessions: Vec<i64>,
+    },
+    /// Move a user's address in a network. Their tunnel stays up at the new one.
+    Reassign {
+        user: String,
+        address: Ipv4Addr,
+        /// the default network if not given
+        #[arg(short, long)]
+        network: Option<String>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result {
+    let cli = Cli::parse();
+
+    let socket = match (cli.socket, &cli.config) {
+        (Some(socket), _) => socket,
+        (None, Some(path)) => Config::from_file(path)?.admin_socket,
+        (None, None) => Config::default().admin_socket,
+    };
+    let mut admin = connect(&socket).await?;
+
+    match cli.command {
+        Command::Users => {
+            println!("{:>6}  {:<20} {:>8}  banned", "id", "username", "sessions");
+            for user in admin.users().await? {
+                let banned = if user.banned { "yes" } else { "no" };
+                println!(
+                    "{:>6}  {:<20} {:>8}  {banned}",
+                    user.id, user.username, user.sessions
+                );
+            }
+        }
+        Command::Sessions { user } => {
+            println!(
+                "{:>6}  {:<20} {:<16} {:<15} {:>12}  {:>12}",
+                "id", "username", "network", "address", "created_at", "expires_at"
+            );
+            for session in admin.sessions(user.as_deref()).await? {
+                let address = session
+                    .address
+                    .map(|address| Ipv4Addr::from_bits(address).to_string())
+                    .unwrap_or_default();
+                println!(
+                    "{:>6}  {:<20} {:<16} {:<15} {:>12}  {:>12}",
+                    session.id,
+                    session.username,
+                    session.network,
+                    address,
+                    session.created_at,
+                    session.expires_at
+                );
+            }
+        }
+        Command::Routes { watch: None } => print_routes(&mut admin).await?,
+        Command::Routes {
+            watch: Some(interval),
+        } => loop {
+            print_routes(&mut admin).await?;
+            println!();
+            tokio::time::sleep(Duration::from_secs(interval.max(1))).await;
+        },
+        Command::Kick { user } => print_ended(admin.kick(&user).await?),
+        Command::Ban { user } => print_ended(admin.ban(&user).await?),
+        Command::Unban { user } => admin.unban(&user).await?,
+        Command::Revoke { sessions } => print_ended(admin.revoke(&sessions).await?),
+        Command::Reassign {
+            user,
+            address,
+            network,
+        } => {
+            let moved = admin.reassign(&user, network.as_deref(), address).await?;
+            println!("{user} is at {address} now, {} tunnels moved", moved.len());
+        }
+    }
+
+    Ok(())
+}
+
+async fn connect(socket: &Path) -> Result<AdminClient> {
+    AdminClient::connect(socket).await.inspect_err(|_| {
+        eprintln!(
+            "could not reach the relay at {}, is it running as this user?",
+            socket.display()
+        )
+    })
+}
+
+async fn print_routes(admin: &mut AdminClient) -> Result {
+    println!(
+        "{:<16} {:<15} {:<20} {:>7} {:>9}  {:>10} {:>12}  {:>10} {:>12}  {:>6} {:>7} {:>7}",
+        "network",
+        "address",
+        "username",
+        "session",
+        "connected",
+        "rx packets",
+        "rx bytes",
+        "tx packets",
+        "tx bytes",
+        "queued",
+        "dropped",
+        "spoofed"
+    );
+
+    for route in admin.routes().await? {
+        println!(
+            "{:<16} {:<15} {:<20} {:>7} {:>8}s  {:>10} {:>12}  {:>10} {:>12}  {:>6} {:>7} {:>7}",
+            route.network,
+            Ipv4Addr::from_bits(route.address).to_string(),
+            route.username,
+            route.session,
+            route.connected_secs,
+            route.received_packets,
+            route.received_bytes,
+            route.sent_packets,
+            route.sent_bytes,
+            route.queued,
+            route.dropped,
+            route.spoofed
+        );
+    }
+
+    Ok(())
+}
+
+fn print_ended(sessions: Vec<i64>) {
+    match sessions.as_slice() {
+        [] => println!("no sessions ended"),
+        sessions => println!("ended sessions {sessions:?}"),
+    }
+}
@@ -1,45 +1,104 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
-pub use s2n_quic::stream::BidirectionalStream;
-use s2n_quic::{client::Connect, Client as QuicClient, Connection};
-use serde::de::DeserializeOwned;
+pub use ipnet::Ipv4Net;
+use s2n_quic::{client::Connect, connection, stream::BidirectionalStream, Client as QuicClient};
+use tokio::sync::Mutex;
 
+pub use crate::action::response::{PeerInfo, PeerState};
 pub use crate::action::ServerApi;
+pub use crate::events::{PeerEvent, PeerEvents, PeerTable};
+pub use crate::tunnel::{Tunnel, TunnelReceiver, TunnelSender};
 use crate::{
     action::{response::*, Action},
     error::*,
-    wire, CERT,
+    hello::{Capabilities, Hello},
+    tunnel,
+    wire::{self, Message},
 };
 
+/// Where the relay is and how to tell that it really is the relay
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub server_addr: SocketAddr,
+    /// Name the relay's certificate was issued for, also sent as SNI
+    pub server_name: String,
+    /// PEM file with the certificate (or CA) the relay's certificate has to chain up to
+    pub trust_anchor: PathBuf,
+    /// Whether to offer QUIC datagrams for tunnel packets, see [`Tunnel`]
+    pub datagrams: bool,
+    /// Whether to receive broadcast and multicast packets from the rest of the network
+    pub broadcast: bool,
+}
+
+/// Talks to the relay over a single connection, opened on first use and again whenever it is
+/// lost. Every call is a request on a stream of its own, so calls may run side by side, and the
+/// tunnel from [`ServerApi::upgrade_conn`] and the events from [`ServerApi::watch_peers`] live on
+/// the same connection.
 #[derive(Debug)]
 pub struct Client {
     quic_client: QuicClient,
     server_addr: SocketAddr,
+    server_name: String,
+    /// what we offer in our [`Hello`]
+    capabilities: Capabilities,
+    /// the connection, along with the capabilities the relay shares with us on it
+    connection: Mutex<Option<(connection::Handle, Capabilities)>>,
+    /// How long connecting, or a single request, may take
     pub timeout: Duration,
 }
 
 impl Client {
     #[instrument]
-    pub async fn try_new(server_addr: SocketAddr) -> Result<Self> {
-        let quic_client = QuicClient::builder()
-            .with_tls(CERT)
-            .expect("infailable error: s2n_quic::Client with_tls")
+    pub async fn try_new(config: ClientConfig) -> Result<Self> {
+        let ClientConfig {
+            server_addr,
+            server_name,
+            trust_anchor,
+            datagrams,
+            broadcast,
+        } = config;
+
+        let builder = QuicClient::builder()
+            .with_tls(trust_anchor.as_path())
+            .map_err(|error| QuicError::TlsError(error.to_string()))?
             .with_io("0.0.0.0:0")
-            .map_err(QuicError::from)?
-            .start()
             .map_err(QuicError::from)?;
+
+        let mut capabilities = match broadcast {
+            true => Capabilities::BROADCAST,
+            false => Capabilities::NONE,
+        };
+        let quic_client = if datagrams {
+            capabilities = capabilities | Capabilities::DATAGRAMS;
+            builder
+                .with_datagram(tunnel::datagram_endpoint())
+                .expect("quic datagram error: infailable")
+                .start()
+        } else {
+            builder.start()
+        };
+        let quic_client = quic_client.map_err(QuicError::from)?;
         let timeout = Duration::from_secs(30);
 
         Ok(Self {
             quic_client,
             server_addr,
+            server_name,
+            capabilities,
+            connection: Mutex::new(None),
             timeout,
         })
     }
 
+    /// Connects to the relay and exchanges hellos with it, returning the capabilities both
+    /// sides have
     #[instrument(skip(self))]
-    async fn get_connection(&self) -> Result<Connection> {
-        let connect = Connect::new(self.server_addr).with_server_name("localhost");
+    async fn connect(&self) -> Result<(connection::Handle, Capabilities)> {
+        let connect = Connect::new(self.server_addr).with_server_name(self.server_name.as_str());
 
         trace!("trying to connect to the server");
         let connection = self
@@ -47,68 +106,125 @@ impl Client {
             .connect(connect)
             .await
             .map_err(QuicError::from)?;
+        let (mut handle, mut acceptor) = connection.split();
 
-        Ok(connection)
-    }
+        // the connection is meant to outlive any single request
+        if let Err(error) = handle.keep_alive(true) {
+            error!("Connection::keep_alive failed: {error}");
+        }
 
-    #[instrument(skip(self, connection))]
-    async fn send_action(&self, connection: &mut Connection, action: Action) -> Result<()> {
-        trace!("trying to open an uni-directional stream");
-        let mut send_stream = connection
-            .open_send_stream()
-            .await
-            .map_err(QuicError::from)?;
+        let ours = Hello::new(self.capabilities);
+        let handshake = async {
+            // the relay says hello before anything else
+            let mut recv_stream = acceptor
+                .accept_receive_stream()
+                .await
+                .map_err(QuicError::from)?
+                .ok_or_else(|| {
+                    warn!("connection closed prematurely");
+                    Error::PrematureClosure
+                })?;
+            let theirs: Hello = wire::deserialise_stream(&mut recv_stream).await?;
+            let hello = ours.negotiate(&theirs)?;
 
-        trace!("trying to serialize data");
-        wire::serialise_stream(&mut send_stream, &action).await?;
+            let mut send_stream = handle.open_send_stream().await.map_err(QuicError::from)?;
+            wire::serialise_stream(&mut send_stream, &ours).await?;
 
-        Ok(())
+            Ok::<_, Error>(hello)
+        };
+        let hello = tokio::time::timeout(self.timeout, handshake)
+            .await
+            .map_err(|_| Error::Timeout)??;
+        debug!(?hello, "relay speaks our protocol");
+
+        Ok((handle, hello.capabilities))
     }
 
-    #[instrument(skip(self, connection))]
-    async fn receive_data<T: DeserializeOwned>(&self, connection: &mut Connection) -> Result<T> {
-        let recv_stream = connection
-            .accept_receive_stream()
-            .await
-            .map_err(QuicError::from)?;
+    /// Opens a stream for a single request, connecting first if there is no connection yet or
+    /// the last one was lost
+    #[instrument(skip(self))]
+    async fn open_stream(&self) -> Result<(connection::Handle, Capabilities, BidirectionalStream)> {
+        let mut connection = self.connection.lock().await;
 
-        let mut recv_stream = match recv_stream {
-            Some(value) => value,
-            None => {
-                warn!("connection closed prematurely");
-                return Err(Error::PrematureClosure);
+        if let Some((handle, capabilities)) = connection.as_mut() {
+            match handle.open_bidirectional_stream().await {
+                Ok(stream) => return Ok((handle.clone(), *capabilities, stream)),
+                Err(error) => debug!("connection to the relay was lost, reconnecting: {error}"),
             }
-        };
+        }
 
-        trace!("waiting for a response");
-        let res = wire::deserialise_stream(&mut recv_stream).await?;
+        let (mut handle, capabilities) = self.connect().await?;
+        let stream = handle
+            .open_bidirectional_stream()
+            .await
+            .map_err(QuicError::from)?;
+        *connection = Some((handle.clone(), capabilities));
 
-        Ok(res)
+        Ok((handle, capabilities, stream))
     }
 
-    async fn send_and_recv<T: DeserializeOwned>(
+    /// Sends `action` and waits for the answer, both on `stream`
+    #[instrument(skip_all)]
+    async fn exchange<T: Message>(
         &self,
-        connection: &mut Connection,
+        stream: &mut BidirectionalStream,
         action: Action,
     ) -> Result<T> {
-        self.send_action(connection, action).await?;
-        let res = self.receive_data(connection).await?;
-        Ok(res)
+        let exchange = async {
+            trace!("trying to serialize data");
+            wire::serialise_stream(stream, &action).await?;
+
+            trace!("waiting for a response");
+            wire::deserialise_stream(stream).await
+        };
+
+        tokio::time::timeout(self.timeout, exchange)
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    async fn send_and_recv<T: Message>(&self, action: Action) -> Result<T> {
+        let (_, _, mut stream) = self.open_stream().await?;
+        self.exchange(&mut stream, action).await
     }
 }
 
 impl ServerApi for Client {
-    #[instrument(skip(self))]
-    async fn login(&self, username: &str) -> Result<LoginResp> {
-        trace!("trying to log in user");
+    #[instrument(skip(self, password))]
+    async fn register(&self, username: &str, password: &str, invite: Option<&str>) -> Result {
+        trace!("trying to register user");
 
-        let mut connection = self.get_connection().await?;
+        let action = Action::Register {
+            name: username.to_string(),
+            password: password.to_string(),
+            invite: invite.map(str::to_string),
+        };
+
+        let res: Response = self.send_and_recv(action).await?;
+        res?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self, password))]
+    async fn login(
+        &self,
+        username: &str,
+        password: &str,
+        network: Option<&str>,
+        address: Option<Ipv4Addr>,
+    ) -> Result<LoginResp> {
+        trace!("trying to log in user");
 
         let action = Action::Login {
             name: username.to_string(),
+            password: password.to_string(),
+            network: network.map(str::to_string),
+            address,
         };
 
-        let res: LoginResp = self.send_and_recv(&mut connection, action).await?;
+        let res: Response<LoginResp> = self.send_and_recv(action).await?;
+        let res = res?;
 
         debug!(
             "server assigned ip: {}, mask: {} for {}",
@@ -118,29 +234,126 @@ impl ServerApi for Client {
         Ok(res)
     }
 
-    #[instrument(skip(self))]
-    async fn upgrade_conn(&self, token: &str) -> Result<BidirectionalStream> {
+    #[instrument(skip_all)]
+    async fn refresh(&self, token: &str) -> Result<TokenResp> {
+        trace!("trying to refresh token");
+
+        let action = Action::Refresh {
+            token: token.to_string(),
+        };
+
+        let res: Response<TokenResp> = self.send_and_recv(action).await?;
+        let res = res?;
+
+        debug!("token is valid until {}", res.expires_at);
+
+        Ok(res)
+    }
+
+    #[instrument(skip_all)]
+    async fn logout(&self, token: &str) -> Result {
+        trace!("trying to log out");
+
+        let action = Action::Logout {
+            token: token.to_string(),
+        };
+
+        let res: Response = self.send_and_recv(action).await?;
+        res?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn upgrade_conn(&self, token: &str) -> Result<Tunnel> {
         let token = token.to_string();
-        let mut connection = self.get_connection().await?;
+        let (connection, capabilities, mut stream) = self.open_stream().await?;
 
-        info!("negotiating parameters for a bi-directional stream over an uni-directional channel");
-        self.send_action(&mut connection, Action::UpgradeConn { token })
+        info!("asking the relay to carry our packets on the connection");
+        let res: Response = self
+            .exchange(&mut stream, Action::UpgradeConn { token })
             .await?;
-        info!("one-way connection has been dropped upgrading to a bi-directional one");
+        res?;
+        info!("relay accepted the upgrade, the stream now carries packets");
 
-        // everything after this is handeled by server handler
-        let res = connection.accept_bidirectional_stream().await;
+        Ok(Tunnel::new(connection, stream, capabilities))
+    }
 
-        let bi = match res {
-            Ok(Some(value)) => value,
-            Ok(None) => todo!(),
-            Err(_error) => todo!(),
+    #[instrument(skip(self, token))]
+    async fn create_network(&self, token: &str, name: &str, subnet: Option<Ipv4Net>) -> Result {
+        let action = Action::CreateNetwork {
+            token: token.to_string(),
+            name: name.to_string(),
+            subnet,
         };
 
-        if let Err(error) = connection.keep_alive(true) {
-            error!("Connection::keep_alive failed: {error}");
-        }
+        let res: Response = self.send_and_recv(action).await?;
+        res?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self, token))]
+    async fn invite_to_network(&self, token: &str, name: &str, username: &str) -> Result {
+        let action = Action::InviteToNetwork {
+            token: token.to_string(),
+            name: name.to_string(),
+            username: username.to_string(),
+        };
+
+        let res: Response = self.send_and_recv(action).await?;
+        res?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self, token))]
+    async fn join_network(&self, token: &str, name: &str) -> Result {
+        let action = Action::JoinNetwork {
+            token: token.to_string(),
+            name: name.to_string(),
+        };
+
+        let res: Response = self.send_and_recv(action).await?;
+        res?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self, token))]
+    async fn leave_network(&self, token: &str, name: &str) -> Result {
+        let action = Action::LeaveNetwork {
+            token: token.to_string(),
+            name: name.to_string(),
+        };
+
+        let res: Response = self.send_and_recv(action).await?;
+        res?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn list_peers(&self, token: &str) -> Result<Vec<PeerInfo>> {
+        let action = Action::ListPeers {
+            token: token.to_string(),
+        };
+
+        let res: Response<Vec<PeerInfo>> = self.send_and_recv(action).await?;
+        Ok(res?)
+    }
+
+    #[instrument(skip_all)]
+    async fn watch_peers(&self, token: &str) -> Result<PeerEvents> {
+        let token = token.to_string();
+        let (connection, _, mut stream) = self.open_stream().await?;
+
+        let res: Response = self
+            .exchange(&mut stream, Action::WatchPeers { token })
+            .await?;
+        res?;
+        debug!("relay accepted the watch, the stream now carries peer events");
 
-        Ok(bi)
+        Ok(PeerEvents::new(connection, stream))
     }
 }
@@ -0,0 +1,233 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use ipnet::Ipv4Net;
+use serde::{Deserialize, Serialize};
+
+use crate::error::*;
+
+/// Everything a relay needs to start. Usually read from a TOML file, with command line flags
+/// layered on top, but tests can just as well build one by hand.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// UDP address the QUIC endpoint binds to. Use port 0 to let the OS pick one.
+    pub listen_addr: SocketAddr,
+    /// sqlite database holding users and their leases
+    pub db_path: PathBuf,
+    /// Unix socket `relay-admin` connects to. Only the user the relay runs as may use it.
+    pub admin_socket: PathBuf,
+    /// TCP address to serve Prometheus metrics on, at `/metrics`. Off if not set. Anyone who can
+    /// reach it sees every username, so keep it on loopback or behind a firewall.
+    pub metrics_addr: Option<SocketAddr>,
+    /// PEM encoded certificate chain, reloaded on SIGHUP. If neither it nor `key_path` exist, a
+    /// self-signed pair is generated on startup.
+    pub cert_path: PathBuf,
+    /// PEM encoded private key for `cert_path`
+    pub key_path: PathBuf,
+    /// Names a generated self-signed certificate is valid for. Clients have to connect using one
+    /// of these as their server name.
+    pub cert_names: Vec<String>,
+    /// Virtual network that peers get their addresses from. The default is the carrier-grade NAT
+    /// range, which is never routed on the internet and rarely used on home networks.
+    pub subnet: Ipv4Net,
+    /// Whether users may ask for a specific address when logging in
+    pub static_addresses: bool,
+    /// Whether to offer QUIC datagrams for tunnel packets. Peers fall back to a stream when this
+    /// is off, at the cost of one lost packet holding up every packet behind it.
+    pub datagrams: bool,
+    /// Whether to log every packet dropped for not coming from its sender's own address. They
+    /// are counted either way.
+    pub log_spoofed: bool,
+    /// Who may create new accounts
+    pub registration: RegistrationPolicy,
+    /// What logging in does to the sessions a user already has
+    pub session_policy: SessionPolicy,
+    /// How long a session token stays valid without a `Refresh`
+    pub token_ttl_secs: u64,
+    /// How long a peer's connection may go quiet before the relay considers it dead. Daemons
+    /// send keep-alives well within this, so only peers that are really gone hit it.
+    pub idle_timeout_secs: u64,
+    /// How long a new connection may take over each step of its hello, opening its stream and
+    /// sending the hello itself, and how long any request may take to arrive once its stream is
+    /// open. Clients that stall are dropped.
+    pub handshake_timeout_secs: u64,
+    /// How long a disconnected peer keeps its session and address. Reconnecting within this
+    /// picks up where it left off, after it the session ends as if it logged out.
+    pub lease_grace_secs: u64,
+    /// How long a connected peer may send no packets before `ListPeers` reports it as idle
+    pub peer_idle_secs: u64,
+    /// `tracing` filter directive, e.g. `info` or `relay_server=debug,info`
+    pub log_level: String,
+    pub limits: Limits,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum RegistrationPolicy {
+    /// anyone who can reach the relay may register
+    #[default]
+    Open,
+    /// registering requires a code from `relay-server invite`
+    InviteOnly,
+    /// only existing users may log in
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SessionPolicy {
+    /// a new login logs out every other session of that user in the same network,
+    /// disconnecting them
+    #[default]
+    Replace,
+    /// old sessions stay valid until they expire or log out
+    Keep,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// make room by dropping the packet that has waited longest, stale game state is worth
+    /// less than fresh
+    #[default]
+    DropOldest,
+    /// keep what is queued and drop the packet that did not fit
+    DropNewest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Maximum number of registered users. `0` means unlimited.
+    pub max_users: u32,
+    /// How many freshly upgraded peers may wait for the router before `UpgradeConn` stalls
+    pub routing_backlog: usize,
+    /// Broadcast and multicast packets a single peer may send per second, on average. Each one
+    /// is copied to every other peer in the network, so this is kept well below what unicast
+    /// gets. `0` means unlimited.
+    pub broadcast_per_sec: u32,
+    /// How many broadcasts a peer may send at once before `broadcast_per_sec` kicks in
+    pub broadcast_burst: u32,
+    /// ICMP host unreachable errors the relay sends a single peer per second, on average, for
+    /// packets to addresses nobody in its network has. Packets past this are dropped without
+    /// one. `0` means unlimited.
+    pub unreachable_per_sec: u32,
+    /// How many errors a peer may get at once before `unreachable_per_sec` kicks in
+    pub unreachable_burst: u32,
+    /// Logins and registrations a single address may attempt per second, on average. `0`
+    /// means unlimited.
+    pub auth_per_sec: u32,
+    /// How many attempts an address may make at once before `auth_per_sec` kicks in
+    pub auth_burst: u32,
+    /// Packets waiting to be written to a single peer. A peer that cannot keep up loses packets
+    /// past this point instead of slowing down everyone sending to it.
+    pub peer_queue: usize,
+    /// Which packet a full peer queue drops
+    pub peer_queue_overflow: OverflowPolicy,
+    /// Peer events a watching daemon may fall behind by. One that falls further behind has its
+    /// event stream ended, and has to watch again to catch up.
+    pub peer_events: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 4433)),
+            db_path: PathBuf::from("lanshare-relay.db"),
+            admin_socket: PathBuf::from("lanshare-relay.sock"),
+            metrics_addr: None,
+            cert_path: PathBuf::from("lanshare-relay.crt"),
+            key_path: PathBuf::from("lanshare-relay.key"),
+            cert_names: vec!["localhost".to_string()],
+            subnet: Ipv4Net::new(Ipv4Addr::new(100, 64, 0, 0), 10).expect("infailable"),
+            static_addresses: true,
+            datagrams: true,
+            log_spoofed: false,
+            registration: RegistrationPolicy::default(),
+            session_policy: SessionPolicy::default(),
+            token_ttl_secs: 7 * 24 * 60 * 60,
+            idle_timeout_secs: 30,
+            handshake_timeout_secs: 10,
+            lease_grace_secs: 120,
+            peer_idle_secs: 300,
+            log_level: "info".to_string(),
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_users: 0,
+            routing_backlog: 16,
+            broadcast_per_sec: 50,
+            broadcast_burst: 100,
+            unreachable_per_sec: 10,
+            unreachable_burst: 20,
+            auth_per_sec: 1,
+            auth_burst: 20,
+            peer_queue: 256,
+            peer_queue_overflow: OverflowPolicy::default(),
+            peer_events: 256,
+        }
+    }
+}
+
+impl Config {
+    #[instrument]
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(Error::ConfigIoError)?;
+        let config = toml::from_str(&contents)?;
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_config_is_default() {
+        let config: Config = toml::from_str("").unwrap();
+        let default = Config::default();
+
+        assert_eq!(config.listen_addr, default.listen_addr);
+        assert_eq!(config.subnet, default.subnet);
+        assert_eq!(config.limits.max_users, default.limits.max_users);
+    }
+
+    #[test]
+    fn test_partial_config() {
+        let config: Config = toml::from_str(
+            r#"
+            listen_addr = "127.0.0.1:9000"
+            subnet = "10.10.0.0/16"
+            registration = "invite-only"
+
+            [limits]
+            max_users = 8
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.listen_addr, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.subnet, "10.10.0.0/16".parse().unwrap());
+        assert_eq!(config.registration, RegistrationPolicy::InviteOnly);
+        assert_eq!(config.limits.max_users, 8);
+        assert_eq!(
+            config.limits.routing_backlog,
+            Limits::default().routing_backlog
+        );
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        let res: Result<Config, _> = toml::from_str("listen = \"127.0.0.1:9000\"");
+        assert!(res.is_err());
+    }
+}
@@ -1,37 +1,207 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use rusqlite::Connection;
 use tokio::sync::Mutex;
 
-use crate::error::*;
+use crate::{
+    error::*,
+    ipalloc::{IpAllocator, SequentialAllocator},
+};
+
+/// Forward-only schema migrations, applied in order. `PRAGMA user_version` records how many of
+/// these a database has already seen, so a migration must never be edited once it has shipped.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../schemas/0001-user-table.sql"),
+    include_str!("../schemas/0002-accounts.sql"),
+    include_str!("../schemas/0003-sessions.sql"),
+    include_str!("../schemas/0004-networks.sql"),
+    include_str!("../schemas/0005-bans.sql"),
+    include_str!("../schemas/0006-network-invites.sql"),
+];
 
 #[derive(Clone)]
 pub struct Db {
     pub(crate) db_conn: Arc<Mutex<Connection>>,
+    pub(crate) allocator: Arc<dyn IpAllocator>,
+    path: Option<Arc<PathBuf>>,
 }
 
 impl Debug for Db {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "(in memory sqlite db)")
+        match &self.path {
+            Some(path) => write!(f, "(sqlite db at {})", path.display()),
+            None => write!(f, "(in memory sqlite db)"),
+        }
     }
 }
 
 impl Db {
-    pub async fn try_new() -> Result<Self> {
+    /// Opens (or creates) the database at `path` and brings its schema up to date.
+    #[instrument]
+    pub async fn try_new(path: &Path) -> Result<Self> {
+        let db = Connection::open(path)?;
+        db.pragma_update(None, "journal_mode", "wal")?;
+
+        Self::from_connection(db, Some(path.to_path_buf()))
+    }
+
+    /// Same as [`Db::try_new`], but nothing survives the process.
+    #[cfg(test)]
+    pub async fn try_new_in_memory() -> Result<Self> {
         let db = Connection::open_in_memory()?;
 
+        Self::from_connection(db, None)
+    }
+
+    fn from_connection(mut db: Connection, path: Option<PathBuf>) -> Result<Self> {
+        migrate(&mut db)?;
+        db.pragma_update(None, "foreign_keys", true)?;
+
+        let users: u64 = db.query_row("select count(*) from users", [], |row| row.get(0))?;
+        info!("database ready, {users} existing users");
+
         let db = Self {
             db_conn: Arc::new(Mutex::new(db)),
+            allocator: Arc::new(SequentialAllocator),
+            path: path.map(Arc::new),
         };
 
         Ok(db)
     }
 
-    pub async fn load_schema(&self, schema: &str) -> Result {
-        let db = self.db_conn.lock().await;
-        let mut stmt = db.prepare(schema).map_err(Error::SchemaError)?;
-        stmt.execute([]).map_err(Error::SchemaError)?;
+    /// Replaces the default [`SequentialAllocator`] used for new leases
+    pub fn with_allocator(mut self, allocator: Arc<dyn IpAllocator>) -> Self {
+        self.allocator = allocator;
+        self
+    }
+}
+
+fn schema_version(db: &Connection) -> Result<usize> {
+    let version: usize = db.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    Ok(version)
+}
+
+#[instrument(skip(db))]
+fn migrate(db: &mut Connection) -> Result {
+    let current = schema_version(db)?;
+    let supported = MIGRATIONS.len();
+
+    if current > supported {
+        error!("database was written by a newer relay");
+        return Err(Error::SchemaTooNew { current, supported });
+    }
+
+    // Rebuilding a table means dropping it while other tables still point at it, which sqlite
+    // only allows with foreign keys off. They are checked by hand before each commit instead.
+    // This pragma is a no-op inside a transaction, so it has to happen out here.
+    db.pragma_update(None, "foreign_keys", false)
+        .map_err(Error::SchemaError)?;
+
+    for (version, schema) in MIGRATIONS.iter().enumerate().skip(current) {
+        let version = version + 1;
+        debug!("migrating database schema to version {version}");
+
+        let tx = db.transaction().map_err(Error::SchemaError)?;
+        tx.execute_batch(schema).map_err(Error::SchemaError)?;
+
+        let dangling: bool = tx
+            .prepare("pragma foreign_key_check")
+            .and_then(|mut stmt| stmt.exists([]))
+            .map_err(Error::SchemaError)?;
+        if dangling {
+            error!("migration left dangling foreign keys");
+            return Err(Error::DanglingForeignKeys { version });
+        }
+
+        tx.pragma_update(None, "user_version", version)
+            .map_err(Error::SchemaError)?;
+        tx.commit().map_err(Error::SchemaError)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::config::Config;
+
+    #[tokio::test]
+    async fn test_fresh_db_is_fully_migrated() {
+        let db = Db::try_new_in_memory().await.unwrap();
+        let conn = db.db_conn.lock().await;
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+    }
+
+    #[tokio::test]
+    async fn test_users_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay.db");
+
+        let config = Config::default();
+        let db = Db::try_new(&path).await.unwrap();
+        db.register("alice", "hunter2", None, &config)
+            .await
+            .unwrap();
+        let (resp, _) = db
+            .login("alice", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+        drop(db);
+
+        let db = Db::try_new(&path).await.unwrap();
+        let session = db.session(&resp.token).await.unwrap();
+        assert_eq!(session.address, resp.address);
+    }
+
+    #[tokio::test]
+    async fn test_leases_move_to_default_network() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay.db");
+
+        let conn = Connection::open(&path).unwrap();
+        for schema in &MIGRATIONS[..3] {
+            conn.execute_batch(schema).unwrap();
+        }
+        conn.pragma_update(None, "user_version", 3).unwrap();
+        conn.execute(
+            "insert into users (username, ip) values ('alice', ?1)",
+            [Ipv4Addr::new(25, 0, 0, 7).to_bits()],
+        )
+        .unwrap();
+        drop(conn);
+
+        let db = Db::try_new(&path).await.unwrap();
+        let conn = db.db_conn.lock().await;
+        let (network, ip): (String, u32) = conn
+            .query_row(
+                "select networks.name, memberships.ip from memberships
+                    join networks on networks.id = memberships.network_id",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(network, crate::network::DEFAULT_NETWORK);
+        assert_eq!(Ipv4Addr::from_bits(ip), Ipv4Addr::new(25, 0, 0, 7));
+    }
+
+    #[tokio::test]
+    async fn test_refuses_newer_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay.db");
+
+        let conn = Connection::open(&path).unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        drop(conn);
 
-        Ok(())
+        let res = Db::try_new(&path).await;
+        assert!(matches!(res, Err(Error::SchemaTooNew { .. })));
     }
 }
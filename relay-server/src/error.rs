@@ -10,18 +10,88 @@ pub enum Error {
     SqliteError(#[from] rusqlite::Error),
     #[error("schema error: {}", 0)]
     SchemaError(rusqlite::Error),
+    #[error("database schema is at version {current}, but this relay only knows {supported}")]
+    SchemaTooNew { current: usize, supported: usize },
+    #[error("migrating to schema version {version} would leave dangling foreign keys")]
+    DanglingForeignKeys { version: usize },
     #[error("sql error: {}", 0)]
     SqlError(rusqlite::Error),
     #[error("user already exists")]
     UserAlreadyExists,
-    #[error("bincode error: {}", 0)]
-    BincodeError(#[from] bincode::Error),
+    #[error("relay has reached its user limit")]
+    ServerFull,
+    #[error("requested address is reserved, taken or outside the subnet")]
+    AddressUnavailable,
+    #[error("every address in the subnet is leased out")]
+    SubnetExhausted,
+    #[error("no network with that name")]
+    NoSuchNetwork,
+    #[error("a network with that name already exists")]
+    NetworkAlreadyExists,
+    #[error("not a member of that network")]
+    NotAMember,
+    #[error("not invited to that network")]
+    NotInvited,
+    #[error("only the network's owner may do that")]
+    NotTheOwner,
+    #[error("subnet is too small, or outside the private and carrier-grade NAT ranges")]
+    InvalidSubnet,
+    #[error("no user with that name")]
+    NoSuchUser,
+    #[error("wrong username or password")]
+    InvalidCredentials,
+    #[error("token is unknown, expired or revoked")]
+    InvalidToken,
+    #[error("registration is closed on this relay")]
+    RegistrationClosed,
+    #[error("invite code is missing, unknown or already used")]
+    InvalidInvite,
+    #[error("too many attempts, try again later")]
+    RateLimited,
+    #[error("relay speaks a different protocol version")]
+    VersionMismatch,
+    #[error("relay could not understand the request")]
+    BadRequest,
+    #[error("relay could not process the request")]
+    InternalServerError,
+    #[error("password hash error: {0}")]
+    PasswordHashError(#[from] argon2::password_hash::Error),
+    #[error("could not access certificate: {0}")]
+    CertIoError(io::Error),
+    #[error("only one of the certificate and key exist, refusing to generate the other")]
+    IncompleteCertPair,
+    #[error("could not generate certificate: {0}")]
+    CertGenError(#[from] rcgen::Error),
+    #[error("could not read config: {0}")]
+    ConfigIoError(io::Error),
+    #[error("invalid config: {0}")]
+    ConfigError(#[from] toml::de::Error),
+    #[error("could not decode message: {0}")]
+    DecodeError(#[from] prost::DecodeError),
+    #[error("message of {len} bytes is larger than the protocol allows")]
+    MessageTooLarge { len: usize },
+    #[error("message is missing {0}, or it is invalid")]
+    InvalidMessage(&'static str),
     #[error("data had insufficient len bytes")]
     InsufficientLenBytes,
     #[error("wire error: {}", 0)]
     WireError(io::Error),
+    #[error("timed out waiting for the other end")]
+    Timeout,
     #[error("server closed the connection prematurely")]
     PrematureClosure,
+    #[error("packet of {len} bytes is too large to frame")]
+    PacketTooLarge { len: usize },
+    #[error("tunnel stream lost its framing, skipped {skipped} bytes without finding a packet")]
+    Desynced { skipped: usize },
+    #[error("could not use admin socket: {0}")]
+    AdminSocketError(io::Error),
+    #[error("admin socket is in use, is another relay running?")]
+    AdminSocketInUse,
+    #[error("relay refused: {0}")]
+    AdminRefused(String),
+    #[error("could not bind metrics listener: {0}")]
+    MetricsError(io::Error),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -32,4 +102,8 @@ pub enum QuicError {
     StartError(#[from] s2n_quic::provider::StartError),
     #[error(transparent)]
     ConnectionError(#[from] s2n_quic::connection::Error),
+    #[error("tls error: {0}")]
+    TlsError(String),
+    #[error("invalid connection limits: {0}")]
+    LimitsError(String),
 }
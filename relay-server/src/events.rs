@@ -0,0 +1,317 @@
+//! Who else is on a network. A daemon asks with `WatchPeers`, and the relay answers with a
+//! [`PeerEvent`] for every peer already there. It then keeps the request's stream open and pushes
+//! another one whenever a peer joins, leaves or changes address. The daemon folds them into a
+//! [`PeerTable`]. The watcher's own session only ever shows up in a `Moved`, which is how it
+//! learns that an operator moved its address.
+
+use std::{collections::HashMap, net::Ipv4Addr, sync::Arc};
+
+use s2n_quic::{connection, stream::BidirectionalStream};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite},
+    sync::broadcast::{self, error::RecvError},
+};
+
+use crate::{error::*, wire};
+
+/// Something that happened to another peer in the watcher's network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    Joined {
+        username: String,
+        address: Ipv4Addr,
+    },
+    Left {
+        username: String,
+        address: Ipv4Addr,
+    },
+    /// the peer is still there, under a new address
+    Moved {
+        username: String,
+        from: Ipv4Addr,
+        to: Ipv4Addr,
+    },
+}
+
+/// The other peers in a network by address, as far as the events so far tell
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerTable(HashMap<Ipv4Addr, String>);
+
+impl PeerTable {
+    pub fn apply(&mut self, event: &PeerEvent) {
+        match event {
+            PeerEvent::Joined { username, address } => {
+                self.0.insert(*address, username.clone());
+            }
+            PeerEvent::Left { address, .. } => {
+                self.0.remove(address);
+            }
+            PeerEvent::Moved { username, from, to } => {
+                self.0.remove(from);
+                self.0.insert(*to, username.clone());
+            }
+        }
+    }
+
+    /// Username of the peer at `address`
+    pub fn get(&self, address: Ipv4Addr) -> Option<&str> {
+        self.0.get(&address).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Ipv4Addr, &str)> {
+        self.0
+            .iter()
+            .map(|(address, username)| (*address, username.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// The client's end of a `WatchPeers`, see [`crate::client::ServerApi::watch_peers`]
+#[derive(Debug)]
+pub struct PeerEvents {
+    /// keeps the connection up for as long as the events are wanted
+    _connection: connection::Handle,
+    stream: BidirectionalStream,
+}
+
+impl PeerEvents {
+    pub(crate) fn new(connection: connection::Handle, stream: BidirectionalStream) -> Self {
+        Self {
+            _connection: connection,
+            stream,
+        }
+    }
+
+    /// Waits for the next event. `None` once the relay ends the stream, because the session
+    /// ended or because the watcher fell too far behind.
+    pub async fn next(&mut self) -> Result<Option<PeerEvent>> {
+        match wire::deserialise_stream(&mut self.stream).await {
+            Ok(event) => Ok(Some(event)),
+            // the stream ended between two events
+            Err(Error::InsufficientLenBytes) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// What `handle_routing` tells watchers about
+#[derive(Debug, Clone)]
+pub(crate) enum RelayEvent {
+    Peer {
+        network: i64,
+        session: i64,
+        event: PeerEvent,
+    },
+    /// these sessions ended, whoever watches with one of them stops
+    Ended(Arc<[i64]>),
+}
+
+/// A watcher's view of the relay's events, starting with a `Joined` for every peer that was
+/// already there when it subscribed
+#[derive(Debug)]
+pub struct Subscription {
+    pub(crate) snapshot: Vec<RelayEvent>,
+    pub(crate) events: broadcast::Receiver<RelayEvent>,
+}
+
+/// Sends the watcher the events of its network on `send`, until its session ends, it falls
+/// behind by more than the events channel holds, or `recv` tells that it went away
+#[instrument(skip(recv, send, subscription))]
+pub(crate) async fn watch<R, W>(
+    mut recv: R,
+    mut send: W,
+    session: i64,
+    network: i64,
+    subscription: Subscription,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let Subscription {
+        snapshot,
+        mut events,
+    } = subscription;
+
+    for event in &snapshot {
+        if !forward(&mut send, session, network, event).await {
+            return;
+        }
+    }
+
+    let mut buf = [0; 1];
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            // nothing follows the request, so this only returns once the watcher is gone
+            _ = recv.read(&mut buf) => return debug!("watcher went away"),
+        };
+
+        let event = match event {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                return warn!(missed, "watcher fell behind, ending its stream");
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        if !forward(&mut send, session, network, &event).await {
+            return;
+        }
+    }
+}
+
+/// Passes `event` on if it concerns the watcher, which its own session only does when it moves.
+/// Returns whether the watcher still wants more.
+async fn forward<W>(send: &mut W, session: i64, network: i64, event: &RelayEvent) -> bool
+where
+    W: AsyncWrite + Unpin,
+{
+    match event {
+        RelayEvent::Peer {
+            network: theirs,
+            session: peer,
+            event,
+        } if *theirs == network
+            && (*peer != session || matches!(event, PeerEvent::Moved { .. })) =>
+        {
+            match wire::serialise_stream(send, event).await {
+                Ok(()) => true,
+                Err(error) => {
+                    debug!("could not send peer event: {error}");
+                    false
+                }
+            }
+        }
+        RelayEvent::Peer { .. } => true,
+        RelayEvent::Ended(sessions) => !sessions.contains(&session),
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use rstest::*;
+    use tokio::io::{AsyncWriteExt as _, DuplexStream};
+
+    fn joined(username: &str, address: [u8; 4]) -> PeerEvent {
+        PeerEvent::Joined {
+            username: username.to_string(),
+            address: address.into(),
+        }
+    }
+
+    fn left(username: &str, address: [u8; 4]) -> PeerEvent {
+        PeerEvent::Left {
+            username: username.to_string(),
+            address: address.into(),
+        }
+    }
+
+    fn moved(username: &str, from: [u8; 4], to: [u8; 4]) -> PeerEvent {
+        PeerEvent::Moved {
+            username: username.to_string(),
+            from: from.into(),
+            to: to.into(),
+        }
+    }
+
+    #[rstest]
+    #[case::joined(vec![joined("bob", [10, 0, 0, 2])], &[("bob", [10, 0, 0, 2])])]
+    #[case::left(vec![joined("bob", [10, 0, 0, 2]), left("bob", [10, 0, 0, 2])], &[])]
+    #[case::moved(
+        vec![joined("bob", [10, 0, 0, 2]), moved("bob", [10, 0, 0, 2], [10, 0, 0, 9])],
+        &[("bob", [10, 0, 0, 9])]
+    )]
+    #[case::rejoined_elsewhere(
+        vec![joined("bob", [10, 0, 0, 2]), left("bob", [10, 0, 0, 2]), joined("bob", [10, 0, 0, 3])],
+        &[("bob", [10, 0, 0, 3])]
+    )]
+    fn test_peer_table(#[case] events: Vec<PeerEvent>, #[case] expected: &[(&str, [u8; 4])]) {
+        let mut table = PeerTable::default();
+        for event in &events {
+            table.apply(event);
+        }
+
+        assert_eq!(table.len(), expected.len());
+        for (username, address) in expected {
+            assert_eq!(table.get((*address).into()), Some(*username));
+        }
+    }
+
+    fn peer(network: i64, session: i64, event: PeerEvent) -> RelayEvent {
+        RelayEvent::Peer {
+            network,
+            session,
+            event,
+        }
+    }
+
+    async fn received(mut send: DuplexStream) -> Vec<PeerEvent> {
+        let mut events = Vec::new();
+        while let Ok(event) = wire::deserialise_stream(&mut send).await {
+            events.push(event);
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn test_watch_only_forwards_its_network() {
+        let (tx, events) = broadcast::channel(16);
+        let subscription = Subscription {
+            snapshot: vec![peer(1, 2, joined("bob", [10, 0, 0, 2]))],
+            events,
+        };
+        let (_client_recv, recv) = tokio::io::duplex(64);
+        let (client_send, send) = tokio::io::duplex(4096);
+
+        let watcher = tokio::spawn(watch(recv, send, 1, 1, subscription));
+        // our own, another network's, then one for us and our own move
+        tx.send(peer(1, 1, joined("alice", [10, 0, 0, 1]))).unwrap();
+        tx.send(peer(2, 3, joined("carol", [10, 0, 0, 3]))).unwrap();
+        tx.send(peer(1, 2, left("bob", [10, 0, 0, 2]))).unwrap();
+        tx.send(peer(1, 1, moved("alice", [10, 0, 0, 1], [10, 0, 0, 9])))
+            .unwrap();
+        tx.send(RelayEvent::Ended(Arc::new([1]))).unwrap();
+        watcher.await.unwrap();
+
+        assert_eq!(
+            received(client_send).await,
+            [
+                joined("bob", [10, 0, 0, 2]),
+                left("bob", [10, 0, 0, 2]),
+                moved("alice", [10, 0, 0, 1], [10, 0, 0, 9])
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_stops_when_the_watcher_goes_away() {
+        let (_tx, events) = broadcast::channel(16);
+        let subscription = Subscription {
+            snapshot: Vec::new(),
+            events,
+        };
+        let (mut client_recv, recv) = tokio::io::duplex(64);
+        let (_client_send, send) = tokio::io::duplex(64);
+
+        let watcher = tokio::spawn(watch(recv, send, 1, 1, subscription));
+        client_recv.shutdown().await.unwrap();
+        drop(client_recv);
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), watcher)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
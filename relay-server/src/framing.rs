@@ -0,0 +1,241 @@
+//! Packet framing for the tunnel stream. Datagrams keep packet boundaries on their own, a stream
+//! does not, so every packet on it is sent as a big endian `u16` length followed by the packet.
+//!
+//! The length is checked against the length field in the packet's own IP header. If the two
+//! disagree the stream has lost its framing, most likely because of a bug on the other end. The
+//! decoder then skips ahead one byte at a time until it finds a frame that checks out again, and
+//! gives up on the stream after [`MAX_RESYNC`] bytes without one.
+
+use bytes::{Buf as _, Bytes, BytesMut};
+
+use crate::error::*;
+
+const PREFIX_LEN: usize = 2;
+
+/// How much garbage the decoder wades through before calling the stream broken
+pub const MAX_RESYNC: usize = 64 * 1024;
+
+/// Prefixes `pkt` with its length, ready to be written to the stream in one go
+pub fn encode(pkt: &[u8]) -> Result<Vec<u8>> {
+    let len = u16::try_from(pkt.len()).map_err(|_| Error::PacketTooLarge { len: pkt.len() })?;
+
+    let mut frame = Vec::with_capacity(PREFIX_LEN + pkt.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(pkt);
+
+    Ok(frame)
+}
+
+/// Turns whatever the stream hands out, in whatever pieces, back into whole packets
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: BytesMut,
+    /// bytes thrown away since the last good frame
+    skipped: usize,
+}
+
+enum Check {
+    Valid,
+    Invalid,
+    /// not enough of the header yet to tell
+    Incomplete,
+}
+
+impl FrameDecoder {
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Whether part of a packet is still waiting for the rest of it
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Returns the next whole packet, or `None` until more data arrives
+    pub fn decode(&mut self) -> Result<Option<Bytes>> {
+        loop {
+            if self.buf.len() < PREFIX_LEN {
+                return Ok(None);
+            }
+
+            let len = u16::from_be_bytes([self.buf[0], self.buf[1]]) as usize;
+
+            // the header is checked before waiting for the whole packet, or a garbage length
+            // could stall the stream until 64 KiB of data arrive
+            match check(len, &self.buf[PREFIX_LEN..]) {
+                Check::Incomplete => return Ok(None),
+                Check::Valid if self.buf.len() < PREFIX_LEN + len => return Ok(None),
+                Check::Valid => {
+                    if self.skipped != 0 {
+                        warn!(skipped = self.skipped, "tunnel stream resynchronised");
+                        self.skipped = 0;
+                    }
+
+                    let mut frame = self.buf.split_to(PREFIX_LEN + len);
+                    frame.advance(PREFIX_LEN);
+                    return Ok(Some(frame.freeze()));
+                }
+                Check::Invalid => {
+                    self.buf.advance(1);
+                    self.skipped += 1;
+
+                    if self.skipped > MAX_RESYNC {
+                        error!("tunnel stream lost its framing for good");
+                        return Err(Error::Desynced {
+                            skipped: self.skipped,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Whether a frame of `len` bytes starting with `pkt` holds exactly one IP packet
+fn check(len: usize, pkt: &[u8]) -> Check {
+    let Some(first) = pkt.first() else {
+        return Check::Incomplete;
+    };
+
+    match first >> 4 {
+        4 => {
+            let Some(total_len) = pkt.get(2..4) else {
+                return Check::Incomplete;
+            };
+            let total_len = u16::from_be_bytes([total_len[0], total_len[1]]) as usize;
+            let header_len = (first & 0x0f) as usize * 4;
+
+            if header_len >= 20 && len >= header_len && total_len == len {
+                Check::Valid
+            } else {
+                Check::Invalid
+            }
+        }
+        6 => {
+            let Some(payload_len) = pkt.get(4..6) else {
+                return Check::Incomplete;
+            };
+            let payload_len = u16::from_be_bytes([payload_len[0], payload_len[1]]) as usize;
+
+            if len >= 40 && payload_len + 40 == len {
+                Check::Valid
+            } else {
+                Check::Invalid
+            }
+        }
+        _ => Check::Invalid,
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use rstest::*;
+
+    fn ipv4_packet(payload: &[u8]) -> Vec<u8> {
+        let builder = etherparse::PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64).udp(1, 2);
+        let mut packet = Vec::new();
+        builder.write(&mut packet, payload).unwrap();
+        packet
+    }
+
+    fn ipv6_packet(payload: &[u8]) -> Vec<u8> {
+        let builder = etherparse::PacketBuilder::ipv6([1; 16], [2; 16], 64).udp(1, 2);
+        let mut packet = Vec::new();
+        builder.write(&mut packet, payload).unwrap();
+        packet
+    }
+
+    fn decode_all(decoder: &mut FrameDecoder) -> Vec<Bytes> {
+        std::iter::from_fn(|| decoder.decode().unwrap()).collect()
+    }
+
+    #[rstest]
+    #[case::whole(usize::MAX)]
+    #[case::byte_by_byte(1)]
+    #[case::split_in_the_header(3)]
+    #[case::uneven_chunks(7)]
+    fn test_split_reads(#[case] chunk: usize) {
+        let packet = ipv4_packet(b"hello");
+        let frame = encode(&packet).unwrap();
+        let mut decoder = FrameDecoder::default();
+
+        let mut decoded = Vec::new();
+        for piece in frame.chunks(chunk.min(frame.len())) {
+            decoder.extend(piece);
+            decoded.extend(decode_all(&mut decoder));
+        }
+
+        assert_eq!(decoded, vec![Bytes::from(packet)]);
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn test_coalesced_reads() {
+        let packets = [
+            ipv4_packet(b"one"),
+            ipv6_packet(b"two"),
+            ipv4_packet(b"three"),
+        ];
+        let mut decoder = FrameDecoder::default();
+
+        for packet in &packets {
+            decoder.extend(&encode(packet).unwrap());
+        }
+
+        assert_eq!(decode_all(&mut decoder), packets.map(Bytes::from));
+    }
+
+    #[test]
+    fn test_resyncs_after_garbage() {
+        let packet = ipv4_packet(b"hello");
+        let mut decoder = FrameDecoder::default();
+
+        decoder.extend(&[0xff, 0x00, 0x13, 0x37, 0x45]);
+        decoder.extend(&encode(&packet).unwrap());
+
+        assert_eq!(decode_all(&mut decoder), vec![Bytes::from(packet)]);
+        assert_eq!(decoder.skipped, 0);
+    }
+
+    /// A length and header that agree, but are too short to hold the header itself
+    #[rstest]
+    #[case::empty(&[0x00, 0x00, 0x45, 0x00, 0x00, 0x00])]
+    #[case::truncated_ipv4(&[0x00, 0x0c, 0x45, 0x00, 0x00, 0x0c])]
+    #[case::long_ipv4_header(&[0x00, 0x14, 0x46, 0x00, 0x00, 0x14])]
+    fn test_resyncs_after_undersized_length(#[case] garbage: &[u8]) {
+        let packet = ipv4_packet(b"hello");
+        let mut decoder = FrameDecoder::default();
+
+        decoder.extend(garbage);
+        decoder.extend(&encode(&packet).unwrap());
+
+        assert_eq!(decode_all(&mut decoder), vec![Bytes::from(packet)]);
+    }
+
+    #[test]
+    fn test_length_must_match_header() {
+        let packet = ipv4_packet(b"hello");
+        let mut frame = encode(&packet).unwrap();
+        // claims one byte less than the packet holds
+        frame[1] -= 1;
+
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&frame);
+        assert!(decode_all(&mut decoder).is_empty());
+    }
+
+    #[test]
+    fn test_gives_up_on_endless_garbage() {
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&vec![0xff; MAX_RESYNC + 8]);
+
+        assert!(matches!(decoder.decode(), Err(Error::Desynced { .. })));
+    }
+
+    #[test]
+    fn test_oversized_packet_is_refused() {
+        let res = encode(&vec![0; u16::MAX as usize + 1]);
+        assert!(matches!(res, Err(Error::PacketTooLarge { .. })));
+    }
+}
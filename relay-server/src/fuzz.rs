@@ -0,0 +1,103 @@
+//! Entry points for the fuzz targets in `fuzz/`, built by cargo-fuzz and by the tests, which run
+//! the seeds in `fuzz/seeds/` through them. Each one feeds its input to
+//! [`wire::deserialise_stream`] as if it came from a peer, and checks that whatever decodes also
+//! survives being encoded and decoded again.
+
+use std::{
+    future::Future,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+
+use crate::{
+    action::{
+        response::{LoginResp, PeerInfo, Response},
+        Action,
+    },
+    events::PeerEvent,
+    hello::Hello,
+    wire::{self, Message},
+};
+
+/// Runs `future` to completion. Reading from a slice and writing to a `Vec` never wait, so
+/// there is no runtime needed for them.
+fn ready<F: Future>(future: F) -> F::Output {
+    match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("in memory streams never wait"),
+    }
+}
+
+/// Whether `data` decodes as a `T` at all
+fn round_trip<T: Message>(data: &[u8]) -> bool {
+    let Ok(message) = ready(wire::deserialise_stream::<_, T>(&mut &data[..])) else {
+        return false;
+    };
+
+    let mut frame = Vec::new();
+    ready(wire::serialise_stream(&mut frame, &message)).expect("a decoded message encodes");
+    let again: T =
+        ready(wire::deserialise_stream(&mut frame.as_slice())).expect("an encoded message decodes");
+
+    let mut again_frame = Vec::new();
+    ready(wire::serialise_stream(&mut again_frame, &again)).expect("a decoded message encodes");
+    assert_eq!(frame, again_frame, "the message changed on the way");
+    true
+}
+
+pub fn request(data: &[u8]) -> bool {
+    round_trip::<Action>(data)
+}
+
+/// Tries every kind of response, a frame may well decode as more than one
+pub fn response(data: &[u8]) -> bool {
+    round_trip::<Response<LoginResp>>(data)
+        | round_trip::<Response<Vec<PeerInfo>>>(data)
+        | round_trip::<Response>(data)
+}
+
+pub fn hello(data: &[u8]) -> bool {
+    round_trip::<Hello>(data)
+}
+
+pub fn peer_event(data: &[u8]) -> bool {
+    round_trip::<PeerEvent>(data)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::{fs, path::Path};
+
+    use rstest::*;
+
+    use super::*;
+
+    /// The seeds are valid messages to start the fuzzer out with, so every one of them has to
+    /// keep decoding as the protocol changes
+    #[rstest]
+    #[case::request("deserialise_request", request)]
+    #[case::response("deserialise_response", response)]
+    #[case::hello("deserialise_hello", hello)]
+    #[case::peer_event("deserialise_peer_event", peer_event)]
+    fn test_seeds_decode(#[case] target: &str, #[case] decode: fn(&[u8]) -> bool) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fuzz/seeds")
+            .join(target);
+        let seeds: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert!(!seeds.is_empty(), "no seeds in {}", dir.display());
+
+        for seed in seeds {
+            let data = fs::read(&seed).unwrap();
+            assert!(decode(&data), "{} does not decode", seed.display());
+        }
+    }
+
+    #[test]
+    fn test_garbage_does_not_decode() {
+        assert!(!request(b"\x00\x00\x00\x02\xff\xff"));
+        assert!(!hello(&[]));
+    }
+}
@@ -0,0 +1,143 @@
+//! The first thing either side sends on a connection. The relay sends its [`Hello`] before
+//! anything else, the client answers with its own before its first request, and both sides
+//! check that they can understand each other. They do so once per connection, however many
+//! requests follow.
+//!
+//! The encoding of [`Hello`] must never change, or a mismatch could no longer be told apart from
+//! garbage. Everything else on the wire may change, as long as [`PROTOCOL_VERSION`] goes up.
+//!
+//! It changed once, from bincode to protobuf, which broke the wire for good. Clients from before
+//! that send a hello the relay cannot decode, so they are turned away as garbage instead of with
+//! a version mismatch.
+
+use std::ops::{BitAnd, BitOr};
+
+use crate::error::*;
+
+/// Version of everything sent after the hello. Bump it whenever the encoding of an action or a
+/// response changes, the golden tests in `wire` fail until then. Version 1 was bincode, version 2
+/// opened a connection for every action, version 4 added `WatchPeers`, version 5 `ListPeers` and
+/// version 6 `InviteToNetwork`.
+pub const PROTOCOL_VERSION: u16 = 6;
+
+/// Oldest version this build still speaks. Older relays answer the actions they do not know with
+/// `BadRequest`, everything else is the same.
+pub const MIN_PROTOCOL_VERSION: u16 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub min_version: u16,
+    pub capabilities: Capabilities,
+}
+
+/// Optional features, only used when both sides have them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// tunnel packets may travel as QUIC datagrams, see [`crate::tunnel::Tunnel`]
+    pub const DATAGRAMS: Self = Self(1 << 0);
+    // 1 << 1 was compression, which was never implemented
+    /// the client wants broadcast and multicast packets from the rest of its network
+    pub const BROADCAST: Self = Self(1 << 2);
+
+    /// Keeps unknown bits, they are simply never shared
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self & other == other
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl Hello {
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    /// What the two sides agree on, or why they cannot talk to each other
+    pub fn negotiate(&self, theirs: &Hello) -> Result<Hello> {
+        if theirs.version < self.min_version || self.version < theirs.min_version {
+            warn!(
+                ours = self.version,
+                theirs = theirs.version,
+                "incompatible protocol versions"
+            );
+            return Err(Error::VersionMismatch);
+        }
+
+        Ok(Hello {
+            version: self.version.min(theirs.version),
+            min_version: self.min_version.max(theirs.min_version),
+            capabilities: self.capabilities & theirs.capabilities,
+        })
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use rstest::*;
+
+    fn hello(version: u16, min_version: u16) -> Hello {
+        Hello {
+            version,
+            min_version,
+            capabilities: Capabilities::NONE,
+        }
+    }
+
+    #[rstest]
+    #[case::same(hello(1, 1), hello(1, 1), Some(1))]
+    #[case::newer_but_compatible(hello(2, 1), hello(1, 1), Some(1))]
+    #[case::older_but_compatible(hello(1, 1), hello(3, 1), Some(1))]
+    #[case::too_old(hello(2, 2), hello(1, 1), None)]
+    #[case::too_new(hello(1, 1), hello(3, 2), None)]
+    fn test_negotiate(#[case] ours: Hello, #[case] theirs: Hello, #[case] expected: Option<u16>) {
+        let version = ours.negotiate(&theirs).ok().map(|hello| hello.version);
+        assert_eq!(version, expected);
+
+        // both sides come to the same conclusion
+        let version = theirs.negotiate(&ours).ok().map(|hello| hello.version);
+        assert_eq!(version, expected);
+    }
+
+    #[test]
+    fn test_only_shared_capabilities_are_used() {
+        let ours = Hello::new(Capabilities::DATAGRAMS | Capabilities::BROADCAST);
+        let unknown = Capabilities::from_bits(1 << 31);
+        let theirs = Hello::new(Capabilities::DATAGRAMS | unknown);
+
+        let capabilities = ours.negotiate(&theirs).unwrap().capabilities;
+        assert!(capabilities.contains(Capabilities::DATAGRAMS));
+        assert!(!capabilities.contains(Capabilities::BROADCAST));
+        assert!(!capabilities.contains(unknown));
+    }
+}
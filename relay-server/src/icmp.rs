@@ -0,0 +1,180 @@
+//! Packets the relay writes into a tunnel itself, instead of forwarding them from a peer
+
+use std::net::Ipv4Addr;
+
+use etherparse::{
+    icmpv4::DestUnreachableHeader, Icmpv4Header, Icmpv4Slice, Icmpv4Type, IpNumber, Ipv4Header,
+    PacketBuilder,
+};
+
+/// Most of the original packet an ICMP error quotes, so the whole error stays within the 576
+/// bytes every host must accept (RFC 1812, 4.3.2.3)
+const MAX_QUOTED: usize = 576 - Ipv4Header::MIN_LEN - Icmpv4Header::MIN_LEN;
+
+/// ICMP types that are errors themselves. Errors are never answered with another error
+/// (RFC 1122, 3.2.2), or two hosts could bounce them back and forth forever.
+const ICMP_ERRORS: [u8; 5] = [3, 4, 5, 11, 12];
+
+/// An ICMP destination unreachable (host unreachable) from `from`, telling the sender of `pkt`
+/// that nobody has its destination. `None` for packets that must not be answered with an error.
+pub(crate) fn host_unreachable(from: Ipv4Addr, header: &Ipv4Header, pkt: &[u8]) -> Option<Vec<u8>> {
+    // only the first fragment carries the headers the sender could match the error against
+    if header.fragment_offset.value() != 0 {
+        return None;
+    }
+    if header.protocol == IpNumber::ICMP
+        && pkt
+            .get(header.header_len())
+            .is_some_and(|icmp_type| ICMP_ERRORS.contains(icmp_type))
+    {
+        return None;
+    }
+
+    let quoted = &pkt[..pkt.len().min(MAX_QUOTED)];
+    let builder = PacketBuilder::ipv4(from.octets(), header.source, 64).icmpv4(
+        Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::Host),
+    );
+
+    let mut reply = Vec::with_capacity(builder.size(quoted.len()));
+    builder.write(&mut reply, quoted).ok()?;
+    Some(reply)
+}
+
+/// The answer to `pkt` if it is a ping, coming from the `gateway` it was sent to. Pings too big
+/// to fit in a single fragment go unanswered, the relay does not reassemble packets.
+pub(crate) fn echo_reply(gateway: Ipv4Addr, header: &Ipv4Header, pkt: &[u8]) -> Option<Vec<u8>> {
+    if header.protocol != IpNumber::ICMP || header.is_fragmenting_payload() {
+        return None;
+    }
+
+    let icmp = pkt.get(header.header_len()..usize::from(header.total_len))?;
+    let icmp = Icmpv4Slice::from_slice(icmp).ok()?;
+    let Icmpv4Type::EchoRequest(echo) = icmp.icmp_type() else {
+        return None;
+    };
+
+    let builder = PacketBuilder::ipv4(gateway.octets(), header.source, 64)
+        .icmpv4_echo_reply(echo.id, echo.seq);
+    let mut reply = Vec::with_capacity(builder.size(icmp.payload().len()));
+    builder.write(&mut reply, icmp.payload()).ok()?;
+    Some(reply)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use etherparse::{NetSlice, SlicedPacket, TransportSlice};
+
+    use super::*;
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const NOWHERE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 99);
+
+    fn udp_packet(payload: &[u8]) -> Vec<u8> {
+        let builder = PacketBuilder::ipv4(SOURCE.octets(), NOWHERE.octets(), 64).udp(4000, 4000);
+        let mut pkt = Vec::new();
+        builder.write(&mut pkt, payload).unwrap();
+        pkt
+    }
+
+    fn reply_to(pkt: &[u8]) -> Option<Vec<u8>> {
+        let (header, _) = Ipv4Header::from_slice(pkt).unwrap();
+        host_unreachable(NOWHERE, &header, pkt)
+    }
+
+    #[test]
+    fn test_quotes_the_original() {
+        let pkt = udp_packet(b"hello");
+        let reply = reply_to(&pkt).unwrap();
+        let reply = SlicedPacket::from_ip(&reply).unwrap();
+
+        let Some(NetSlice::Ipv4(ip)) = reply.net else {
+            panic!("reply is not ipv4");
+        };
+        assert_eq!(ip.header().source_addr(), NOWHERE);
+        assert_eq!(ip.header().destination_addr(), SOURCE);
+
+        let Some(TransportSlice::Icmpv4(icmp)) = reply.transport else {
+            panic!("reply is not icmp");
+        };
+        assert_eq!(
+            icmp.icmp_type(),
+            Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::Host)
+        );
+        assert_eq!(icmp.payload(), pkt);
+    }
+
+    #[test]
+    fn test_long_packets_are_cut_short() {
+        let reply = reply_to(&udp_packet(&[0; 1200])).unwrap();
+        assert_eq!(reply.len(), 576);
+    }
+
+    #[test]
+    fn test_errors_are_not_answered() {
+        let builder = PacketBuilder::ipv4(SOURCE.octets(), NOWHERE.octets(), 64).icmpv4(
+            Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::Port),
+        );
+        let mut pkt = Vec::new();
+        builder.write(&mut pkt, b"quoted").unwrap();
+        assert!(reply_to(&pkt).is_none());
+
+        // a ping is no error, so it gets one
+        let builder =
+            PacketBuilder::ipv4(SOURCE.octets(), NOWHERE.octets(), 64).icmpv4_echo_request(1, 1);
+        let mut pkt = Vec::new();
+        builder.write(&mut pkt, b"ping").unwrap();
+        assert!(reply_to(&pkt).is_some());
+    }
+
+    #[test]
+    fn test_pings_are_echoed() {
+        let builder =
+            PacketBuilder::ipv4(SOURCE.octets(), NOWHERE.octets(), 64).icmpv4_echo_request(7, 3);
+        let mut pkt = Vec::new();
+        builder.write(&mut pkt, b"ping").unwrap();
+        let (header, _) = Ipv4Header::from_slice(&pkt).unwrap();
+
+        let reply = echo_reply(NOWHERE, &header, &pkt).unwrap();
+        let reply = SlicedPacket::from_ip(&reply).unwrap();
+        let Some(NetSlice::Ipv4(ip)) = reply.net else {
+            panic!("reply is not ipv4");
+        };
+        assert_eq!(ip.header().source_addr(), NOWHERE);
+        assert_eq!(ip.header().destination_addr(), SOURCE);
+
+        let Some(TransportSlice::Icmpv4(icmp)) = reply.transport else {
+            panic!("reply is not icmp");
+        };
+        let Icmpv4Type::EchoReply(echo) = icmp.icmp_type() else {
+            panic!("reply is not an echo reply");
+        };
+        assert_eq!((echo.id, echo.seq), (7, 3));
+        assert_eq!(icmp.payload(), b"ping");
+    }
+
+    #[test]
+    fn test_only_pings_are_echoed() {
+        let pkt = udp_packet(b"hello");
+        let (header, _) = Ipv4Header::from_slice(&pkt).unwrap();
+        assert!(echo_reply(NOWHERE, &header, &pkt).is_none());
+
+        // an echo reply is no request
+        let builder =
+            PacketBuilder::ipv4(SOURCE.octets(), NOWHERE.octets(), 64).icmpv4_echo_reply(7, 3);
+        let mut pkt = Vec::new();
+        builder.write(&mut pkt, b"pong").unwrap();
+        let (header, _) = Ipv4Header::from_slice(&pkt).unwrap();
+        assert!(echo_reply(NOWHERE, &header, &pkt).is_none());
+    }
+
+    #[test]
+    fn test_later_fragments_are_not_answered() {
+        let mut header =
+            Ipv4Header::new(8, 64, IpNumber::UDP, SOURCE.octets(), NOWHERE.octets()).unwrap();
+        header.fragment_offset = 185.try_into().unwrap();
+        let mut pkt = header.to_bytes().to_vec();
+        pkt.extend_from_slice(&[0; 8]);
+
+        assert!(reply_to(&pkt).is_none());
+    }
+}
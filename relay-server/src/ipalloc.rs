@@ -0,0 +1,136 @@
+use std::{collections::HashSet, fmt::Debug, net::Ipv4Addr};
+
+use ipnet::Ipv4Net;
+
+use crate::error::*;
+
+/// Decides which address in a subnet a new lease gets. Implementations only pick, the db is
+/// what actually records the lease, so they do not need to keep any state of their own.
+pub trait IpAllocator: Debug + Send + Sync {
+    /// `taken` holds every address in `subnet` that is already leased out. A `requested` address
+    /// has to be honoured exactly or refused, never swapped for another one.
+    fn allocate(
+        &self,
+        subnet: Ipv4Net,
+        taken: &HashSet<Ipv4Addr>,
+        requested: Option<Ipv4Addr>,
+    ) -> Result<Ipv4Addr>;
+}
+
+/// Hands out the lowest free address, so the same set of leases always leads to the same pick
+#[derive(Debug, Default)]
+pub struct SequentialAllocator;
+
+impl IpAllocator for SequentialAllocator {
+    fn allocate(
+        &self,
+        subnet: Ipv4Net,
+        taken: &HashSet<Ipv4Addr>,
+        requested: Option<Ipv4Addr>,
+    ) -> Result<Ipv4Addr> {
+        if let Some(ip) = requested {
+            if !is_assignable(subnet, ip) || taken.contains(&ip) {
+                warn!(%ip, "requested address is reserved, taken or outside {subnet}");
+                return Err(Error::AddressUnavailable);
+            }
+
+            return Ok(ip);
+        }
+
+        subnet
+            .hosts()
+            .find(|ip| is_assignable(subnet, *ip) && !taken.contains(ip))
+            .ok_or(Error::SubnetExhausted)
+    }
+}
+
+/// Whether `ip` can be leased to a peer at all. Leaves out the network and broadcast addresses
+/// and the relay's own [`gateway`], except on /31 and /32 where there are no such addresses to
+/// leave out.
+pub fn is_assignable(subnet: Ipv4Net, ip: Ipv4Addr) -> bool {
+    if !subnet.contains(&ip) {
+        return false;
+    }
+
+    if subnet.prefix_len() >= 31 {
+        return true;
+    }
+
+    ip != subnet.network() && ip != subnet.broadcast() && Some(ip) != gateway(subnet)
+}
+
+/// The relay's own address in a network, the first host address of its subnet. It answers ping,
+/// so peers can measure their latency to the relay. /31 and /32 have no room for one.
+pub fn gateway(subnet: Ipv4Net) -> Option<Ipv4Addr> {
+    if subnet.prefix_len() >= 31 {
+        return None;
+    }
+
+    subnet.hosts().next()
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use rstest::*;
+
+    fn allocate(subnet: &str, taken: &[&str], requested: Option<&str>) -> Result<Ipv4Addr> {
+        let taken = taken.iter().map(|ip| ip.parse().unwrap()).collect();
+        let requested = requested.map(|ip| ip.parse().unwrap());
+        SequentialAllocator.allocate(subnet.parse().unwrap(), &taken, requested)
+    }
+
+    #[rstest]
+    #[case::network_address("10.0.0.0/24", "10.0.0.0", false)]
+    #[case::broadcast_address("10.0.0.0/24", "10.0.0.255", false)]
+    #[case::gateway("10.0.0.0/24", "10.0.0.1", false)]
+    #[case::second_host("10.0.0.0/24", "10.0.0.2", true)]
+    #[case::outside("10.0.0.0/24", "10.0.1.1", false)]
+    #[case::point_to_point("10.0.0.0/31", "10.0.0.0", true)]
+    fn test_is_assignable(#[case] subnet: &str, #[case] ip: &str, #[case] expected: bool) {
+        assert_eq!(
+            is_assignable(subnet.parse().unwrap(), ip.parse().unwrap()),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case::small("10.0.0.0/30", Some("10.0.0.1"))]
+    #[case::large("100.64.0.0/10", Some("100.64.0.1"))]
+    #[case::point_to_point("10.0.0.0/31", None)]
+    #[case::single_host("10.0.0.0/32", None)]
+    fn test_gateway(#[case] subnet: &str, #[case] expected: Option<&str>) {
+        let expected = expected.map(|ip| ip.parse().unwrap());
+        assert_eq!(gateway(subnet.parse().unwrap()), expected);
+    }
+
+    #[test]
+    fn test_picks_lowest_free_address() {
+        let ip = allocate("10.0.0.0/24", &[], None).unwrap();
+        assert_eq!(ip, Ipv4Addr::new(10, 0, 0, 2));
+
+        let ip = allocate("10.0.0.0/24", &["10.0.0.2", "10.0.0.4"], None).unwrap();
+        assert_eq!(ip, Ipv4Addr::new(10, 0, 0, 3));
+    }
+
+    #[test]
+    fn test_exhausted_subnet() {
+        let res = allocate("10.0.0.0/30", &["10.0.0.2"], None);
+        assert!(matches!(res, Err(Error::SubnetExhausted)));
+    }
+
+    #[rstest]
+    #[case::free("10.0.0.7", true)]
+    #[case::taken("10.0.0.2", false)]
+    #[case::gateway("10.0.0.1", false)]
+    #[case::broadcast("10.0.0.255", false)]
+    #[case::outside("192.168.0.1", false)]
+    fn test_requested_address(#[case] requested: &str, #[case] ok: bool) {
+        let res = allocate("10.0.0.0/24", &["10.0.0.2"], Some(requested));
+
+        match res {
+            Ok(ip) => assert!(ok && ip == requested.parse::<Ipv4Addr>().unwrap()),
+            Err(error) => assert!(!ok && matches!(error, Error::AddressUnavailable)),
+        }
+    }
+}
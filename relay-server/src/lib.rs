@@ -3,69 +3,359 @@ extern crate tracing;
 
 pub mod access;
 mod action;
+pub mod admin;
 pub mod client;
-mod db;
+pub mod config;
+pub mod db;
 pub mod error;
+pub mod events;
+pub mod framing;
+#[cfg(any(fuzzing, test))]
+#[doc(hidden)]
+pub mod fuzz;
+pub mod hello;
+mod icmp;
+pub mod ipalloc;
+mod metrics;
+pub mod network;
 mod packet;
+pub mod peer;
+pub mod proto;
+mod ratelimit;
+mod tls;
+pub mod tunnel;
 mod wire;
 
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
-use std::sync::Arc;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
 
-use s2n_quic::stream::{ReceiveStream, SendStream};
-use s2n_quic::{Connection, Server as QuicServer};
+use ipnet::Ipv4Net;
+use s2n_quic::{
+    application, connection, provider::limits, stream::BidirectionalStream, Connection,
+    Server as QuicServer,
+};
+use tokio::net::{TcpListener, UnixListener};
 use tokio::sync::mpsc::{self, Sender};
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{broadcast, oneshot, RwLock};
+use tokio::task::AbortHandle;
 
-use crate::{action::Action, db::Db, error::*};
+use crate::{
+    access::unix_now,
+    action::{
+        response::{PeerInfo, PeerState},
+        Action,
+    },
+    config::Config,
+    db::Db,
+    error::*,
+    events::{PeerEvent, RelayEvent, Subscription},
+    hello::{Capabilities, Hello},
+    metrics::{Metrics, OpenConnection},
+    packet::Sender as PacketSender,
+    peer::{PeerAddress, PeerCounters, PeerQueue, PeerStats},
+    ratelimit::{AddrRateLimiter, RateLimiter},
+    tls::TlsConfig,
+    tunnel::Tunnel,
+};
 
-const SOCKET_ADDR: &str = "0.0.0.0:4433";
+// application error code 1 is retired, older relays closed revoked connections with it
 
-static CERT: &str = include_str!("../../certs/cert.pem");
-static KEY: &str = include_str!("../../certs/key.pem");
+/// Application error code for a connection whose route was taken over by a newer one, usually
+/// the same peer reconnecting before the relay noticed it was gone
+const CLOSE_ROUTE_REPLACED: u32 = 2;
+/// Application error code for a client whose hello could not be read, or whose protocol
+/// version the relay does not speak
+const CLOSE_HELLO_REJECTED: u32 = 3;
+
+/// How long the admin socket and metrics listener wait after a failed `accept` before trying
+/// again, so running out of file descriptors does not turn into a busy loop
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A client's connection, along with the capabilities it agreed on in its hello
+#[derive(Debug, Clone)]
+pub struct PeerConnection {
+    handle: connection::Handle,
+    capabilities: Capabilities,
+}
 
 pub struct RoutingInfo {
     ip: Ipv4Addr,
-    recv: ReceiveStream,
-    send: SendStream,
+    network: i64,
+    subnet: Ipv4Net,
+    session: i64,
+    username: String,
+    connection: connection::Handle,
+    /// whether the peer takes part in broadcasts, see [`Capabilities::BROADCAST`]
+    broadcast: bool,
+    tunnel: Tunnel,
+}
+
+/// Changes to the route table, applied in order by `handle_routing`
+pub enum RouteUpdate {
+    Add(Box<RoutingInfo>),
+    /// these sessions were logged out or revoked, disconnect anyone still using them
+    Revoke {
+        sessions: Vec<i64>,
+    },
+    /// the peer's tunnel ended, or its connection with it. Its route goes, unless a newer one
+    /// has taken its address over in the meantime.
+    Disconnected {
+        network: i64,
+        ip: Ipv4Addr,
+        route: u64,
+    },
+    /// `session` disconnected `lease_grace_secs` ago. Unless it came back, it ends and its
+    /// lease is released.
+    Expire {
+        session: i64,
+    },
+    /// someone wants to hear about the peers of `network`, see [`events`]
+    Watch {
+        network: i64,
+        reply: oneshot::Sender<Subscription>,
+    },
+    /// an operator moved these sessions' lease in `network` to `to`. Their routes move along,
+    /// and each peer hears about it from its own peer events.
+    Reassign {
+        network: i64,
+        sessions: Vec<i64>,
+        to: Ipv4Addr,
+    },
+    /// someone wants to know who is connected to `network` right now
+    List {
+        network: i64,
+        reply: oneshot::Sender<Vec<PeerInfo>>,
+    },
+}
+
+/// A peer's tunnel, as far as the relay is concerned. Dropping it ends the tunnel, but not the
+/// connection it lives on.
+pub struct Route {
+    /// tells the route apart from earlier ones for the same address
+    id: u64,
+    session: i64,
+    username: String,
+    connection: connection::Handle,
+    /// shared with the packet loop, which only takes packets from this address
+    address: Arc<PeerAddress>,
+    /// whether broadcast and multicast packets from the rest of the network reach the peer
+    broadcast: bool,
+    /// unix timestamp of when the tunnel came up
+    connected_at: u64,
+    /// drained by the peer's writer task, see [`peer`]
+    queue: Arc<PeerQueue>,
+    /// kept up to date by the peer's packet loop
+    counters: Arc<PeerCounters>,
+    packets: AbortHandle,
+}
+
+impl Route {
+    /// How the route looks from the outside at `now`
+    fn info(&self, ip: Ipv4Addr, now: u64, config: &Config) -> PeerInfo {
+        let last_packet = self.counters.last_packet.load(Ordering::Relaxed);
+        let quiet = now.saturating_sub(last_packet.max(self.connected_at));
+
+        PeerInfo {
+            username: self.username.clone(),
+            address: ip,
+            state: match quiet < config.peer_idle_secs {
+                true => PeerState::Online,
+                false => PeerState::Idle,
+            },
+            connected_secs: now.saturating_sub(self.connected_at),
+        }
+    }
+}
+
+impl Drop for Route {
+    fn drop(&mut self) {
+        self.queue.close();
+        self.packets.abort();
+    }
+}
+
+impl std::fmt::Debug for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(route for session {})", self.session)
+    }
+}
+
+/// Routes inside a single network. Each network gets its own table, and a peer's packets are
+/// only ever looked up in the table of the network it logged in to.
+pub(crate) type RouteTable = Arc<RwLock<HashMap<Ipv4Addr, Route>>>;
+
+/// Every network's route table, by network id. Owned by `handle_routing`, but shared so the
+/// relay can report on its peers while it runs.
+#[derive(Clone, Default)]
+pub struct Networks(Arc<RwLock<HashMap<i64, RouteTable>>>);
+
+impl Networks {
+    pub async fn peer_stats(&self) -> Vec<PeerStats> {
+        let mut stats = Vec::new();
+        let now = unix_now();
+
+        for (network, route_table) in self.0.read().await.iter() {
+            for (ip, route) in route_table.read().await.iter() {
+                let counters = &route.counters;
+                stats.push(PeerStats {
+                    network: *network,
+                    ip: *ip,
+                    session: route.session,
+                    username: route.username.clone(),
+                    connected_secs: now.saturating_sub(route.connected_at),
+                    queued: route.queue.len(),
+                    dropped: route.queue.dropped(),
+                    spoofed: counters.spoofed.load(Ordering::Relaxed),
+                    received_packets: counters.received_packets.load(Ordering::Relaxed),
+                    received_bytes: counters.received_bytes.load(Ordering::Relaxed),
+                    sent_packets: counters.sent_packets.load(Ordering::Relaxed),
+                    sent_bytes: counters.sent_bytes.load(Ordering::Relaxed),
+                });
+            }
+        }
+
+        stats
+    }
 }
 
 pub struct Server {
     db: Db,
     server: QuicServer,
+    tls: TlsConfig,
+    config: Arc<Config>,
+    networks: Networks,
+    /// taken by [`Server::accept`], which serves it next to the QUIC endpoint
+    admin: Option<UnixListener>,
+    metrics: Arc<Metrics>,
+    /// taken by [`Server::accept`] like `admin`, `None` if metrics are off
+    exporter: Option<TcpListener>,
 }
 
 impl Server {
-    pub async fn try_new() -> Result<Self> {
-        let db = Db::try_new().await?;
+    pub async fn try_new(config: Config) -> Result<Self> {
+        let db = Db::try_new(&config.db_path).await?;
 
-        let schema = include_str!("../schemas/user-table.sql");
-        db.load_schema(schema).await?;
+        let tls = TlsConfig::try_new(&config)?;
 
-        let server = QuicServer::builder()
-            .with_io(SOCKET_ADDR)
+        let builder = QuicServer::builder()
+            .with_io(config.listen_addr)
             .map_err(error::QuicError::from)?
-            .with_tls((CERT, KEY))
+            .with_tls(tls.server())
             .expect("quic tls error: infailable")
-            .start()
-            .map_err(error::QuicError::from)?;
+            .with_limits(limits(&config)?)
+            .expect("quic limits error: infailable");
+
+        // without the datagram provider, peers see no datagram support and use streams only
+        let server = if config.datagrams {
+            builder
+                .with_datagram(tunnel::datagram_endpoint())
+                .expect("quic datagram error: infailable")
+                .start()
+        } else {
+            builder.start()
+        };
+        let server = server.map_err(error::QuicError::from)?;
+
+        let admin = admin::bind(&config.admin_socket).await?;
+        let exporter = match config.metrics_addr {
+            Some(addr) => Some(TcpListener::bind(addr).await.map_err(Error::MetricsError)?),
+            None => None,
+        };
+        let config = Arc::new(config);
+
+        Ok(Self {
+            db,
+            server,
+            tls,
+            config,
+            networks: Networks::default(),
+            admin: Some(admin),
+            metrics: Arc::default(),
+            exporter,
+        })
+    }
+
+    /// The address the relay actually bound to, useful when `listen_addr` used port 0
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        let addr = self.server.local_addr().map_err(QuicError::from)?;
+        Ok(addr)
+    }
+
+    /// The address metrics are served on, if they are on. Tells the port when `metrics_addr`
+    /// used port 0.
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        let exporter = self.exporter.as_ref()?;
+        exporter.local_addr().ok()
+    }
 
-        Ok(Self { db, server })
+    /// Every connected peer, for as long as the relay runs
+    pub fn networks(&self) -> Networks {
+        self.networks.clone()
     }
 
     #[instrument(skip(self))]
     pub async fn accept(&mut self) {
-        info!("listening on {SOCKET_ADDR}");
+        match self.local_addr() {
+            Ok(addr) => info!("listening on {addr}"),
+            Err(error) => warn!("could not get local address: {error}"),
+        }
 
-        let (tx, rx) = mpsc::channel(16);
-        tokio::spawn(async { handle_routing(rx).await });
+        tokio::spawn(self.tls.clone().reload_on_sighup(self.config.clone()));
+
+        let (tx, rx) = mpsc::channel(self.config.limits.routing_backlog);
+        tokio::spawn(handle_routing(
+            rx,
+            tx.clone(),
+            self.networks.clone(),
+            self.db.clone(),
+            self.config.clone(),
+            self.metrics.clone(),
+        ));
+
+        if let Some(listener) = self.admin.take() {
+            let admin = admin::Admin {
+                db: self.db.clone(),
+                networks: self.networks.clone(),
+                tx: tx.clone(),
+                config: self.config.clone(),
+            };
+            tokio::spawn(admin::serve(listener, admin));
+        }
+
+        if let Some(listener) = self.exporter.take() {
+            match listener.local_addr() {
+                Ok(addr) => info!("serving metrics on http://{addr}/metrics"),
+                Err(error) => warn!("could not get metrics address: {error}"),
+            }
+            let exporter = metrics::Exporter {
+                metrics: self.metrics.clone(),
+                networks: self.networks.clone(),
+                db: self.db.clone(),
+                config: self.config.clone(),
+            };
+            tokio::spawn(metrics::serve(listener, exporter));
+        }
+
+        let auth_limit = Arc::new(AddrRateLimiter::new(
+            self.config.limits.auth_per_sec,
+            self.config.limits.auth_burst,
+        ));
 
         // docs on s2n_quic::server::Server::poll_accept say:
         // "Once None is returned, this function should not be called again"
         // or I would have ran this inside a loop {}
         while let Some(connection) = self.server.accept().await {
-            tokio::spawn(handle_connection(connection, self.db.clone(), tx.clone()));
+            tokio::spawn(handle_connection(
+                connection,
+                self.db.clone(),
+                self.config.clone(),
+                tx.clone(),
+                auth_limit.clone(),
+                self.metrics.clone(),
+            ));
         }
 
         debug!("quic server has been closed");
@@ -74,40 +364,512 @@ impl Server {
 
 // this function should ideally not "return" the error
 // if it fails, we handle it here. propogating it upwards would be an error
-#[instrument(skip(connection, db, tx), fields(remote_addr = ?connection.remote_addr()))]
-async fn handle_connection(mut connection: Connection, db: Db, tx: Sender<RoutingInfo>) {
+#[instrument(
+    skip(connection, db, config, tx, auth_limit, metrics),
+    fields(remote_addr = ?connection.remote_addr())
+)]
+async fn handle_connection(
+    connection: Connection,
+    db: Db,
+    config: Arc<Config>,
+    tx: Sender<RouteUpdate>,
+    auth_limit: Arc<AddrRateLimiter>,
+    metrics: Arc<Metrics>,
+) {
     info!("Connection accepted from {:?}", connection.remote_addr());
-    let mut recv_stream = match connection.accept_receive_stream().await {
-        Ok(Some(value)) => value,
-        // void returns, acts as an early exit
-        Ok(None) => return debug!("stream was closed without an error"),
-        Err(error) => return error!("{error}"),
-    };
+    let _open = OpenConnection::new(metrics.clone());
+    let (mut handle, mut acceptor) = connection.split();
 
-    let action = wire::deserialise_stream(&mut recv_stream).await;
-    drop(recv_stream);
+    // our hello goes out first, so even a client we cannot understand learns why
+    let ours = Hello::new(capabilities(&config));
+    if let Err(error) = action::send(&mut handle, &ours).await {
+        return error!("could not send hello: {error}");
+    }
 
-    let action: Action = match action {
+    // every read until the client's hello is in gets its own deadline, or a client that
+    // connects and then says nothing would hold on to its connection for good
+    let deadline = Duration::from_secs(config.handshake_timeout_secs);
+
+    let mut hello_stream =
+        match tokio::time::timeout(deadline, acceptor.accept_receive_stream()).await {
+            Ok(Ok(Some(value))) => value,
+            // void returns, acts as an early exit
+            Ok(Ok(None)) => return debug!("stream was closed without an error"),
+            Ok(Err(error)) => return error!("{error}"),
+            Err(_) => return warn!("client did not open a stream in time"),
+        };
+
+    let hello = wire::deserialise_within(&mut hello_stream, deadline)
+        .await
+        .and_then(|theirs: Hello| ours.negotiate(&theirs));
+    let hello = match hello {
         Ok(value) => value,
-        // void return, acts as an early exit
-        Err(error) => return error!(?error, "{error}"),
+        Err(error) => {
+            // the client judges our hello the same way, there is no request to answer yet
+            warn!("refusing client: {error}");
+            metrics.rejected_hellos.fetch_add(1, Ordering::Relaxed);
+            return handle.close(application::Error::from(CLOSE_HELLO_REJECTED));
+        }
     };
+    drop(hello_stream);
 
-    action.handle_action(connection, db, tx).await;
+    let peer = PeerConnection {
+        handle,
+        capabilities: hello.capabilities,
+    };
+
+    // the connection lasts as long as the client keeps it, each request on a stream of its own
+    loop {
+        let stream = match acceptor.accept_bidirectional_stream().await {
+            Ok(Some(value)) => value,
+            Ok(None) => break,
+            Err(error) => {
+                debug!("{error}");
+                break;
+            }
+        };
+
+        tokio::spawn(handle_request(
+            stream,
+            peer.clone(),
+            db.clone(),
+            config.clone(),
+            tx.clone(),
+            auth_limit.clone(),
+            metrics.clone(),
+        ));
+    }
 
     info!("connection ended");
 }
 
-#[instrument(skip(rx))]
-async fn handle_routing(mut rx: mpsc::Receiver<RoutingInfo>) {
-    let route_table = Arc::new(RwLock::new(HashMap::new()));
+#[instrument(skip_all, fields(stream = stream.id()))]
+async fn handle_request(
+    mut stream: BidirectionalStream,
+    connection: PeerConnection,
+    db: Db,
+    config: Arc<Config>,
+    tx: Sender<RouteUpdate>,
+    auth_limit: Arc<AddrRateLimiter>,
+    metrics: Arc<Metrics>,
+) {
+    let deadline = Duration::from_secs(config.handshake_timeout_secs);
+
+    let action: Action = match wire::deserialise_within(&mut stream, deadline).await {
+        Ok(value) => value,
+        Err(error) => {
+            error!(?error, "{error}");
+            // whatever the client sent, it still gets an answer it can make sense of
+            return action::respond::<()>(&mut stream, Err(error)).await;
+        }
+    };
+
+    // guessing passwords or flooding the relay with accounts takes many attempts
+    if matches!(action, Action::Login { .. } | Action::Register { .. })
+        && let Ok(remote_addr) = connection.handle.remote_addr()
+        && !auth_limit.try_acquire(remote_addr.ip())
+    {
+        if matches!(action, Action::Login { .. }) {
+            metrics.failed_logins.fetch_add(1, Ordering::Relaxed);
+        }
+        return action::respond::<()>(&mut stream, Err(Error::RateLimited)).await;
+    }
+
+    action
+        .handle_action(stream, connection, db, config, tx, metrics)
+        .await;
+}
+
+/// Ends `session` after `lease_grace_secs`, unless it has a route again by then
+fn expire_later(tx: &Sender<RouteUpdate>, session: i64, config: &Config) {
+    let grace = Duration::from_secs(config.lease_grace_secs);
+    let tx = tx.clone();
+
+    tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+        if let Err(error) = tx.send(RouteUpdate::Expire { session }).await {
+            error!("could not expire session: {error}");
+        }
+    });
+}
+
+/// Tells whoever watches `network` about `event`, which happened to `session`. Nobody watching
+/// is fine.
+fn publish(events: &broadcast::Sender<RelayEvent>, network: i64, session: i64, event: PeerEvent) {
+    debug!(?event, "peer event in network {network}");
+    let _ = events.send(RelayEvent::Peer {
+        network,
+        session,
+        event,
+    });
+}
+
+/// What the relay offers in its [`Hello`]
+fn capabilities(config: &Config) -> Capabilities {
+    let datagrams = match config.datagrams {
+        true => Capabilities::DATAGRAMS,
+        false => Capabilities::NONE,
+    };
+
+    datagrams | Capabilities::BROADCAST
+}
+
+/// Waits out a failed `accept`, which usually only costs the connection being accepted. Returns
+/// `false` if the listener is gone for good: one that stopped listening fails with `EINVAL`
+/// every time.
+async fn accept_failed(error: &io::Error) -> bool {
+    if error.kind() == io::ErrorKind::InvalidInput {
+        return false;
+    }
+
+    tokio::time::sleep(ACCEPT_BACKOFF).await;
+    true
+}
+
+fn limits(config: &Config) -> Result<limits::Limits> {
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+    let limits = limits::Limits::new()
+        .with_max_idle_timeout(idle_timeout)
+        .map_err(|error| QuicError::LimitsError(error.to_string()))?;
+
+    Ok(limits)
+}
+
+#[instrument(skip(rx, tx, networks, db, config, metrics))]
+async fn handle_routing(
+    mut rx: mpsc::Receiver<RouteUpdate>,
+    tx: Sender<RouteUpdate>,
+    networks: Networks,
+    db: Db,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+) {
+    let mut next_route = 0;
+    let (events, _) = broadcast::channel(config.limits.peer_events);
+
+    while let Some(update) = rx.recv().await {
+        // observed when the update is done with, whichever way it gets there
+        let _timer = metrics.update_latency.start_timer();
+
+        match update {
+            RouteUpdate::Add(info) => {
+                let RoutingInfo {
+                    ip,
+                    network,
+                    subnet,
+                    session,
+                    username,
+                    connection,
+                    broadcast,
+                    tunnel,
+                } = *info;
+
+                let id = next_route;
+                next_route += 1;
+
+                let (recv, send) = tunnel.split();
+
+                let queue = Arc::new(PeerQueue::new(
+                    config.limits.peer_queue,
+                    config.limits.peer_queue_overflow,
+                ));
+                let counters = Arc::new(PeerCounters::default());
+                tokio::spawn(peer::write_to_peer(
+                    ip,
+                    send,
+                    queue.clone(),
+                    counters.clone(),
+                ));
+
+                // every route that ends to make way for this one, as (network, ip, session, username)
+                let mut replaced = Vec::new();
+
+                let mut networks_w = networks.0.write().await;
+                // datagrams cannot tell tunnels apart, so a connection carries one at a time
+                for (theirs, route_table) in networks_w.iter() {
+                    route_table.write().await.retain(|ip, route| {
+                        if route.connection.id() != connection.id() {
+                            return true;
+                        }
+
+                        info!("{ip} upgraded its connection again, ending its old tunnel");
+                        expire_later(&tx, route.session, &config);
+                        replaced.push((*theirs, *ip, route.session, route.username.clone()));
+                        false
+                    });
+                }
+                let route_table = networks_w.entry(network).or_default().clone();
+                drop(networks_w);
+
+                let address = Arc::new(PeerAddress::new(ip));
+                let sender = PacketSender {
+                    ip: address.clone(),
+                    subnet,
+                    queue: queue.clone(),
+                    counters: counters.clone(),
+                };
+                let broadcast_limit = RateLimiter::new(
+                    config.limits.broadcast_per_sec,
+                    config.limits.broadcast_burst,
+                );
+                let unreachable_limit = RateLimiter::new(
+                    config.limits.unreachable_per_sec,
+                    config.limits.unreachable_burst,
+                );
+                let packets = packet::parsepkt(
+                    recv,
+                    route_table.clone(),
+                    sender,
+                    broadcast_limit,
+                    unreachable_limit,
+                    config.log_spoofed,
+                    metrics.clone(),
+                );
+                let packets = tokio::spawn({
+                    let tx = tx.clone();
+                    let address = address.clone();
+                    async move {
+                        packets.await;
+
+                        // wherever the peer was moved to in the meantime
+                        let update = RouteUpdate::Disconnected {
+                            network,
+                            ip: address.get(),
+                            route: id,
+                        };
+                        if let Err(error) = tx.send(update).await {
+                            error!("could not remove route: {error}");
+                        }
+                    }
+                });
+
+                let route = Route {
+                    id,
+                    session,
+                    username: username.clone(),
+                    connection,
+                    address,
+                    broadcast,
+                    connected_at: unix_now(),
+                    queue,
+                    counters,
+                    packets: packets.abort_handle(),
+                };
+
+                let mut table_w = route_table.write().await;
+                if let Some(old) = table_w.insert(ip, route) {
+                    info!("{ip} reconnected, closing its old connection");
+                    old.connection
+                        .close(application::Error::from(CLOSE_ROUTE_REPLACED));
+                    replaced.push((network, ip, old.session, old.username.clone()));
+                }
+                drop(table_w);
+                info!(?route_table, "ADDED {ip} to the table of network {network}");
+
+                // the session coming back is not news to its peers, unless it moved
+                let mut previous = None;
+                for (theirs, old_ip, old_session, old_username) in replaced {
+                    if theirs == network && old_session == session {
+                        previous = Some(old_ip);
+                        continue;
+                    }
+
+                    let left = PeerEvent::Left {
+                        username: old_username,
+                        address: old_ip,
+                    };
+                    publish(&events, theirs, old_session, left);
+                }
+
+                let event = match previous {
+                    None => PeerEvent::Joined {
+                        username,
+                        address: ip,
+                    },
+                    Some(from) if from != ip => PeerEvent::Moved {
+                        username,
+                        from,
+                        to: ip,
+                    },
+                    Some(_) => continue,
+                };
+                publish(&events, network, session, event);
+            }
+            RouteUpdate::Revoke { sessions } => {
+                let mut networks_w = networks.0.write().await;
+                let mut emptied = Vec::new();
+                let mut left = Vec::new();
+
+                for (network, route_table) in networks_w.iter() {
+                    let mut table_w = route_table.write().await;
+                    table_w.retain(|ip, route| {
+                        if !sessions.contains(&route.session) {
+                            return true;
+                        }
+
+                        // dropping the route ends the tunnel, but the connection stays up for
+                        // the client's other requests
+                        info!(
+                            dropped = route.queue.dropped(),
+                            "REMOVED {ip} from the table of network {network}, its session was revoked"
+                        );
+                        let event = PeerEvent::Left {
+                            username: route.username.clone(),
+                            address: *ip,
+                        };
+                        left.push((*network, route.session, event));
+                        false
+                    });
+
+                    if table_w.is_empty() {
+                        emptied.push(*network);
+                    }
+                }
+
+                for network in emptied {
+                    networks_w.remove(&network);
+                }
+                drop(networks_w);
+
+                for (network, session, event) in left {
+                    publish(&events, network, session, event);
+                }
+                // whoever watches with a revoked session stops hearing about its old peers
+                let _ = events.send(RelayEvent::Ended(sessions.into()));
+            }
+            RouteUpdate::Disconnected { network, ip, route } => {
+                let mut networks_w = networks.0.write().await;
+                let Some(route_table) = networks_w.get(&network) else {
+                    continue;
+                };
+
+                let mut table_w = route_table.write().await;
+                // revoked already, or taken over by a newer route
+                if table_w.get(&ip).is_none_or(|current| current.id != route) {
+                    continue;
+                }
+
+                let route = table_w.remove(&ip).expect("route was just found");
+                info!(
+                    dropped = route.queue.dropped(),
+                    "REMOVED {ip} from the table of network {network}, its peer disconnected"
+                );
+
+                if table_w.is_empty() {
+                    drop(table_w);
+                    networks_w.remove(&network);
+                }
+
+                expire_later(&tx, route.session, &config);
+
+                let left = PeerEvent::Left {
+                    username: route.username.clone(),
+                    address: ip,
+                };
+                publish(&events, network, route.session, left);
+            }
+            RouteUpdate::Expire { session } => {
+                let mut reconnected = false;
+                for route_table in networks.0.read().await.values() {
+                    let table_r = route_table.read().await;
+                    reconnected |= table_r.values().any(|route| route.session == session);
+                }
+
+                if reconnected {
+                    continue;
+                }
+                match db.expire_session(session).await {
+                    Ok(()) => {
+                        let _ = events.send(RelayEvent::Ended(Arc::new([session])));
+                    }
+                    Err(error) => error!("could not expire session {session}: {error}"),
+                }
+            }
+            RouteUpdate::Watch { network, reply } => {
+                // taken in one go, so no event falls between the snapshot and the subscription
+                let mut snapshot = Vec::new();
+                if let Some(route_table) = networks.0.read().await.get(&network) {
+                    for (ip, route) in route_table.read().await.iter() {
+                        snapshot.push(RelayEvent::Peer {
+                            network,
+                            session: route.session,
+                            event: PeerEvent::Joined {
+                                username: route.username.clone(),
+                                address: *ip,
+                            },
+                        });
+                    }
+                }
+
+                let subscription = Subscription {
+                    snapshot,
+                    events: events.subscribe(),
+                };
+                if reply.send(subscription).is_err() {
+                    debug!("watcher went away before its subscription was ready");
+                }
+            }
+            RouteUpdate::Reassign {
+                network,
+                sessions,
+                to,
+            } => {
+                let Some(route_table) = networks.0.read().await.get(&network).cloned() else {
+                    continue;
+                };
+
+                let mut table_w = route_table.write().await;
+                let from: Vec<_> = table_w
+                    .iter()
+                    .filter(|(_, route)| sessions.contains(&route.session))
+                    .map(|(ip, _)| *ip)
+                    .collect();
+
+                // the tunnel stays up, only the address it speaks for changes. The db made sure
+                // nobody else holds `to`.
+                let mut moved = Vec::new();
+                for from in from {
+                    let route = table_w.remove(&from).expect("route was just found");
+                    route.address.set(to);
+                    info!("MOVED {from} to {to} in the table of network {network}");
+                    moved.push((route.session, route.username.clone(), from));
+                    table_w.insert(to, route);
+                }
+                drop(table_w);
+
+                for (session, username, from) in moved {
+                    let event = PeerEvent::Moved { username, from, to };
+                    publish(&events, network, session, event);
+                }
+            }
+            RouteUpdate::List { network, reply } => {
+                let now = unix_now();
+                let mut peers = Vec::new();
+                if let Some(route_table) = networks.0.read().await.get(&network) {
+                    for (ip, route) in route_table.read().await.iter() {
+                        peers.push(route.info(*ip, now, &config));
+                    }
+                }
+                peers.sort_by_key(|peer| peer.address);
+
+                if reply.send(peers).is_err() {
+                    debug!("peer list was not wanted anymore");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
 
-    while let Some(RoutingInfo { ip, send, recv }) = rx.recv().await {
-        let mut table_w = route_table.write().await;
-        table_w.insert(ip, Mutex::new(send));
-        drop(table_w);
-        info!(?route_table, "ADDED {ip} to the table");
+    #[tokio::test]
+    async fn test_accept_failures_are_waited_out() {
+        // out of file descriptors, for now
+        assert!(accept_failed(&io::Error::from_raw_os_error(24)).await);
+        assert!(accept_failed(&io::Error::from(io::ErrorKind::ConnectionAborted)).await);
 
-        tokio::spawn(packet::parsepkt(recv, route_table.clone()));
+        assert!(!accept_failed(&io::Error::from(io::ErrorKind::InvalidInput)).await);
     }
 }
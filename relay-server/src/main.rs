@@ -1,11 +1,143 @@
-use relay_server::{error::*, Server};
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::{Parser, Subcommand};
+use ipnet::Ipv4Net;
+use tracing_subscriber::EnvFilter;
+
+use relay_server::{
+    config::{Config, RegistrationPolicy, SessionPolicy},
+    db::Db,
+    error::*,
+    Server,
+};
+
+/// Relay server for LAN-Share. Flags override values from the config file.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// TOML config file
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// UDP address to listen on
+    #[arg(long)]
+    listen_addr: Option<SocketAddr>,
+    /// sqlite database file
+    #[arg(long)]
+    db_path: Option<PathBuf>,
+    /// Unix socket for relay-admin
+    #[arg(long)]
+    admin_socket: Option<PathBuf>,
+    /// TCP address to serve Prometheus metrics on, off by default
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// PEM certificate chain, generated if it does not exist
+    #[arg(long)]
+    cert_path: Option<PathBuf>,
+    /// PEM private key, generated if it does not exist
+    #[arg(long)]
+    key_path: Option<PathBuf>,
+    /// Name to put in a generated certificate, can be repeated
+    #[arg(long = "cert-name")]
+    cert_names: Vec<String>,
+    /// Virtual network peers are assigned addresses from, e.g. 10.20.0.0/16
+    #[arg(long)]
+    subnet: Option<Ipv4Net>,
+    /// Who may create new accounts
+    #[arg(long, value_enum)]
+    registration: Option<RegistrationPolicy>,
+    /// What logging in does to the sessions a user already has
+    #[arg(long, value_enum)]
+    session_policy: Option<SessionPolicy>,
+    /// Log filter, takes precedence over RUST_LOG
+    #[arg(long)]
+    log_level: Option<String>,
+    /// Maximum number of registered users, 0 for unlimited
+    #[arg(long)]
+    max_users: Option<u32>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the relay (default)
+    Serve,
+    /// Print a single use code for registering on an invite-only relay
+    Invite,
+}
+
+impl Cli {
+    fn into_config(self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        if let Some(listen_addr) = self.listen_addr {
+            config.listen_addr = listen_addr;
+        }
+        if let Some(db_path) = self.db_path {
+            config.db_path = db_path;
+        }
+        if let Some(admin_socket) = self.admin_socket {
+            config.admin_socket = admin_socket;
+        }
+        if let Some(metrics_addr) = self.metrics_addr {
+            config.metrics_addr = Some(metrics_addr);
+        }
+        if let Some(cert_path) = self.cert_path {
+            config.cert_path = cert_path;
+        }
+        if let Some(key_path) = self.key_path {
+            config.key_path = key_path;
+        }
+        if !self.cert_names.is_empty() {
+            config.cert_names = self.cert_names;
+        }
+        if let Some(subnet) = self.subnet {
+            config.subnet = subnet;
+        }
+        if let Some(registration) = self.registration {
+            config.registration = registration;
+        }
+        if let Some(session_policy) = self.session_policy {
+            config.session_policy = session_policy;
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(max_users) = self.max_users {
+            config.limits.max_users = max_users;
+        }
+
+        Ok(config)
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result {
-    tracing_subscriber::fmt::init();
+    let mut cli = Cli::parse();
+    let log_level_flag = cli.log_level.is_some();
+    let command = cli.command.take().unwrap_or(Command::Serve);
+    let config = cli.into_config()?;
+
+    // an explicit --log-level wins, then RUST_LOG, then whatever the config file says
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) if !log_level_flag => filter,
+        _ => EnvFilter::new(&config.log_level),
+    };
+    tracing_subscriber::fmt().with_env_filter(filter).init();
 
-    let mut server = Server::try_new().await?;
-    server.accept().await;
+    match command {
+        Command::Serve => {
+            let mut server = Server::try_new(config).await?;
+            server.accept().await;
+        }
+        Command::Invite => {
+            let db = Db::try_new(&config.db_path).await?;
+            println!("{}", db.create_invite().await?);
+        }
+    }
 
     Ok(())
 }
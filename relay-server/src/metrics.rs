@@ -0,0 +1,537 @@
+//! Counters for the relay as a whole, served as Prometheus text over plain HTTP on
+//! `metrics_addr`. Per-peer numbers are not kept here, they are read off the route table, see
+//! [`PeerStats`], when someone scrapes.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{accept_failed, config::Config, db::Db, error::*, peer::PeerStats, Networks};
+
+/// The longest request head a scraper may send, anything longer is not a scraper
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// Upper bounds, in seconds, of the buckets for how long a packet takes from arriving to
+/// sitting in its destination's queue
+const PACKET_BUCKETS: &[f64] = &[
+    0.000_001, 0.000_005, 0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01,
+];
+/// Upper bounds, in seconds, of the buckets for how long the router takes over a route update
+const UPDATE_BUCKETS: &[f64] = &[0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+/// Why a packet from a peer went nowhere
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DropReason {
+    /// nobody in the sender's network has the destination address
+    NoRoute,
+    /// the destination is the relay's own address, which only answers ping
+    Gateway,
+    /// not an IPv4 packet the relay could make sense of
+    ParseError,
+    /// the destination's queue was full, see [`crate::peer::PeerQueue`]
+    QueueFull,
+    /// the source was not the sender's own address
+    Spoofed,
+    /// a broadcast over the sender's limit
+    RateLimited,
+}
+
+impl DropReason {
+    const ALL: [Self; 6] = [
+        Self::NoRoute,
+        Self::Gateway,
+        Self::ParseError,
+        Self::QueueFull,
+        Self::Spoofed,
+        Self::RateLimited,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::NoRoute => "no_route",
+            Self::Gateway => "gateway",
+            Self::ParseError => "parse_error",
+            Self::QueueFull => "queue_full",
+            Self::Spoofed => "spoofed",
+            Self::RateLimited => "rate_limited",
+        }
+    }
+}
+
+/// A Prometheus histogram with fixed buckets
+#[derive(Debug)]
+pub(crate) struct Histogram {
+    bounds: &'static [f64],
+    /// observations per bucket, the last one past every bound. Made cumulative when rendered.
+    buckets: Box<[AtomicU64]>,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// Observes the time until the returned timer is dropped
+    pub fn start_timer(&self) -> Timer<'_> {
+        Timer {
+            histogram: self,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = self.bounds.partition_point(|bound| *bound < secs);
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+
+        let mut count = 0;
+        for (bucket, bound) in self.buckets.iter().zip(self.bounds) {
+            count += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        count += self.buckets[self.bounds.len()].load(Ordering::Relaxed);
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+/// See [`Histogram::start_timer`]
+pub(crate) struct Timer<'a> {
+    histogram: &'a Histogram,
+    started: Instant,
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.histogram.observe(self.started.elapsed());
+    }
+}
+
+/// What the relay counts while it runs. Shared by every task that has something to count.
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    /// QUIC connections accepted
+    pub connections: AtomicU64,
+    /// connections still open
+    pub open_connections: AtomicU64,
+    /// connections closed because their hello was unreadable or from another version
+    pub rejected_hellos: AtomicU64,
+    pub logins: AtomicU64,
+    pub failed_logins: AtomicU64,
+    /// packets handed to a destination's queue, broadcasts once per destination
+    pub routed_packets: AtomicU64,
+    pub routed_bytes: AtomicU64,
+    /// ICMP host unreachable errors sent for packets with no route
+    pub unreachable_replies: AtomicU64,
+    /// pings the gateway answered
+    pub echo_replies: AtomicU64,
+    dropped: [AtomicU64; DropReason::ALL.len()],
+    pub packet_latency: Histogram,
+    pub update_latency: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            connections: AtomicU64::new(0),
+            open_connections: AtomicU64::new(0),
+            rejected_hellos: AtomicU64::new(0),
+            logins: AtomicU64::new(0),
+            failed_logins: AtomicU64::new(0),
+            routed_packets: AtomicU64::new(0),
+            routed_bytes: AtomicU64::new(0),
+            unreachable_replies: AtomicU64::new(0),
+            echo_replies: AtomicU64::new(0),
+            dropped: Default::default(),
+            packet_latency: Histogram::new(PACKET_BUCKETS),
+            update_latency: Histogram::new(UPDATE_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn dropped(&self, reason: DropReason) {
+        self.dropped[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn routed(&self, len: usize) {
+        self.routed_packets.fetch_add(1, Ordering::Relaxed);
+        self.routed_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Everything in the Prometheus text format, along with one series per connected peer
+    fn render(&self, peers: &[PeerStats], network_names: &HashMap<i64, String>) -> String {
+        let mut out = String::new();
+
+        let counters = [
+            (
+                "lanshare_connections_total",
+                "QUIC connections accepted",
+                &self.connections,
+            ),
+            (
+                "lanshare_rejected_hellos_total",
+                "Connections closed over their hello",
+                &self.rejected_hellos,
+            ),
+            ("lanshare_logins_total", "Successful logins", &self.logins),
+            (
+                "lanshare_failed_logins_total",
+                "Refused logins, for whatever reason",
+                &self.failed_logins,
+            ),
+            (
+                "lanshare_routed_packets_total",
+                "Packets handed to a peer's queue",
+                &self.routed_packets,
+            ),
+            (
+                "lanshare_routed_bytes_total",
+                "Bytes handed to a peer's queue",
+                &self.routed_bytes,
+            ),
+            (
+                "lanshare_unreachable_replies_total",
+                "ICMP host unreachable errors sent for packets with no route",
+                &self.unreachable_replies,
+            ),
+            (
+                "lanshare_echo_replies_total",
+                "Pings the relay answered at its gateway address",
+                &self.echo_replies,
+            ),
+        ];
+        for (name, help, value) in counters {
+            header(&mut out, name, help, "counter");
+            let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+        }
+
+        let name = "lanshare_open_connections";
+        header(&mut out, name, "QUIC connections currently open", "gauge");
+        let open = self.open_connections.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name} {open}");
+
+        let name = "lanshare_dropped_packets_total";
+        header(
+            &mut out,
+            name,
+            "Packets from peers that went nowhere",
+            "counter",
+        );
+        for reason in DropReason::ALL {
+            let dropped = self.dropped[reason as usize].load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}{{reason=\"{}\"}} {dropped}", reason.label());
+        }
+
+        self.packet_latency.render(
+            &mut out,
+            "lanshare_packet_routing_seconds",
+            "Time from a packet arriving to it being queued for its destination",
+        );
+        self.update_latency.render(
+            &mut out,
+            "lanshare_route_update_seconds",
+            "Time the router spends applying a single route update",
+        );
+
+        render_peers(&mut out, peers, network_names);
+
+        out
+    }
+}
+
+/// Counts an open connection for as long as it lives
+pub(crate) struct OpenConnection(Arc<Metrics>);
+
+impl OpenConnection {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        metrics.connections.fetch_add(1, Ordering::Relaxed);
+        metrics.open_connections.fetch_add(1, Ordering::Relaxed);
+        Self(metrics)
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn render_peers(out: &mut String, peers: &[PeerStats], network_names: &HashMap<i64, String>) {
+    let name = "lanshare_peers";
+    header(out, name, "Peers with a route, by network", "gauge");
+    let mut per_network: Vec<(&str, usize)> = Vec::new();
+    for peer in peers {
+        let network = network_names.get(&peer.network).map_or("", String::as_str);
+        match per_network.iter_mut().find(|(name, _)| *name == network) {
+            Some((_, count)) => *count += 1,
+            None => per_network.push((network, 1)),
+        }
+    }
+    per_network.sort();
+    for (network, count) in per_network {
+        let _ = writeln!(out, "{name}{{network=\"{}\"}} {count}", escape(network));
+    }
+
+    type Stat = fn(&PeerStats) -> u64;
+    let series: [(&str, &str, &str, Stat); 6] = [
+        (
+            "lanshare_peer_received_packets_total",
+            "Packets a peer sent to the relay",
+            "counter",
+            |peer| peer.received_packets,
+        ),
+        (
+            "lanshare_peer_received_bytes_total",
+            "Bytes a peer sent to the relay",
+            "counter",
+            |peer| peer.received_bytes,
+        ),
+        (
+            "lanshare_peer_sent_packets_total",
+            "Packets written to a peer",
+            "counter",
+            |peer| peer.sent_packets,
+        ),
+        (
+            "lanshare_peer_sent_bytes_total",
+            "Bytes written to a peer",
+            "counter",
+            |peer| peer.sent_bytes,
+        ),
+        (
+            "lanshare_peer_dropped_packets_total",
+            "Packets for a peer dropped because its queue was full",
+            "counter",
+            |peer| peer.dropped,
+        ),
+        (
+            "lanshare_peer_queued_packets",
+            "Packets waiting to be written to a peer",
+            "gauge",
+            |peer| peer.queued as u64,
+        ),
+    ];
+    for (name, help, kind, stat) in series {
+        header(out, name, help, kind);
+        for peer in peers {
+            let network = network_names.get(&peer.network).map_or("", String::as_str);
+            let _ = writeln!(
+                out,
+                "{name}{{network=\"{}\",address=\"{}\",username=\"{}\"}} {}",
+                escape(network),
+                peer.ip,
+                escape(&peer.username),
+                stat(peer)
+            );
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Escapes a label value, as the text format wants it
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Everything a scrape reads from
+#[derive(Clone)]
+pub(crate) struct Exporter {
+    pub metrics: Arc<Metrics>,
+    pub networks: Networks,
+    pub db: Db,
+    pub config: Arc<Config>,
+}
+
+#[instrument(skip_all)]
+pub(crate) async fn serve(listener: TcpListener, exporter: Exporter) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_scrape(stream, exporter.clone()));
+            }
+            Err(error) => {
+                warn!("could not accept scrape: {error}");
+                if !accept_failed(&error).await {
+                    return error!("metrics listener stopped accepting: {error}");
+                }
+            }
+        }
+    }
+}
+
+/// Answers a single HTTP request, then hangs up. Only `GET /metrics` gets anything useful.
+#[instrument(skip_all)]
+async fn handle_scrape(mut stream: TcpStream, exporter: Exporter) {
+    let deadline = Duration::from_secs(exporter.config.handshake_timeout_secs);
+    let path = match tokio::time::timeout(deadline, read_request(&mut stream)).await {
+        Ok(Ok(path)) => path,
+        Ok(Err(error)) => return debug!("could not read scrape: {error}"),
+        Err(_) => return debug!("scraper did not send its request in time"),
+    };
+
+    let response = match path.as_deref() {
+        Some("/metrics") => match exporter.render().await {
+            Ok(body) => http_response("200 OK", &body),
+            Err(error) => {
+                error!("could not collect metrics: {error}");
+                http_response("500 Internal Server Error", "")
+            }
+        },
+        Some(_) => http_response("404 Not Found", ""),
+        None => http_response("405 Method Not Allowed", ""),
+    };
+
+    if let Err(error) = stream.write_all(response.as_bytes()).await {
+        debug!("could not answer scrape: {error}");
+    }
+}
+
+impl Exporter {
+    async fn render(&self) -> Result<String> {
+        let network_names = self.db.network_names().await?;
+        let peers = self.networks.peer_stats().await;
+
+        Ok(self.metrics.render(&peers, &network_names))
+    }
+}
+
+/// Reads a request head, returning the path it asks for, or `None` if it is not a `GET`
+async fn read_request(stream: &mut TcpStream) -> Result<Option<String>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await.map_err(Error::WireError)?;
+        if read == 0 {
+            return Err(Error::PrematureClosure);
+        }
+        head.extend_from_slice(&buf[..read]);
+        if head.len() > MAX_REQUEST_LEN {
+            return Err(Error::MessageTooLarge { len: head.len() });
+        }
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let path = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(path)) => Some(path.to_string()),
+        _ => None,
+    };
+
+    Ok(path)
+}
+
+fn http_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\n\
+        Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new(&[0.001, 0.01]);
+        histogram.observe(Duration::from_micros(500));
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_secs(1));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "latency", "help");
+
+        assert!(out.contains("latency_bucket{le=\"0.001\"} 1\n"));
+        assert!(out.contains("latency_bucket{le=\"0.01\"} 2\n"));
+        assert!(out.contains("latency_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("latency_sum 1.0055\n"));
+        assert!(out.contains("latency_count 3\n"));
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.dropped(DropReason::NoRoute);
+        metrics.dropped(DropReason::NoRoute);
+        metrics.routed(100);
+
+        let peer = PeerStats {
+            network: 1,
+            ip: Ipv4Addr::new(100, 64, 0, 1),
+            session: 1,
+            username: "al\"ice".to_string(),
+            connected_secs: 5,
+            queued: 0,
+            dropped: 0,
+            spoofed: 0,
+            received_packets: 3,
+            received_bytes: 300,
+            sent_packets: 0,
+            sent_bytes: 0,
+        };
+        let names = HashMap::from([(1, "default".to_string())]);
+        let out = metrics.render(&[peer], &names);
+
+        assert!(out.contains("lanshare_dropped_packets_total{reason=\"no_route\"} 2\n"));
+        assert!(out.contains("lanshare_dropped_packets_total{reason=\"queue_full\"} 0\n"));
+        assert!(out.contains("lanshare_routed_bytes_total 100\n"));
+        assert!(out.contains("lanshare_peers{network=\"default\"} 1\n"));
+        assert!(out.contains(
+            "lanshare_peer_received_bytes_total{network=\"default\",address=\"100.64.0.1\",username=\"al\\\"ice\"} 300\n"
+        ));
+    }
+
+    #[test]
+    fn test_open_connections_are_counted_while_they_last() {
+        let metrics = Arc::new(Metrics::default());
+
+        let connection = OpenConnection::new(metrics.clone());
+        assert_eq!(metrics.open_connections.load(Ordering::Relaxed), 1);
+        drop(connection);
+
+        assert_eq!(metrics.open_connections.load(Ordering::Relaxed), 0);
+        assert_eq!(metrics.connections.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,481 @@
+use std::{collections::HashSet, net::Ipv4Addr};
+
+use ipnet::Ipv4Net;
+use rusqlite::{params, types::Type, Connection, ErrorCode, OptionalExtension, ToSql};
+
+use crate::{
+    config::Config,
+    db::Db,
+    error::*,
+    ipalloc::{self, IpAllocator},
+};
+
+/// The network everyone is a member of just by logging in. It always leases from the relay's
+/// configured subnet.
+pub const DEFAULT_NETWORK: &str = "default";
+
+/// Where a network's subnet has to lie: the private ranges (RFC 1918) and carrier-grade NAT
+/// (RFC 6598). Peers route the whole subnet into their tunnel, so anything else could hide part
+/// of the internet from them.
+const PRIVATE_RANGES: [Ipv4Net; 4] = [
+    Ipv4Net::new_assert(Ipv4Addr::new(10, 0, 0, 0), 8),
+    Ipv4Net::new_assert(Ipv4Addr::new(172, 16, 0, 0), 12),
+    Ipv4Net::new_assert(Ipv4Addr::new(192, 168, 0, 0), 16),
+    Ipv4Net::new_assert(Ipv4Addr::new(100, 64, 0, 0), 10),
+];
+
+impl Db {
+    /// Creates a network owned by whoever holds `token` and makes them its first member.
+    /// Without a `subnet` the network leases from the relay's configured one, which is fine
+    /// since addresses never have to be unique across networks.
+    #[instrument(skip(self, token))]
+    pub async fn create_network(&self, token: &str, name: &str, subnet: Option<Ipv4Net>) -> Result {
+        let session = self.session(token).await?;
+
+        if let Some(subnet) = subnet
+            && !is_valid_subnet(subnet)
+        {
+            warn!("refusing subnet {subnet}");
+            return Err(Error::InvalidSubnet);
+        }
+        let subnet = subnet.as_ref().map(Ipv4Net::trunc);
+
+        let mut db = self.db_conn.lock().await;
+        let tx = db.transaction()?;
+
+        let res = tx.execute(
+            "insert into networks (name, subnet, owner, created_at)
+                values (?1, ?2, ?3, unixepoch())",
+            params![
+                name,
+                subnet.map(|subnet| subnet.to_string()),
+                session.user_id
+            ],
+        );
+
+        if let Err(error) = &res
+            && error.sqlite_error_code() == Some(ErrorCode::ConstraintViolation)
+        {
+            warn!("network already exists");
+            return Err(Error::NetworkAlreadyExists);
+        }
+        res?;
+
+        tx.execute(
+            "insert into memberships (network_id, user_id) values (?1, ?2)",
+            params![tx.last_insert_rowid(), session.user_id],
+        )?;
+        tx.commit()?;
+
+        info!("network created");
+        Ok(())
+    }
+
+    /// Lets `username` join `name`, which only its owner may do. The invitation lasts until it
+    /// is used, inviting twice is not an error.
+    #[instrument(skip(self, token, config))]
+    pub async fn invite_to_network(
+        &self,
+        token: &str,
+        name: &str,
+        username: &str,
+        config: &Config,
+    ) -> Result {
+        let session = self.session(token).await?;
+
+        let db = self.db_conn.lock().await;
+        let network = find(&db, name, config)?;
+        if network.owner != Some(session.user_id) {
+            warn!("not the owner of {name}");
+            return Err(Error::NotTheOwner);
+        }
+
+        let user_id: Option<i64> = db
+            .query_row(
+                "select id from users where username = ?1",
+                [username],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(user_id) = user_id else {
+            warn!("no such user");
+            return Err(Error::NoSuchUser);
+        };
+
+        db.execute(
+            "insert or ignore into network_invites (network_id, user_id, created_at)
+                values (?1, ?2, unixepoch())",
+            params![network.id, user_id],
+        )?;
+
+        info!("invited {username} to {name}");
+        Ok(())
+    }
+
+    /// Lets whoever holds `token` log in to `name` from now on. Anyone may join the default
+    /// network, any other takes an invitation from its owner. Joining twice is not an error.
+    #[instrument(skip(self, token, config))]
+    pub async fn join_network(&self, token: &str, name: &str, config: &Config) -> Result {
+        let session = self.session(token).await?;
+
+        let mut db = self.db_conn.lock().await;
+        let tx = db.transaction()?;
+        let network = find(&tx, name, config)?;
+
+        let member: bool = tx.query_row(
+            "select exists(select 1 from memberships where network_id = ?1 and user_id = ?2)",
+            params![network.id, session.user_id],
+            |row| row.get(0),
+        )?;
+        let invited = tx.execute(
+            "delete from network_invites where network_id = ?1 and user_id = ?2",
+            params![network.id, session.user_id],
+        )? > 0;
+
+        if !member && !invited && network.name != DEFAULT_NETWORK {
+            warn!("not invited to {name}");
+            return Err(Error::NotInvited);
+        }
+
+        tx.execute(
+            "insert or ignore into memberships (network_id, user_id) values (?1, ?2)",
+            params![network.id, session.user_id],
+        )?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Drops the membership and lease of whoever holds `token` in `name`. Returns the sessions
+    /// they had there, so their routes can be torn down.
+    #[instrument(skip(self, token, config))]
+    pub async fn leave_network(
+        &self,
+        token: &str,
+        name: &str,
+        config: &Config,
+    ) -> Result<Vec<i64>> {
+        let session = self.session(token).await?;
+
+        let mut db = self.db_conn.lock().await;
+        let tx = db.transaction()?;
+        let network = find(&tx, name, config)?;
+
+        let left = tx.execute(
+            "delete from memberships where network_id = ?1 and user_id = ?2",
+            params![network.id, session.user_id],
+        )?;
+        if left == 0 {
+            return Err(Error::NotAMember);
+        }
+
+        let revoked = tx
+            .prepare("delete from sessions where network_id = ?1 and user_id = ?2 returning id")?
+            .query_map(params![network.id, session.user_id], |row| row.get(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+        tx.commit()?;
+
+        Ok(revoked)
+    }
+
+    /// The subnet a network leases from, which is also what its broadcast address follows
+    pub async fn subnet_of(&self, network_id: i64, config: &Config) -> Result<Ipv4Net> {
+        let db = self.db_conn.lock().await;
+        let network = query(&db, "id = ?1", network_id, config)?;
+
+        Ok(network.subnet)
+    }
+}
+
+/// Whether a network may lease from `subnet`: it has to lie in [`PRIVATE_RANGES`] and have room
+/// for a gateway and at least two peers
+fn is_valid_subnet(subnet: Ipv4Net) -> bool {
+    let peers = subnet
+        .hosts()
+        .filter(|ip| ipalloc::is_assignable(subnet, *ip))
+        .take(2)
+        .count();
+
+    PRIVATE_RANGES.iter().any(|range| range.contains(&subnet))
+        && ipalloc::gateway(subnet).is_some()
+        && peers == 2
+}
+
+pub(crate) struct NetworkRow {
+    pub id: i64,
+    pub name: String,
+    /// already resolved against the relay's configured subnet
+    pub subnet: Ipv4Net,
+    /// whoever created the network, `None` for the default one
+    pub owner: Option<i64>,
+}
+
+pub(crate) fn find(db: &Connection, name: &str, config: &Config) -> Result<NetworkRow> {
+    query(db, "name = ?1", name, config)
+}
+
+fn query(db: &Connection, filter: &str, key: impl ToSql, config: &Config) -> Result<NetworkRow> {
+    let network = db
+        .query_row(
+            &format!("select id, name, subnet, owner from networks where {filter}"),
+            [key],
+            |row| {
+                let subnet = row
+                    .get::<_, Option<String>>(2)?
+                    .map(|subnet| subnet.parse())
+                    .transpose()
+                    .map_err(|error| {
+                        rusqlite::Error::FromSqlConversionFailure(2, Type::Text, Box::new(error))
+                    })?;
+
+                Ok(NetworkRow {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    subnet: subnet.unwrap_or(config.subnet),
+                    owner: row.get(3)?,
+                })
+            },
+        )
+        .optional()?;
+
+    network.ok_or_else(|| {
+        warn!("no such network");
+        Error::NoSuchNetwork
+    })
+}
+
+/// Returns the address `user_id` holds in `network`, leasing one first if they hold none or
+/// `requested` a different one
+pub(crate) fn lease(
+    db: &Connection,
+    allocator: &dyn IpAllocator,
+    network: &NetworkRow,
+    user_id: i64,
+    requested: Option<Ipv4Addr>,
+    config: &Config,
+) -> Result<Ipv4Addr> {
+    let membership: Option<Option<u32>> = db
+        .query_row(
+            "select ip from memberships where network_id = ?1 and user_id = ?2",
+            [network.id, user_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    let Some(ip) = membership else {
+        warn!("not a member of {}", network.name);
+        return Err(Error::NotAMember);
+    };
+
+    // a lease from before the subnet was changed is as good as no lease
+    let leased = ip
+        .map(Ipv4Addr::from_bits)
+        .filter(|ip| ipalloc::is_assignable(network.subnet, *ip));
+
+    match (leased, requested) {
+        (Some(ip), None) => Ok(ip),
+        (Some(ip), Some(requested)) if ip == requested => Ok(ip),
+        (_, Some(_)) if !config.static_addresses => {
+            warn!("static addresses are disabled on this relay");
+            Err(Error::AddressUnavailable)
+        }
+        (_, requested) => {
+            let taken: HashSet<Ipv4Addr> = db
+                .prepare(
+                    "select ip from memberships
+                        where network_id = ?1 and user_id != ?2 and ip is not null",
+                )?
+                .query_map([network.id, user_id], |row| {
+                    row.get(0).map(Ipv4Addr::from_bits)
+                })?
+                .collect::<Result<_, _>>()?;
+
+            let ip = allocator.allocate(network.subnet, &taken, requested)?;
+            debug!(%ip, "leasing a new address in {}", network.name);
+
+            db.execute(
+                "update memberships set ip = ?1 where network_id = ?2 and user_id = ?3",
+                params![ip.to_bits(), network.id, user_id],
+            )?;
+            Ok(ip)
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use rstest::*;
+
+    async fn logged_in(db: &Db, config: &Config, username: &str) -> String {
+        db.register(username, "hunter2", None, config)
+            .await
+            .unwrap();
+        let (resp, _) = db
+            .login(username, "hunter2", None, None, config)
+            .await
+            .unwrap();
+        resp.token
+    }
+
+    #[tokio::test]
+    async fn test_networks_have_their_own_address_space() {
+        let db = Db::try_new_in_memory().await.unwrap();
+        let config = Config::default();
+        let alice = logged_in(&db, &config, "alice").await;
+        let subnet = "10.1.0.0/24".parse().unwrap();
+
+        db.create_network(&alice, "lan", Some(subnet))
+            .await
+            .unwrap();
+        let (default, _) = db
+            .login("alice", "hunter2", None, None, &config)
+            .await
+            .unwrap();
+        let (lan, _) = db
+            .login("alice", "hunter2", Some("lan"), None, &config)
+            .await
+            .unwrap();
+
+        assert_eq!(default.address, Ipv4Addr::new(100, 64, 0, 2));
+        assert_eq!(lan.address, Ipv4Addr::new(10, 1, 0, 2));
+        assert_eq!(lan.netmask, subnet.netmask());
+        assert_eq!(lan.gateway, Some(Ipv4Addr::new(10, 1, 0, 1)));
+        assert_ne!(
+            db.session(&default.token).await.unwrap().network_id,
+            db.session(&lan.token).await.unwrap().network_id
+        );
+    }
+
+    #[tokio::test]
+    async fn test_login_requires_membership() {
+        let db = Db::try_new_in_memory().await.unwrap();
+        let config = Config::default();
+        let alice = logged_in(&db, &config, "alice").await;
+        let bob = logged_in(&db, &config, "bob").await;
+
+        db.create_network(&alice, "lan", None).await.unwrap();
+        let res = db.login("bob", "hunter2", Some("lan"), None, &config).await;
+        assert!(matches!(res, Err(Error::NotAMember)));
+
+        db.invite_to_network(&alice, "lan", "bob", &config)
+            .await
+            .unwrap();
+        db.join_network(&bob, "lan", &config).await.unwrap();
+        let (resp, _) = db
+            .login("bob", "hunter2", Some("lan"), None, &config)
+            .await
+            .unwrap();
+        // alice never logged in to lan, so she holds no lease there yet
+        assert_eq!(resp.address, Ipv4Addr::new(100, 64, 0, 2));
+    }
+
+    #[tokio::test]
+    async fn test_joining_takes_an_invite() {
+        let db = Db::try_new_in_memory().await.unwrap();
+        let config = Config::default();
+        let alice = logged_in(&db, &config, "alice").await;
+        let bob = logged_in(&db, &config, "bob").await;
+        let carol = logged_in(&db, &config, "carol").await;
+
+        db.create_network(&alice, "lan", None).await.unwrap();
+        let res = db.join_network(&bob, "lan", &config).await;
+        assert!(matches!(res, Err(Error::NotInvited)));
+
+        // only alice may invite, and only people who exist
+        let res = db.invite_to_network(&carol, "lan", "bob", &config).await;
+        assert!(matches!(res, Err(Error::NotTheOwner)));
+        let res = db
+            .invite_to_network(&alice, "lan", "mallory", &config)
+            .await;
+        assert!(matches!(res, Err(Error::NoSuchUser)));
+        let res = db
+            .invite_to_network(&alice, DEFAULT_NETWORK, "bob", &config)
+            .await;
+        assert!(matches!(res, Err(Error::NotTheOwner)));
+
+        db.invite_to_network(&alice, "lan", "bob", &config)
+            .await
+            .unwrap();
+        db.join_network(&bob, "lan", &config).await.unwrap();
+        db.join_network(&bob, "lan", &config).await.unwrap();
+        let res = db.join_network(&carol, "lan", &config).await;
+        assert!(matches!(res, Err(Error::NotInvited)));
+
+        // the invite was used up by joining
+        db.leave_network(&bob, "lan", &config).await.unwrap();
+        let res = db.join_network(&bob, "lan", &config).await;
+        assert!(matches!(res, Err(Error::NotInvited)));
+
+        db.join_network(&carol, DEFAULT_NETWORK, &config)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_network_names_are_unique() {
+        let db = Db::try_new_in_memory().await.unwrap();
+        let config = Config::default();
+        let alice = logged_in(&db, &config, "alice").await;
+
+        let res = db.create_network(&alice, DEFAULT_NETWORK, None).await;
+        assert!(matches!(res, Err(Error::NetworkAlreadyExists)));
+
+        let res = db.join_network(&alice, "nowhere", &config).await;
+        assert!(matches!(res, Err(Error::NoSuchNetwork)));
+    }
+
+    #[rstest]
+    #[case::private("192.168.4.0/24", true)]
+    #[case::cgnat("100.100.0.0/16", true)]
+    #[case::host_bits_set("10.1.2.3/24", true)]
+    #[case::smallest("172.16.0.0/29", true)]
+    #[case::no_room_for_two("172.16.0.0/30", false)]
+    #[case::no_gateway("10.0.0.0/31", false)]
+    #[case::single_address("10.0.0.1/32", false)]
+    #[case::everything("0.0.0.0/0", false)]
+    #[case::public("8.8.8.0/24", false)]
+    #[case::straddling("10.0.0.0/7", false)]
+    #[tokio::test]
+    async fn test_subnets_are_checked(#[case] subnet: &str, #[case] valid: bool) {
+        let db = Db::try_new_in_memory().await.unwrap();
+        let config = Config::default();
+        let alice = logged_in(&db, &config, "alice").await;
+
+        let res = db
+            .create_network(&alice, "lan", Some(subnet.parse().unwrap()))
+            .await;
+        match valid {
+            true => res.unwrap(),
+            false => assert!(matches!(res, Err(Error::InvalidSubnet))),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_leaving_revokes_sessions() {
+        let db = Db::try_new_in_memory().await.unwrap();
+        let config = Config {
+            session_policy: crate::config::SessionPolicy::Keep,
+            ..Default::default()
+        };
+        let alice = logged_in(&db, &config, "alice").await;
+
+        db.create_network(&alice, "lan", None).await.unwrap();
+        let (lan, _) = db
+            .login("alice", "hunter2", Some("lan"), None, &config)
+            .await
+            .unwrap();
+        let session = db.session(&lan.token).await.unwrap();
+
+        let revoked = db.leave_network(&alice, "lan", &config).await.unwrap();
+        assert_eq!(revoked, vec![session.id]);
+        assert!(matches!(
+            db.session(&lan.token).await,
+            Err(Error::InvalidToken)
+        ));
+        db.session(&alice).await.unwrap();
+
+        let res = db.leave_network(&alice, "lan", &config).await;
+        assert!(matches!(res, Err(Error::NotAMember)));
+    }
+}
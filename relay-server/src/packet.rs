@@ -1,45 +1,190 @@
-use std::{collections::HashMap, net::Ipv4Addr, sync::Arc};
+use std::{
+    net::Ipv4Addr,
+    sync::{atomic::Ordering, Arc},
+};
+
+use bytes::Bytes;
 
+use crate::{
+    access::unix_now,
+    icmp, ipalloc,
+    metrics::{DropReason, Metrics},
+    peer::{PeerAddress, PeerCounters, PeerQueue},
+    ratelimit::RateLimiter,
+    tunnel::TunnelReceiver,
+    Route, RouteTable,
+};
 use etherparse::err::ipv4::{HeaderError, HeaderSliceError};
 use etherparse::Ipv4Header;
-use s2n_quic::stream::{ReceiveStream, SendStream};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    sync::{Mutex, RwLock},
-};
+use ipnet::Ipv4Net;
 
-#[instrument(skip(recv, route_table))]
-pub async fn parsepkt(
-    mut recv: ReceiveStream,
-    route_table: Arc<RwLock<HashMap<Ipv4Addr, Mutex<SendStream>>>>,
+/// The peer a packet loop reads from
+#[derive(Debug, Clone)]
+pub struct Sender {
+    /// the address the peer's session leased, the only source it may send from
+    pub(crate) ip: Arc<PeerAddress>,
+    /// subnet of the sender's network, needed to recognise its broadcast address
+    pub subnet: Ipv4Net,
+    /// the peer's own queue, for whatever the relay itself has to tell it
+    pub(crate) queue: Arc<PeerQueue>,
+    pub(crate) counters: Arc<PeerCounters>,
+}
+
+#[instrument(
+    skip(recv, route_table, sender, broadcast_limit, unreachable_limit, metrics),
+    fields(sender = %sender.ip.get())
+)]
+pub(crate) async fn parsepkt(
+    mut recv: TunnelReceiver,
+    route_table: RouteTable,
+    sender: Sender,
+    mut broadcast_limit: RateLimiter,
+    mut unreachable_limit: RateLimiter,
+    log_spoofed: bool,
+    metrics: Arc<Metrics>,
 ) {
     debug!(?route_table);
-    let mut buf = [0; 4096];
-    while let Ok(amount) = recv.read(&mut buf).await {
-        let pkt = &buf[..amount];
-        match Ipv4Header::from_slice(pkt) {
+    let mut limited = false;
+    let gateway = ipalloc::gateway(sender.subnet);
+
+    while let Ok(Some(pkt)) = recv.recv().await {
+        let _timer = metrics.packet_latency.start_timer();
+        let counters = &sender.counters;
+        counters.last_packet.store(unix_now(), Ordering::Relaxed);
+        counters.received_packets.fetch_add(1, Ordering::Relaxed);
+        counters
+            .received_bytes
+            .fetch_add(pkt.len() as u64, Ordering::Relaxed);
+
+        match Ipv4Header::from_slice(&pkt) {
             Ok((header, _)) => {
-                let destination = parse_ipv4(header);
-                if let Some(send_stream) = route_table.read().await.get(&destination) {
-                    trace!(?route_table, "found stream");
-                    let mut pkt = pkt;
-                    let mut send_stream = send_stream.lock().await;
-                    if let Err(error) = send_stream.write_buf(&mut pkt).await {
-                        error!(?error, "could not send packet to destination: {error}");
+                // a peer may only speak for itself, or it could pass as anyone in the network
+                let ip = sender.ip.get();
+                let source = Ipv4Addr::from_octets(header.source);
+                if source != ip {
+                    sender.counters.spoofed.fetch_add(1, Ordering::Relaxed);
+                    metrics.dropped(DropReason::Spoofed);
+                    if log_spoofed {
+                        warn!(%source, "dropping packet with a spoofed source address");
                     }
-                };
+                    continue;
+                }
+
+                let destination = parse_ipv4(&header);
+
+                // the relay's own address, where it only answers ping
+                if Some(destination) == gateway {
+                    match icmp::echo_reply(destination, &header, &pkt) {
+                        Some(reply) => {
+                            sender.queue.push(reply.into());
+                            metrics.echo_replies.fetch_add(1, Ordering::Relaxed);
+                        }
+                        None => metrics.dropped(DropReason::Gateway),
+                    }
+                    continue;
+                }
+
+                if !is_fan_out(destination, sender.subnet) {
+                    if let Some(route) = route_table.read().await.get(&destination) {
+                        trace!(?route_table, "found stream");
+                        forward(route, pkt, &metrics);
+                        continue;
+                    }
+
+                    metrics.dropped(DropReason::NoRoute);
+                    // tells the sender right away, instead of leaving it to time out. Networks too
+                    // small for a gateway get the error from the address that could not be
+                    // reached instead.
+                    let from = gateway.unwrap_or(destination);
+                    if unreachable_limit.try_acquire()
+                        && let Some(reply) = icmp::host_unreachable(from, &header, &pkt)
+                    {
+                        sender.queue.push(reply.into());
+                        metrics.unreachable_replies.fetch_add(1, Ordering::Relaxed);
+                    }
+                    continue;
+                }
+
+                if !broadcast_limit.try_acquire() {
+                    metrics.dropped(DropReason::RateLimited);
+                    if !limited {
+                        warn!(
+                            "dropping broadcasts for {:?}, sender is over its limit",
+                            broadcast_limit.backoff()
+                        );
+                    }
+                    limited = true;
+                    continue;
+                }
+                limited = false;
+
+                // everyone in the network who wants broadcasts hears it, except the peer that
+                // sent it
+                let table_r = route_table.read().await;
+                let listeners = table_r
+                    .iter()
+                    .filter(|(theirs, route)| **theirs != ip && route.broadcast);
+                for (_, route) in listeners {
+                    forward(route, pkt.clone(), &metrics);
+                }
             }
             // we ignore ipv6 errors
             Err(HeaderSliceError::Content(HeaderError::UnexpectedVersion {
                 version_number: 6,
             })) => (),
             // other errors might be of some concern
-            Err(error) => warn!(?error, "could not parse packet: {error}"),
+            Err(error) => {
+                metrics.dropped(DropReason::ParseError);
+                warn!(?error, "could not parse packet: {error}");
+            }
         }
     }
 }
 
-pub fn parse_ipv4(header: Ipv4Header) -> Ipv4Addr {
+pub fn parse_ipv4(header: &Ipv4Header) -> Ipv4Addr {
     debug!(?header);
     Ipv4Addr::from_octets(header.destination)
 }
+
+/// Hands `pkt` to the destination's writer. The route table stays locked while packets are
+/// forwarded, so this must never wait on the destination.
+fn forward(route: &Route, pkt: Bytes, metrics: &Metrics) {
+    let len = pkt.len();
+    match route.queue.push(pkt) {
+        true => metrics.routed(len),
+        false => metrics.dropped(DropReason::QueueFull),
+    }
+}
+
+/// Whether a packet to `destination` goes to every peer in the network instead of just one.
+/// That is the limited broadcast address, the subnet's own broadcast address and any
+/// multicast group, since the relay does not track group membership.
+fn is_fan_out(destination: Ipv4Addr, subnet: Ipv4Net) -> bool {
+    let subnet_broadcast = subnet.prefix_len() < 31 && destination == subnet.broadcast();
+
+    destination.is_broadcast() || destination.is_multicast() || subnet_broadcast
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case::limited_broadcast("255.255.255.255", true)]
+    #[case::subnet_broadcast("10.0.0.255", true)]
+    #[case::mdns("224.0.0.251", true)]
+    #[case::ssdp("239.255.255.250", true)]
+    #[case::unicast("10.0.0.7", false)]
+    #[case::other_broadcast("10.0.1.255", false)]
+    fn test_is_fan_out(#[case] destination: &str, #[case] expected: bool) {
+        let subnet = "10.0.0.0/24".parse().unwrap();
+        assert_eq!(is_fan_out(destination.parse().unwrap(), subnet), expected);
+    }
+
+    #[test]
+    fn test_point_to_point_has_no_broadcast() {
+        let subnet = "10.0.0.0/31".parse().unwrap();
+        assert!(!is_fan_out(Ipv4Addr::new(10, 0, 0, 1), subnet));
+    }
+}
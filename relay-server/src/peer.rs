@@ -0,0 +1,251 @@
+//! The outbound side of every peer. Packets for a peer are pushed onto its own bounded queue and
+//! written by a task of its own, so a slow peer only ever holds up packets meant for itself. The
+//! packet loops never wait on a peer, they drop packets instead, as picked by
+//! [`OverflowPolicy`].
+
+use std::{
+    collections::VecDeque,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+
+use crate::{config::OverflowPolicy, tunnel::TunnelSender};
+
+#[derive(Debug)]
+pub(crate) struct PeerQueue {
+    packets: Mutex<VecDeque<Bytes>>,
+    ready: Notify,
+    capacity: usize,
+    overflow: OverflowPolicy,
+    dropped: AtomicU64,
+    /// whether the last push dropped something, so a full queue is only logged once
+    full: AtomicBool,
+    closed: AtomicBool,
+}
+
+impl PeerQueue {
+    pub fn new(capacity: usize, overflow: OverflowPolicy) -> Self {
+        Self {
+            packets: Mutex::new(VecDeque::new()),
+            ready: Notify::new(),
+            capacity: capacity.max(1),
+            overflow,
+            dropped: AtomicU64::new(0),
+            full: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Queues `pkt` for the writer. Never waits, a full queue drops a packet instead, in which
+    /// case this returns `false`.
+    pub fn push(&self, pkt: Bytes) -> bool {
+        if self.closed.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        let mut packets = self.packets.lock().expect("peer queue lock poisoned");
+        let overflowed = packets.len() >= self.capacity;
+
+        match self.overflow {
+            OverflowPolicy::DropOldest => {
+                if overflowed {
+                    packets.pop_front();
+                }
+                packets.push_back(pkt);
+            }
+            OverflowPolicy::DropNewest if overflowed => (),
+            OverflowPolicy::DropNewest => packets.push_back(pkt),
+        }
+        drop(packets);
+
+        if overflowed {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            if !self.full.swap(true, Ordering::Relaxed) {
+                warn!(
+                    capacity = self.capacity,
+                    "peer is not keeping up, dropping packets ({:?})", self.overflow
+                );
+            }
+        } else {
+            self.full.store(false, Ordering::Relaxed);
+        }
+
+        self.ready.notify_one();
+        !overflowed
+    }
+
+    /// Waits for the next packet. `None` once the queue is closed.
+    async fn pop(&self) -> Option<Bytes> {
+        loop {
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+
+            let pkt = self
+                .packets
+                .lock()
+                .expect("peer queue lock poisoned")
+                .pop_front();
+            if pkt.is_some() {
+                return pkt;
+            }
+
+            // a push in between stores a permit, so this cannot miss it
+            self.ready.notified().await;
+        }
+    }
+
+    /// Stops the writer. Whatever is still queued is thrown away.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.ready.notify_one();
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.lock().expect("peer queue lock poisoned").len()
+    }
+}
+
+/// The address a peer's tunnel speaks for. An operator can move it while the tunnel is up, see
+/// `RouteUpdate::Reassign`.
+#[derive(Debug)]
+pub(crate) struct PeerAddress(AtomicU32);
+
+impl PeerAddress {
+    pub fn new(ip: Ipv4Addr) -> Self {
+        Self(AtomicU32::new(ip.to_bits()))
+    }
+
+    pub fn get(&self) -> Ipv4Addr {
+        Ipv4Addr::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, ip: Ipv4Addr) {
+        self.0.store(ip.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// What a peer's packet loop and writer count about the packets going through them
+#[derive(Debug, Default)]
+pub(crate) struct PeerCounters {
+    /// packets whose source was not the peer's own address
+    pub spoofed: AtomicU64,
+    /// unix timestamp of the last packet the peer sent, `0` before the first
+    pub last_packet: AtomicU64,
+    pub received_packets: AtomicU64,
+    pub received_bytes: AtomicU64,
+    pub sent_packets: AtomicU64,
+    pub sent_bytes: AtomicU64,
+}
+
+/// Writes everything pushed onto `queue` to the peer, until the queue is closed or the peer
+/// goes away
+#[instrument(skip(send, queue, counters))]
+pub(crate) async fn write_to_peer(
+    ip: Ipv4Addr,
+    mut send: TunnelSender,
+    queue: Arc<PeerQueue>,
+    counters: Arc<PeerCounters>,
+) {
+    while let Some(pkt) = queue.pop().await {
+        if let Err(error) = send.send(&pkt).await {
+            error!(?error, "could not send packet to peer: {error}");
+            queue.close();
+            continue;
+        }
+
+        counters.sent_packets.fetch_add(1, Ordering::Relaxed);
+        counters
+            .sent_bytes
+            .fetch_add(pkt.len() as u64, Ordering::Relaxed);
+    }
+
+    debug!(dropped = queue.dropped(), "writer stopped");
+}
+
+/// A snapshot of one peer's route and its counters
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    pub network: i64,
+    pub ip: Ipv4Addr,
+    pub session: i64,
+    pub username: String,
+    /// seconds since the peer's tunnel came up
+    pub connected_secs: u64,
+    /// packets waiting to be written to the peer
+    pub queued: usize,
+    /// packets thrown away because the queue was full
+    pub dropped: u64,
+    /// packets the peer sent from an address other than its own, all of them dropped
+    pub spoofed: u64,
+    /// packets the peer sent, whether they went anywhere or not
+    pub received_packets: u64,
+    pub received_bytes: u64,
+    /// packets written to the peer
+    pub sent_packets: u64,
+    pub sent_bytes: u64,
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use rstest::*;
+
+    fn pushed(overflow: OverflowPolicy, packets: &[&'static str]) -> PeerQueue {
+        let queue = PeerQueue::new(2, overflow);
+        for pkt in packets {
+            queue.push(Bytes::from_static(pkt.as_bytes()));
+        }
+        queue
+    }
+
+    #[rstest]
+    #[case::drop_oldest(OverflowPolicy::DropOldest, ["two", "three"])]
+    #[case::drop_newest(OverflowPolicy::DropNewest, ["one", "two"])]
+    #[tokio::test]
+    async fn test_overflow(#[case] overflow: OverflowPolicy, #[case] kept: [&str; 2]) {
+        let queue = pushed(overflow, &["one", "two"]);
+        assert!(!queue.push(Bytes::from_static(b"three")));
+
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.len(), 2);
+        for expected in kept {
+            assert_eq!(queue.pop().await.unwrap(), expected.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pop_waits_for_push() {
+        let queue = Arc::new(PeerQueue::new(2, OverflowPolicy::default()));
+
+        let popped = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.pop().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(queue.push(Bytes::from_static(b"late")));
+
+        assert_eq!(popped.await.unwrap().unwrap(), &b"late"[..]);
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_close_stops_the_writer() {
+        let queue = pushed(OverflowPolicy::default(), &["one"]);
+        queue.close();
+
+        assert!(queue.pop().await.is_none());
+        queue.push(Bytes::from_static(b"two"));
+        assert_eq!(queue.dropped(), 0);
+    }
+}
@@ -0,0 +1,422 @@
+//! Conversions between the control protocol's generated types, see `proto/lanshare.proto`, and
+//! the ones the rest of the relay works with. Decoding checks everything the schema cannot
+//! express, like which fields are required.
+
+use std::net::Ipv4Addr;
+
+use ipnet::Ipv4Net;
+
+// the generated types share their names with ours, so ours are always spelled out
+use crate::{
+    action::{
+        response::{self as resp, ActionError},
+        Action,
+    },
+    error::{self, Result},
+    events,
+    hello::{self, Capabilities},
+    wire::Message,
+};
+
+include!(concat!(env!("OUT_DIR"), "/lanshare.rs"));
+
+fn required<T>(field: Option<T>, name: &'static str) -> Result<T> {
+    field.ok_or(error::Error::InvalidMessage(name))
+}
+
+fn to_subnet(subnet: &Ipv4Net) -> Subnet {
+    Subnet {
+        address: subnet.addr().to_bits(),
+        prefix_len: subnet.prefix_len().into(),
+    }
+}
+
+fn ipv4_net(subnet: Subnet) -> Result<Ipv4Net> {
+    let prefix_len = u8::try_from(subnet.prefix_len).unwrap_or(u8::MAX);
+    Ipv4Net::new(Ipv4Addr::from_bits(subnet.address), prefix_len)
+        .map_err(|_| error::Error::InvalidMessage("subnet.prefix_len"))
+}
+
+impl Message for hello::Hello {
+    type Proto = Hello;
+
+    fn to_proto(&self) -> Self::Proto {
+        Hello {
+            version: self.version.into(),
+            min_version: self.min_version.into(),
+            capabilities: self.capabilities.bits(),
+        }
+    }
+
+    fn from_proto(proto: Self::Proto) -> Result<Self> {
+        // a version that does not fit is from far in the future, which negotiating rejects
+        Ok(Self {
+            version: u16::try_from(proto.version).unwrap_or(u16::MAX),
+            min_version: u16::try_from(proto.min_version).unwrap_or(u16::MAX),
+            capabilities: Capabilities::from_bits(proto.capabilities),
+        })
+    }
+}
+
+impl Message for Action {
+    type Proto = Request;
+
+    fn to_proto(&self) -> Self::Proto {
+        let action = match self.clone() {
+            Action::UpgradeConn { token } => request::Action::UpgradeConn(UpgradeConn { token }),
+            Action::Login {
+                name,
+                password,
+                network,
+                address,
+            } => request::Action::Login(Login {
+                name,
+                password,
+                network,
+                address: address.map(Ipv4Addr::to_bits),
+            }),
+            Action::Register {
+                name,
+                password,
+                invite,
+            } => request::Action::Register(Register {
+                name,
+                password,
+                invite,
+            }),
+            Action::Refresh { token } => request::Action::Refresh(Refresh { token }),
+            Action::Logout { token } => request::Action::Logout(Logout { token }),
+            Action::CreateNetwork {
+                token,
+                name,
+                subnet,
+            } => request::Action::CreateNetwork(CreateNetwork {
+                token,
+                name,
+                subnet: subnet.as_ref().map(to_subnet),
+            }),
+            Action::InviteToNetwork {
+                token,
+                name,
+                username,
+            } => request::Action::InviteToNetwork(InviteToNetwork {
+                token,
+                name,
+                username,
+            }),
+            Action::JoinNetwork { token, name } => {
+                request::Action::JoinNetwork(JoinNetwork { token, name })
+            }
+            Action::LeaveNetwork { token, name } => {
+                request::Action::LeaveNetwork(LeaveNetwork { token, name })
+            }
+            Action::WatchPeers { token } => request::Action::WatchPeers(WatchPeers { token }),
+            Action::ListPeers { token } => request::Action::ListPeers(ListPeers { token }),
+        };
+
+        Request {
+            action: Some(action),
+        }
+    }
+
+    fn from_proto(proto: Self::Proto) -> Result<Self> {
+        let action = match required(proto.action, "action")? {
+            request::Action::UpgradeConn(UpgradeConn { token }) => Action::UpgradeConn { token },
+            request::Action::Login(Login {
+                name,
+                password,
+                network,
+                address,
+            }) => Action::Login {
+                name,
+                password,
+                network,
+                address: address.map(Ipv4Addr::from_bits),
+            },
+            request::Action::Register(Register {
+                name,
+                password,
+                invite,
+            }) => Action::Register {
+                name,
+                password,
+                invite,
+            },
+            request::Action::Refresh(Refresh { token }) => Action::Refresh { token },
+            request::Action::Logout(Logout { token }) => Action::Logout { token },
+            request::Action::CreateNetwork(CreateNetwork {
+                token,
+                name,
+                subnet,
+            }) => Action::CreateNetwork {
+                token,
+                name,
+                subnet: subnet.map(ipv4_net).transpose()?,
+            },
+            request::Action::InviteToNetwork(InviteToNetwork {
+                token,
+                name,
+                username,
+            }) => Action::InviteToNetwork {
+                token,
+                name,
+                username,
+            },
+            request::Action::JoinNetwork(JoinNetwork { token, name }) => {
+                Action::JoinNetwork { token, name }
+            }
+            request::Action::LeaveNetwork(LeaveNetwork { token, name }) => {
+                Action::LeaveNetwork { token, name }
+            }
+            request::Action::WatchPeers(WatchPeers { token }) => Action::WatchPeers { token },
+            request::Action::ListPeers(ListPeers { token }) => Action::ListPeers { token },
+        };
+
+        Ok(action)
+    }
+}
+
+impl Message for events::PeerEvent {
+    type Proto = PeerEvent;
+
+    fn to_proto(&self) -> Self::Proto {
+        let event = match self.clone() {
+            events::PeerEvent::Joined { username, address } => peer_event::Event::Joined(Peer {
+                username,
+                address: address.to_bits(),
+            }),
+            events::PeerEvent::Left { username, address } => peer_event::Event::Left(Peer {
+                username,
+                address: address.to_bits(),
+            }),
+            events::PeerEvent::Moved { username, from, to } => {
+                peer_event::Event::Moved(PeerMoved {
+                    username,
+                    from: from.to_bits(),
+                    to: to.to_bits(),
+                })
+            }
+        };
+
+        PeerEvent { event: Some(event) }
+    }
+
+    fn from_proto(proto: Self::Proto) -> Result<Self> {
+        let event = match required(proto.event, "event")? {
+            peer_event::Event::Joined(Peer { username, address }) => events::PeerEvent::Joined {
+                username,
+                address: Ipv4Addr::from_bits(address),
+            },
+            peer_event::Event::Left(Peer { username, address }) => events::PeerEvent::Left {
+                username,
+                address: Ipv4Addr::from_bits(address),
+            },
+            peer_event::Event::Moved(PeerMoved { username, from, to }) => {
+                events::PeerEvent::Moved {
+                    username,
+                    from: Ipv4Addr::from_bits(from),
+                    to: Ipv4Addr::from_bits(to),
+                }
+            }
+        };
+
+        Ok(event)
+    }
+}
+
+/// Whatever a successful [`resp::Response`] can carry
+pub trait Payload: Sized {
+    fn to_result(&self) -> response::Result;
+    /// `None` if `result` holds a different payload
+    fn from_result(result: response::Result) -> Option<Self>;
+}
+
+impl Payload for () {
+    fn to_result(&self) -> response::Result {
+        response::Result::Ok(Empty {})
+    }
+
+    fn from_result(result: response::Result) -> Option<Self> {
+        match result {
+            response::Result::Ok(Empty {}) => Some(()),
+            _ => None,
+        }
+    }
+}
+
+impl Payload for resp::LoginResp {
+    fn to_result(&self) -> response::Result {
+        response::Result::Login(LoginResp {
+            token: self.token.clone(),
+            expires_at: self.expires_at,
+            address: self.address.to_bits(),
+            netmask: self.netmask.to_bits(),
+            gateway: self.gateway.map(Ipv4Addr::to_bits),
+        })
+    }
+
+    fn from_result(result: response::Result) -> Option<Self> {
+        match result {
+            response::Result::Login(resp) => Some(Self {
+                token: resp.token,
+                expires_at: resp.expires_at,
+                address: Ipv4Addr::from_bits(resp.address),
+                netmask: Ipv4Addr::from_bits(resp.netmask),
+                gateway: resp.gateway.map(Ipv4Addr::from_bits),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Payload for resp::TokenResp {
+    fn to_result(&self) -> response::Result {
+        response::Result::Token(TokenResp {
+            token: self.token.clone(),
+            expires_at: self.expires_at,
+        })
+    }
+
+    fn from_result(result: response::Result) -> Option<Self> {
+        match result {
+            response::Result::Token(resp) => Some(Self {
+                token: resp.token,
+                expires_at: resp.expires_at,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Payload for Vec<resp::PeerInfo> {
+    fn to_result(&self) -> response::Result {
+        let peers = self
+            .iter()
+            .map(|peer| PeerInfo {
+                username: peer.username.clone(),
+                address: peer.address.to_bits(),
+                state: PeerState::from(peer.state).into(),
+                connected_secs: peer.connected_secs,
+            })
+            .collect();
+
+        response::Result::Peers(PeerList { peers })
+    }
+
+    fn from_result(result: response::Result) -> Option<Self> {
+        let response::Result::Peers(PeerList { peers }) = result else {
+            return None;
+        };
+
+        let peers = peers
+            .into_iter()
+            .map(|peer| resp::PeerInfo {
+                // a newer relay may know states we do not
+                state: PeerState::try_from(peer.state)
+                    .unwrap_or(PeerState::Idle)
+                    .into(),
+                username: peer.username,
+                address: Ipv4Addr::from_bits(peer.address),
+                connected_secs: peer.connected_secs,
+            })
+            .collect();
+
+        Some(peers)
+    }
+}
+
+impl From<resp::PeerState> for PeerState {
+    fn from(state: resp::PeerState) -> Self {
+        match state {
+            resp::PeerState::Online => Self::Online,
+            resp::PeerState::Idle => Self::Idle,
+        }
+    }
+}
+
+impl From<PeerState> for resp::PeerState {
+    fn from(state: PeerState) -> Self {
+        match state {
+            PeerState::Online => Self::Online,
+            PeerState::Idle => Self::Idle,
+        }
+    }
+}
+
+impl<T: Payload> Message for resp::Response<T> {
+    type Proto = Response;
+
+    fn to_proto(&self) -> Self::Proto {
+        let result = match self {
+            Ok(payload) => payload.to_result(),
+            Err(error) => response::Result::Error(Error::from(*error).into()),
+        };
+
+        Response {
+            result: Some(result),
+        }
+    }
+
+    fn from_proto(proto: Self::Proto) -> Result<Self> {
+        match required(proto.result, "result")? {
+            response::Result::Error(code) => {
+                // a newer relay may know errors we do not
+                let error = Error::try_from(code).unwrap_or(Error::Internal);
+                Ok(Err(error.into()))
+            }
+            result => T::from_result(result)
+                .map(Ok)
+                .ok_or(error::Error::InvalidMessage("result")),
+        }
+    }
+}
+
+impl From<ActionError> for Error {
+    fn from(error: ActionError) -> Self {
+        match error {
+            ActionError::InvalidCredentials => Self::InvalidCredentials,
+            ActionError::InvalidToken => Self::InvalidToken,
+            ActionError::UserAlreadyExists => Self::UserAlreadyExists,
+            ActionError::RegistrationClosed => Self::RegistrationClosed,
+            ActionError::InvalidInvite => Self::InvalidInvite,
+            ActionError::AddressUnavailable => Self::AddressUnavailable,
+            ActionError::NoSuchNetwork => Self::NoSuchNetwork,
+            ActionError::NetworkAlreadyExists => Self::NetworkAlreadyExists,
+            ActionError::NotAMember => Self::NotAMember,
+            ActionError::NotInvited => Self::NotInvited,
+            ActionError::NotTheOwner => Self::NotTheOwner,
+            ActionError::NoSuchUser => Self::NoSuchUser,
+            ActionError::InvalidSubnet => Self::InvalidSubnet,
+            ActionError::ServerFull => Self::ServerFull,
+            ActionError::RateLimited => Self::RateLimited,
+            ActionError::VersionMismatch => Self::VersionMismatch,
+            ActionError::BadRequest => Self::BadRequest,
+            ActionError::Internal => Self::Internal,
+        }
+    }
+}
+
+impl From<Error> for ActionError {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::InvalidCredentials => Self::InvalidCredentials,
+            Error::InvalidToken => Self::InvalidToken,
+            Error::UserAlreadyExists => Self::UserAlreadyExists,
+            Error::RegistrationClosed => Self::RegistrationClosed,
+            Error::InvalidInvite => Self::InvalidInvite,
+            Error::AddressUnavailable => Self::AddressUnavailable,
+            Error::NoSuchNetwork => Self::NoSuchNetwork,
+            Error::NetworkAlreadyExists => Self::NetworkAlreadyExists,
+            Error::NotAMember => Self::NotAMember,
+            Error::NotInvited => Self::NotInvited,
+            Error::NotTheOwner => Self::NotTheOwner,
+            Error::NoSuchUser => Self::NoSuchUser,
+            Error::InvalidSubnet => Self::InvalidSubnet,
+            Error::ServerFull => Self::ServerFull,
+            Error::RateLimited => Self::RateLimited,
+            Error::VersionMismatch => Self::VersionMismatch,
+            Error::BadRequest => Self::BadRequest,
+            Error::Internal => Self::Internal,
+        }
+    }
+}
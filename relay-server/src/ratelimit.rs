@@ -0,0 +1,144 @@
+use std::{collections::HashMap, net::IpAddr, sync::Mutex};
+
+use tokio::time::{Duration, Instant};
+
+/// How many addresses [`AddrRateLimiter`] tracks before it forgets the ones that went quiet
+const MAX_TRACKED_ADDRS: usize = 4096;
+
+/// Token bucket that refills at `per_sec` and holds at most `burst` tokens. A `per_sec` of `0`
+/// turns the limit off.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    per_sec: u32,
+    burst: u32,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_sec: u32, burst: u32) -> Self {
+        Self {
+            per_sec,
+            burst,
+            tokens: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes a token if there is one. Callers drop whatever they were about to send otherwise.
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        if self.per_sec == 0 {
+            return true;
+        }
+
+        let elapsed = now.saturating_duration_since(self.last);
+        self.last = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.per_sec as f64)
+            .min(self.burst.max(1) as f64);
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Whether the bucket has refilled completely, in which case it is as good as a new one
+    fn is_full_at(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last);
+        self.tokens + elapsed.as_secs_f64() * self.per_sec as f64 >= self.burst as f64
+    }
+
+    /// How long until the next token, for logging
+    pub fn backoff(&self) -> Duration {
+        if self.per_sec == 0 || self.tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64((1.0 - self.tokens) / self.per_sec as f64)
+    }
+}
+
+/// A [`RateLimiter`] for each client address, for actions anyone can attempt without an account
+#[derive(Debug)]
+pub struct AddrRateLimiter {
+    per_sec: u32,
+    burst: u32,
+    limiters: Mutex<HashMap<IpAddr, RateLimiter>>,
+}
+
+impl AddrRateLimiter {
+    pub fn new(per_sec: u32, burst: u32) -> Self {
+        Self {
+            per_sec,
+            burst,
+            limiters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn try_acquire(&self, addr: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut limiters = self.limiters.lock().expect("rate limiter lock poisoned");
+
+        if limiters.len() >= MAX_TRACKED_ADDRS {
+            limiters.retain(|_, limiter| !limiter.is_full_at(now));
+        }
+
+        let allowed = limiters
+            .entry(addr)
+            .or_insert_with(|| RateLimiter::new(self.per_sec, self.burst))
+            .try_acquire_at(now);
+        if !allowed {
+            warn!(%addr, "too many attempts, refusing");
+        }
+
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_refill() {
+        let mut limiter = RateLimiter::new(10, 3);
+        let start = limiter.last;
+
+        assert!((0..3).all(|_| limiter.try_acquire_at(start)));
+        assert!(!limiter.try_acquire_at(start));
+
+        assert!(limiter.try_acquire_at(start + Duration::from_millis(100)));
+        assert!(!limiter.try_acquire_at(start + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_refill_is_capped_at_burst() {
+        let mut limiter = RateLimiter::new(10, 2);
+        let later = limiter.last + Duration::from_secs(60);
+
+        assert!((0..2).all(|_| limiter.try_acquire_at(later)));
+        assert!(!limiter.try_acquire_at(later));
+    }
+
+    #[test]
+    fn test_addresses_are_limited_separately() {
+        let limiter = AddrRateLimiter::new(1, 2);
+        let [alice, bob] = [[10, 0, 0, 1], [10, 0, 0, 2]].map(IpAddr::from);
+
+        assert!((0..2).all(|_| limiter.try_acquire(alice)));
+        assert!(!limiter.try_acquire(alice));
+        assert!(limiter.try_acquire(bob));
+    }
+
+    #[test]
+    fn test_zero_rate_is_unlimited() {
+        let mut limiter = RateLimiter::new(0, 0);
+        assert!((0..1000).all(|_| limiter.try_acquire()));
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write as _,
+    os::unix::fs::OpenOptionsExt as _,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use s2n_quic::provider::tls::s2n_tls::{self, ConnectionContext};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{config::Config, error::*};
+
+/// The TLS config handed to every new QUIC connection. Swapping the inner config only affects
+/// handshakes that start afterwards, established connections keep the key they negotiated with.
+#[derive(Clone)]
+pub struct TlsConfig {
+    current: Arc<RwLock<s2n_tls::config::Config>>,
+}
+
+impl TlsConfig {
+    /// Loads the certificate and key named in `config`, generating a self-signed pair first if
+    /// neither of them exists yet.
+    #[instrument(skip(config))]
+    pub fn try_new(config: &Config) -> Result<Self> {
+        ensure_cert(config)?;
+        let current = load(&config.cert_path, &config.key_path)?;
+
+        Ok(Self {
+            current: Arc::new(RwLock::new(current)),
+        })
+    }
+
+    pub fn server(&self) -> s2n_tls::Server<impl s2n_tls::ConfigLoader> {
+        let current = self.current.clone();
+        s2n_tls::Server::from_loader(move |_cx: ConnectionContext| {
+            current.read().expect("tls config lock poisoned").clone()
+        })
+    }
+
+    #[instrument(skip(self, config))]
+    pub fn reload(&self, config: &Config) -> Result {
+        let new = load(&config.cert_path, &config.key_path)?;
+        *self.current.write().expect("tls config lock poisoned") = new;
+
+        info!(
+            "reloaded tls certificate from {}",
+            config.cert_path.display()
+        );
+        Ok(())
+    }
+
+    /// Reloads the certificate every time the process receives SIGHUP. A certificate that fails
+    /// to load is logged and the previous one stays in use.
+    pub async fn reload_on_sighup(self, config: Arc<Config>) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(value) => value,
+            Err(error) => return error!("could not listen for SIGHUP: {error}"),
+        };
+
+        while hangup.recv().await.is_some() {
+            if let Err(error) = self.reload(&config) {
+                error!("keeping the old certificate, reload failed: {error}");
+            }
+        }
+    }
+}
+
+fn load(cert_path: &Path, key_path: &Path) -> Result<s2n_tls::config::Config> {
+    let cert = fs::read_to_string(cert_path).map_err(Error::CertIoError)?;
+    let key = fs::read_to_string(key_path).map_err(Error::CertIoError)?;
+
+    let server = s2n_tls::Server::builder()
+        .with_certificate(cert, key)
+        .and_then(|builder| builder.build())
+        .map_err(|error| QuicError::TlsError(error.to_string()))?;
+
+    Ok(server.into())
+}
+
+#[instrument(skip(config))]
+fn ensure_cert(config: &Config) -> Result {
+    let cert_exists = config.cert_path.try_exists().map_err(Error::CertIoError)?;
+    let key_exists = config.key_path.try_exists().map_err(Error::CertIoError)?;
+
+    match (cert_exists, key_exists) {
+        (true, true) => return Ok(()),
+        (false, false) => (),
+        // generating one half would leave a pair that can never match
+        _ => return Err(Error::IncompleteCertPair),
+    }
+
+    warn!(
+        names = ?config.cert_names,
+        "no certificate found, generating a self-signed one at {}",
+        config.cert_path.display()
+    );
+
+    let generated = rcgen::generate_simple_self_signed(config.cert_names.clone())?;
+
+    write_file(&config.key_path, &generated.key_pair.serialize_pem(), 0o600)?;
+    write_file(&config.cert_path, &generated.cert.pem(), 0o644)?;
+
+    Ok(())
+}
+
+fn write_file(path: &Path, contents: &str, mode: u32) -> Result {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(Error::CertIoError)?;
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)
+        .map_err(Error::CertIoError)?;
+    file.write_all(contents.as_bytes())
+        .map_err(Error::CertIoError)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn test_config(dir: &Path) -> Config {
+        Config {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_generates_missing_pair() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+
+        TlsConfig::try_new(&config).unwrap();
+
+        assert!(config.cert_path.exists());
+        assert!(config.key_path.exists());
+    }
+
+    #[test]
+    fn test_keeps_existing_pair() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+
+        let tls = TlsConfig::try_new(&config).unwrap();
+        let cert = fs::read_to_string(&config.cert_path).unwrap();
+
+        TlsConfig::try_new(&config).unwrap();
+        tls.reload(&config).unwrap();
+        assert_eq!(cert, fs::read_to_string(&config.cert_path).unwrap());
+    }
+
+    #[test]
+    fn test_refuses_half_a_pair() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        fs::write(&config.cert_path, "").unwrap();
+
+        let res = TlsConfig::try_new(&config);
+        assert!(matches!(res, Err(Error::IncompleteCertPair)));
+    }
+}
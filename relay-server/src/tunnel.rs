@@ -0,0 +1,172 @@
+use std::{future::poll_fn, task::Poll};
+
+use bytes::Bytes;
+use s2n_quic::{
+    connection,
+    provider::datagram::default::{self as datagram, DatagramError},
+    stream::{BidirectionalStream, ReceiveStream, SendStream},
+};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+use crate::{
+    error::*,
+    framing::{self, FrameDecoder},
+    hello::Capabilities,
+};
+
+/// Datagrams waiting to go out, or waiting to be read, per connection. Both queues drop packets
+/// once full, which beats delivering them late.
+const DATAGRAM_QUEUE: usize = 1024;
+
+/// s2n-quic silently drops a queued datagram that does not fit in the packet being built. Without
+/// knowing the path MTU, stay below what the smallest packet QUIC allows (1200 bytes) has room
+/// for. Anything bigger, like full sized TCP segments, takes the stream instead.
+const MAX_DATAGRAM_LEN: usize = 1100;
+
+/// Endpoint for `with_datagram`, on both the relay and the daemon
+pub fn datagram_endpoint() -> datagram::Endpoint {
+    datagram::Endpoint::builder()
+        .with_send_capacity(DATAGRAM_QUEUE)
+        .and_then(|builder| builder.with_recv_capacity(DATAGRAM_QUEUE))
+        .expect("datagram queue capacity is not zero")
+        .build()
+        .expect("infailable")
+}
+
+/// One peer's packets, in both directions. Packets travel as unreliable datagrams whenever both
+/// ends agreed on [`Capabilities::DATAGRAMS`], so a lost packet only costs that packet.
+/// Otherwise, or when a packet is too big for a datagram, they fall back to an ordered stream,
+/// framed by [`framing`].
+#[derive(Debug)]
+pub struct Tunnel {
+    connection: connection::Handle,
+    stream: BidirectionalStream,
+    datagrams: bool,
+}
+
+impl Tunnel {
+    /// `capabilities` are the ones both ends of `connection` share
+    pub fn new(
+        connection: connection::Handle,
+        stream: BidirectionalStream,
+        capabilities: Capabilities,
+    ) -> Self {
+        Self {
+            connection,
+            stream,
+            datagrams: capabilities.contains(Capabilities::DATAGRAMS),
+        }
+    }
+
+    pub fn split(self) -> (TunnelReceiver, TunnelSender) {
+        let (recv, send) = self.stream.split();
+
+        let receiver = TunnelReceiver {
+            connection: self.connection.clone(),
+            recv,
+            buf: vec![0; 4096].into_boxed_slice(),
+            decoder: FrameDecoder::default(),
+            datagrams: self.datagrams,
+        };
+        let sender = TunnelSender {
+            connection: self.connection,
+            send,
+            datagrams: self.datagrams,
+        };
+
+        (receiver, sender)
+    }
+}
+
+#[derive(Debug)]
+pub struct TunnelSender {
+    connection: connection::Handle,
+    send: SendStream,
+    datagrams: bool,
+}
+
+impl TunnelSender {
+    pub async fn send(&mut self, pkt: &[u8]) -> Result {
+        if self.datagrams && pkt.len() <= MAX_DATAGRAM_LEN {
+            let datagram = Bytes::copy_from_slice(pkt);
+            let res = self
+                .connection
+                .datagram_mut(|sender: &mut datagram::Sender| sender.send_datagram(datagram));
+
+            match res {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(DatagramError::QueueAtCapacity { .. })) => {
+                    trace!("datagram queue is full, dropping packet");
+                    return Ok(());
+                }
+                Ok(Err(DatagramError::ConnectionError { error, .. })) => {
+                    return Err(QuicError::from(error).into());
+                }
+                // the transport did not negotiate them after all
+                Ok(Err(_)) | Err(_) => (),
+            }
+        }
+
+        let frame = framing::encode(pkt)?;
+        self.send.write_all(&frame).await.map_err(QuicError::from)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct TunnelReceiver {
+    connection: connection::Handle,
+    recv: ReceiveStream,
+    buf: Box<[u8]>,
+    decoder: FrameDecoder,
+    datagrams: bool,
+}
+
+impl TunnelReceiver {
+    /// Waits for the next packet from either the datagrams or the stream. `None` means the peer
+    /// closed the tunnel.
+    pub async fn recv(&mut self) -> Result<Option<Bytes>> {
+        loop {
+            // one read can hold several packets, hand those out before waiting again
+            if let Some(pkt) = self.decoder.decode()? {
+                return Ok(Some(pkt));
+            }
+
+            let (connection, datagrams) = (&self.connection, self.datagrams);
+            let datagram = poll_fn(|cx| {
+                // without datagrams on both ends, the stream is all there is
+                if !datagrams {
+                    return Poll::Pending;
+                }
+
+                let res = connection.datagram_mut(|receiver: &mut datagram::Receiver| {
+                    receiver.poll_recv_datagram(cx)
+                });
+                res.unwrap_or(Poll::Pending)
+            });
+
+            tokio::select! {
+                res = datagram => return match res {
+                    Ok(datagram) => Ok(Some(datagram)),
+                    Err(DatagramError::ConnectionError { error, .. }) => {
+                        Err(QuicError::from(error).into())
+                    }
+                    Err(error) => {
+                        warn!("could not receive datagram: {error}");
+                        Ok(None)
+                    }
+                },
+                res = self.recv.read(&mut self.buf) => match res.map_err(QuicError::from)? {
+                    0 => {
+                        if !self.decoder.is_empty() {
+                            warn!("tunnel stream ended in the middle of a packet");
+                        }
+                        return Ok(None);
+                    }
+                    amount => self.decoder.extend(&self.buf[..amount]),
+                },
+            }
+        }
+    }
+}
@@ -1,27 +1,37 @@
-use std::sync::LazyLock;
+//! Messages of the control protocol on a QUIC stream. Each one is a big endian `u32` length
+//! followed by the message, encoded as protobuf according to `proto/lanshare.proto`.
 
-use bincode::{
-    config::{Bounded, WithOtherLimit},
-    Options,
-};
-use serde::{de::DeserializeOwned, Serialize};
+use std::time::Duration;
+
+use prost::Message as _;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::error::*;
 
-const BINCODE_BYTE_LIMIT: u64 = 16 * 1024;
+/// Longest message either side sends or accepts
+pub const MAX_MESSAGE_LEN: usize = 16 * 1024;
+
+/// Something that goes on the wire as one of the protobuf messages in [`crate::proto`]
+pub trait Message: Sized {
+    type Proto: prost::Message + Default;
 
-pub static BINCODE: LazyLock<WithOtherLimit<bincode::DefaultOptions, Bounded>> =
-    LazyLock::new(|| bincode::DefaultOptions::new().with_limit(BINCODE_BYTE_LIMIT));
+    fn to_proto(&self) -> Self::Proto;
+    fn from_proto(proto: Self::Proto) -> Result<Self>;
+}
 
 #[instrument(skip(stream))]
 pub async fn deserialise_stream<S, T>(stream: &mut S) -> Result<T>
 where
     S: AsyncRead + Unpin,
-    T: DeserializeOwned,
+    T: Message,
 {
     let len = read_stream_len(stream).await?;
 
+    // checked before allocating, the length is whatever the other end claims it is
+    if len > MAX_MESSAGE_LEN {
+        return Err(Error::MessageTooLarge { len });
+    }
+
     let mut buf = vec![0; len];
     let data_len = stream
         .read_exact(&mut buf)
@@ -32,19 +42,32 @@ where
         warn!(?data_len, ?len, "data len is not the same as expected len");
     }
 
-    let data = BINCODE.deserialize(&buf)?;
+    let proto = T::Proto::decode(buf.as_slice())?;
+    T::from_proto(proto)
+}
 
-    Ok(data)
+/// [`deserialise_stream`], giving up with [`Error::Timeout`] once `deadline` passes
+pub async fn deserialise_within<S, T>(stream: &mut S, deadline: Duration) -> Result<T>
+where
+    S: AsyncRead + Unpin,
+    T: Message,
+{
+    tokio::time::timeout(deadline, deserialise_stream(stream))
+        .await
+        .map_err(|_| Error::Timeout)?
 }
 
 pub async fn serialise_stream<S, T>(stream: &mut S, data: &T) -> Result
 where
     S: AsyncWrite + Unpin,
-    T: Serialize,
+    T: Message,
 {
-    let mut data = BINCODE.serialize(data)?;
+    let mut data = data.to_proto().encode_to_vec();
 
     let len = data.len();
+    if len > MAX_MESSAGE_LEN {
+        return Err(Error::MessageTooLarge { len });
+    }
 
     let mut final_data = (len as u32).to_be_bytes().to_vec();
     final_data.append(&mut data);
@@ -72,18 +95,15 @@ where
 #[cfg(test)]
 mod unit_tests {
     use super::*;
+    use crate::{
+        action::Action,
+        hello::{Capabilities, Hello},
+    };
     use rstest::*;
-    use serde::{Deserialize, Serialize};
     use std::io::Cursor;
     use std::pin::Pin;
     use tokio::io::{AsyncRead, AsyncWrite};
 
-    #[derive(Debug, Serialize, Deserialize, PartialEq)]
-    struct TestStruct {
-        field1: String,
-        field2: i32,
-    }
-
     // Mock async stream for testing
     struct MockStream {
         cursor: Cursor<Vec<u8>>,
@@ -145,10 +165,7 @@ mod unit_tests {
     #[rstest]
     #[tokio::test]
     async fn test_serialise_deserialise_round_trip() {
-        let test_data = TestStruct {
-            field1: "test".to_string(),
-            field2: 123,
-        };
+        let test_data = Hello::new(Capabilities::DATAGRAMS);
 
         // Serialize
         let mut write_stream = MockStream::new(Vec::new());
@@ -161,7 +178,7 @@ mod unit_tests {
 
         // Deserialize
         let mut read_stream = MockStream::new(serialized_data);
-        let deserialized: TestStruct = deserialise_stream(&mut read_stream).await.unwrap();
+        let deserialized: Hello = deserialise_stream(&mut read_stream).await.unwrap();
 
         assert_eq!(test_data, deserialized);
     }
@@ -169,7 +186,7 @@ mod unit_tests {
     #[tokio::test]
     async fn test_deserialise_insufficient_len_bytes() {
         let mut stream = MockStream::new(vec![1, 2]); // Insufficient bytes for length
-        let result: Result<TestStruct> = deserialise_stream(&mut stream).await;
+        let result: Result<Hello> = deserialise_stream(&mut stream).await;
         assert!(matches!(result, Err(Error::InsufficientLenBytes)));
     }
 
@@ -177,24 +194,205 @@ mod unit_tests {
     async fn test_deserialise_invalid_data() {
         // Create invalid data with correct length prefix but invalid content
         let mut data = 4u32.to_be_bytes().to_vec();
-        data.extend_from_slice(&[1, 2, 3, 4]); // Invalid bincode data
+        data.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]); // a varint that never ends
         let mut stream = MockStream::new(data);
 
-        let result: Result<TestStruct> = deserialise_stream(&mut stream).await;
-        assert!(matches!(result, Err(Error::BincodeError(_))));
+        let result: Result<Hello> = deserialise_stream(&mut stream).await;
+        assert!(matches!(result, Err(Error::DecodeError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_deserialise_missing_action() {
+        // an empty message is a valid `Request`, just not one with an action in it
+        let mut stream = MockStream::new(0u32.to_be_bytes().to_vec());
+
+        let result: Result<Action> = deserialise_stream(&mut stream).await;
+        assert!(matches!(result, Err(Error::InvalidMessage("action"))));
+    }
+
+    #[tokio::test]
+    async fn test_deserialise_huge_len_is_refused_before_reading() {
+        // nothing follows the length, so only the length check can end this
+        let mut stream = MockStream::new(u32::MAX.to_be_bytes().to_vec());
+
+        let result: Result<Action> = deserialise_stream(&mut stream).await;
+        assert!(matches!(
+            result,
+            Err(Error::MessageTooLarge { len }) if len == u32::MAX as usize
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_deserialise_stalled_stream_times_out() {
+        // the length arrives, the message never does
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&8u32.to_be_bytes()).await.unwrap();
+
+        let deadline = Duration::from_millis(50);
+        let result: Result<Hello> = deserialise_within(&mut server, deadline).await;
+        assert!(matches!(result, Err(Error::Timeout)));
     }
 
     #[tokio::test]
     async fn test_serialise_large_data() {
-        // Create data that exceeds BINCODE_BYTE_LIMIT
-        let large_string = "x".repeat(BINCODE_BYTE_LIMIT as usize + 1);
-        let test_data = TestStruct {
-            field1: large_string,
-            field2: 123,
+        // Create data that exceeds MAX_MESSAGE_LEN
+        let test_data = Action::Register {
+            name: "alice".to_string(),
+            password: "x".repeat(MAX_MESSAGE_LEN + 1),
+            invite: None,
         };
 
         let mut stream = MockStream::new(Vec::new());
         let result = serialise_stream(&mut stream, &test_data).await;
-        assert!(matches!(result, Err(Error::BincodeError(_))));
+        assert!(matches!(result, Err(Error::MessageTooLarge { .. })));
+    }
+
+    /// Everything the other end has to decode, exactly as it goes out. If one of these fails,
+    /// the change breaks compatibility: bump `hello::PROTOCOL_VERSION`, then update the bytes.
+    mod golden {
+        use std::net::Ipv4Addr;
+
+        use super::*;
+        use crate::{action::response::*, events::PeerEvent};
+
+        async fn encoded<T: Message>(data: &T) -> String {
+            let mut frame = Vec::new();
+            serialise_stream(&mut frame, data).await.unwrap();
+
+            frame.iter().map(|byte| format!("{byte:02x}")).collect()
+        }
+
+        #[tokio::test]
+        async fn test_hello() {
+            let hello = Hello::new(Capabilities::DATAGRAMS | Capabilities::BROADCAST);
+            assert_eq!(encoded(&hello).await, "00000006080610031805");
+        }
+
+        #[tokio::test]
+        async fn test_login() {
+            let action = Action::Login {
+                name: "alice".to_string(),
+                password: "hunter2".to_string(),
+                network: Some("lan".to_string()),
+                address: Some(Ipv4Addr::new(100, 64, 0, 1)),
+            };
+            assert_eq!(
+                encoded(&action).await,
+                "0000001c121a0a05616c696365120768756e746572321a036c616e2501004064"
+            );
+        }
+
+        #[tokio::test]
+        async fn test_upgrade_conn() {
+            let action = Action::UpgradeConn {
+                token: "token".to_string(),
+            };
+            assert_eq!(encoded(&action).await, "000000090a070a05746f6b656e");
+        }
+
+        #[tokio::test]
+        async fn test_create_network() {
+            let action = Action::CreateNetwork {
+                token: "token".to_string(),
+                name: "lan".to_string(),
+                subnet: Some("10.1.0.0/24".parse().unwrap()),
+            };
+            assert_eq!(
+                encoded(&action).await,
+                "0000001732150a05746f6b656e12036c616e1a070d0000010a1018"
+            );
+        }
+
+        #[tokio::test]
+        async fn test_invite_to_network() {
+            let action = Action::InviteToNetwork {
+                token: "token".to_string(),
+                name: "lan".to_string(),
+                username: "bob".to_string(),
+            };
+            assert_eq!(
+                encoded(&action).await,
+                "000000135a110a05746f6b656e12036c616e1a03626f62"
+            );
+        }
+
+        #[tokio::test]
+        async fn test_watch_peers() {
+            let action = Action::WatchPeers {
+                token: "token".to_string(),
+            };
+            assert_eq!(encoded(&action).await, "000000094a070a05746f6b656e");
+        }
+
+        #[tokio::test]
+        async fn test_list_peers() {
+            let action = Action::ListPeers {
+                token: "token".to_string(),
+            };
+            assert_eq!(encoded(&action).await, "0000000952070a05746f6b656e");
+        }
+
+        #[tokio::test]
+        async fn test_peer_list() {
+            let res: Response<Vec<PeerInfo>> = Ok(vec![PeerInfo {
+                username: "bob".to_string(),
+                address: Ipv4Addr::new(100, 64, 0, 2),
+                state: PeerState::Idle,
+                connected_secs: 90,
+            }]);
+            assert_eq!(
+                encoded(&res).await,
+                "0000001222100a0e0a03626f6215020040641801205a"
+            );
+        }
+
+        #[tokio::test]
+        async fn test_peer_moved() {
+            let event = PeerEvent::Moved {
+                username: "bob".to_string(),
+                from: Ipv4Addr::new(100, 64, 0, 2),
+                to: Ipv4Addr::new(100, 64, 0, 9),
+            };
+            assert_eq!(
+                encoded(&event).await,
+                "000000111a0f0a03626f6215020040641d09004064"
+            );
+        }
+
+        #[tokio::test]
+        async fn test_login_resp() {
+            let res: Response<LoginResp> = Ok(LoginResp {
+                token: "token".to_string(),
+                expires_at: 1_700_000_000,
+                address: Ipv4Addr::new(100, 64, 0, 1),
+                netmask: Ipv4Addr::new(255, 192, 0, 0),
+                gateway: None,
+            });
+            assert_eq!(
+                encoded(&res).await,
+                "0000001912170a05746f6b656e1080e2cfaa061d01004064250000c0ff"
+            );
+        }
+
+        #[tokio::test]
+        async fn test_login_resp_with_gateway() {
+            let res: Response<LoginResp> = Ok(LoginResp {
+                token: "token".to_string(),
+                expires_at: 1_700_000_000,
+                address: Ipv4Addr::new(100, 64, 0, 2),
+                netmask: Ipv4Addr::new(255, 192, 0, 0),
+                gateway: Some(Ipv4Addr::new(100, 64, 0, 1)),
+            });
+            assert_eq!(
+                encoded(&res).await,
+                "0000001e121c0a05746f6b656e1080e2cfaa061d02004064250000c0ff2d01004064"
+            );
+        }
+
+        #[tokio::test]
+        async fn test_error_resp() {
+            let res: Response = Err(ActionError::RateLimited);
+            assert_eq!(encoded(&res).await, "00000002780b");
+        }
     }
 }
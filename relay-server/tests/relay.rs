@@ -0,0 +1,937 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use relay_server::{admin::AdminClient, client::*, config::Config, error, Networks, Server};
+use rstest::*;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+/// Starts a relay on a random loopback port, backed by a throwaway database and a freshly
+/// generated certificate
+async fn start_relay() -> (ClientConfig, TempDir) {
+    start_relay_with(|_| ()).await
+}
+
+async fn start_relay_with(configure: impl FnOnce(&mut Config)) -> (ClientConfig, TempDir) {
+    let (config, _, dir) = start_watched_relay(configure).await;
+    (config, dir)
+}
+
+/// Same as [`start_relay_with`], but also hands out the relay's route tables to check its
+/// counters
+async fn start_watched_relay(
+    configure: impl FnOnce(&mut Config),
+) -> (ClientConfig, Networks, TempDir) {
+    let (config, mut server, dir) = start_server(configure).await;
+    let networks = server.networks();
+    tokio::spawn(async move { server.accept().await });
+
+    (config, networks, dir)
+}
+
+/// Same as [`start_relay_with`], but hands out the relay itself before it starts accepting
+async fn start_server(configure: impl FnOnce(&mut Config)) -> (ClientConfig, Server, TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut config = Config {
+        listen_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        db_path: dir.path().join("relay.db"),
+        admin_socket: dir.path().join("admin.sock"),
+        cert_path: dir.path().join("relay.crt"),
+        key_path: dir.path().join("relay.key"),
+        ..Default::default()
+    };
+    configure(&mut config);
+    let trust_anchor = config.cert_path.clone();
+
+    let server = Server::try_new(config).await.unwrap();
+    let client_config = ClientConfig {
+        server_addr: server.local_addr().unwrap(),
+        server_name: "localhost".to_string(),
+        trust_anchor,
+        datagrams: true,
+        broadcast: true,
+    };
+
+    (client_config, server, dir)
+}
+
+#[tokio::test]
+async fn test_login_assigns_address_in_subnet() {
+    let (config, _dir) = start_relay().await;
+    let client = Client::try_new(config).await.unwrap();
+
+    client.register("alice", "hunter2", None).await.unwrap();
+    let resp = client.login("alice", "hunter2", None, None).await.unwrap();
+
+    let subnet = Config::default().subnet;
+    assert!(subnet.contains(&resp.address));
+    assert_eq!(resp.netmask, subnet.netmask());
+    assert_eq!(resp.gateway, subnet.hosts().next());
+    assert_ne!(Some(resp.address), resp.gateway);
+}
+
+#[tokio::test]
+async fn test_failures_are_typed() {
+    let (config, _dir) = start_relay().await;
+    let client = Client::try_new(config).await.unwrap();
+
+    client.register("bob", "hunter2", None).await.unwrap();
+
+    let res = client.register("bob", "hunter2", None).await;
+    assert!(matches!(res, Err(error::Error::UserAlreadyExists)));
+
+    let res = client.login("bob", "wrong", None, None).await;
+    assert!(matches!(res, Err(error::Error::InvalidCredentials)));
+}
+
+#[tokio::test]
+async fn test_upgrade_failures_are_typed() {
+    let (config, _dir) = start_relay().await;
+    let client = Client::try_new(config).await.unwrap();
+
+    let res = client.upgrade_conn("not-a-token").await;
+    assert!(matches!(res, Err(error::Error::InvalidToken)));
+}
+
+#[tokio::test]
+async fn test_login_attempts_are_rate_limited() {
+    let (config, _dir) = start_relay_with(|config| {
+        config.limits.auth_per_sec = 1;
+        config.limits.auth_burst = 2;
+    })
+    .await;
+    let client = Client::try_new(config).await.unwrap();
+
+    client.register("mallory", "hunter2", None).await.unwrap();
+    let res = client.login("mallory", "guess", None, None).await;
+    assert!(matches!(res, Err(error::Error::InvalidCredentials)));
+
+    let res = client.login("mallory", "hunter2", None, None).await;
+    assert!(matches!(res, Err(error::Error::RateLimited)));
+}
+
+#[tokio::test]
+async fn test_wrong_server_name_is_rejected() {
+    let (mut config, _dir) = start_relay().await;
+    config.server_name = "not-the-relay".to_string();
+    let client = Client::try_new(config).await.unwrap();
+
+    assert!(client.register("carol", "hunter2", None).await.is_err());
+}
+
+#[tokio::test]
+async fn test_logout_tears_down_route() {
+    let (config, _dir) = start_relay().await;
+    let client = Client::try_new(config).await.unwrap();
+
+    client.register("dave", "hunter2", None).await.unwrap();
+    let resp = client.login("dave", "hunter2", None, None).await.unwrap();
+    let (mut recv, _send) = client.upgrade_conn(&resp.token).await.unwrap().split();
+
+    client.logout(&resp.token).await.unwrap();
+
+    let res = tokio::time::timeout(Duration::from_secs(5), recv.recv()).await;
+    assert!(matches!(res, Ok(Err(_) | Ok(None))));
+}
+
+#[tokio::test]
+async fn test_relogin_resumes_address() {
+    let (config, _dir) = start_relay().await;
+    let client = Client::try_new(config).await.unwrap();
+
+    client.register("erin", "hunter2", None).await.unwrap();
+    let first = client.login("erin", "hunter2", None, None).await.unwrap();
+    let second = client.login("erin", "hunter2", None, None).await.unwrap();
+
+    assert_eq!(first.address, second.address);
+    assert_ne!(first.token, second.token);
+
+    let res = client.refresh(&first.token).await;
+    assert!(matches!(res, Err(error::Error::InvalidToken)));
+}
+
+#[tokio::test]
+async fn test_reconnecting_resumes_route() {
+    let (config, _dir) = start_relay().await;
+    let client = Client::try_new(config.clone()).await.unwrap();
+
+    let mut logins = Vec::new();
+    for name in ["frank", "grace"] {
+        client.register(name, "hunter2", None).await.unwrap();
+        logins.push(client.login(name, "hunter2", None, None).await.unwrap());
+    }
+    let [frank, grace] = <[_; 2]>::try_from(logins).unwrap();
+
+    let frank_daemon = Client::try_new(config.clone()).await.unwrap();
+    let (_frank_recv, mut frank_send) = frank_daemon
+        .upgrade_conn(&frank.token)
+        .await
+        .unwrap()
+        .split();
+    // grace's daemon goes away without logging out, then comes back with the same token
+    let grace_daemon = Client::try_new(config.clone()).await.unwrap();
+    drop(grace_daemon.upgrade_conn(&grace.token).await.unwrap());
+    drop(grace_daemon);
+    let grace_daemon = Client::try_new(config).await.unwrap();
+    let (mut grace_recv, _grace_send) = grace_daemon
+        .upgrade_conn(&grace.token)
+        .await
+        .unwrap()
+        .split();
+
+    let packet = udp_packet(frank.address, grace.address);
+    let received = send_until_received(&mut frank_send, &packet, &mut grace_recv).await;
+    assert_eq!(received, packet);
+}
+
+#[tokio::test]
+async fn test_requests_share_the_tunnels_connection() {
+    let (config, _dir) = start_relay().await;
+    let alice_daemon = Client::try_new(config.clone()).await.unwrap();
+    let bob_daemon = Client::try_new(config).await.unwrap();
+
+    alice_daemon
+        .register("alice", "hunter2", None)
+        .await
+        .unwrap();
+    bob_daemon.register("bob", "hunter2", None).await.unwrap();
+    let alice = alice_daemon.login("alice", "hunter2", None, None).await;
+    let bob = bob_daemon.login("bob", "hunter2", None, None).await;
+    let (alice, bob) = (alice.unwrap(), bob.unwrap());
+
+    let (_alice_recv, mut alice_send) = alice_daemon
+        .upgrade_conn(&alice.token)
+        .await
+        .unwrap()
+        .split();
+    let (mut bob_recv, _bob_send) = bob_daemon.upgrade_conn(&bob.token).await.unwrap().split();
+
+    // requests keep working next to the tunnel, side by side
+    let (created, registered) = tokio::join!(
+        alice_daemon.create_network(&alice.token, "lan", None),
+        alice_daemon.register("carol", "hunter2", None),
+    );
+    created.unwrap();
+    registered.unwrap();
+
+    let packet = udp_packet(alice.address, bob.address);
+    let received = send_until_received(&mut alice_send, &packet, &mut bob_recv).await;
+    assert_eq!(received, packet);
+}
+
+#[tokio::test]
+async fn test_upgrading_again_ends_the_old_tunnel() {
+    let (config, _dir) = start_relay().await;
+    let client = Client::try_new(config).await.unwrap();
+
+    client.register("judy", "hunter2", None).await.unwrap();
+    let judy = client.login("judy", "hunter2", None, None).await.unwrap();
+    let (mut old_recv, _old_send) = client.upgrade_conn(&judy.token).await.unwrap().split();
+    let (_new_recv, _new_send) = client.upgrade_conn(&judy.token).await.unwrap().split();
+
+    let res = tokio::time::timeout(Duration::from_secs(5), old_recv.recv()).await;
+    assert!(matches!(res, Ok(Err(_) | Ok(None))));
+
+    // the connection itself is still there
+    client.refresh(&judy.token).await.unwrap();
+}
+
+async fn next_event(events: &mut PeerEvents) -> Option<PeerEvent> {
+    tokio::time::timeout(Duration::from_secs(5), events.next())
+        .await
+        .expect("no peer event in time")
+        .unwrap()
+}
+
+/// Same as [`next_event`], skipping the `Joined` of whoever is in the network already
+async fn next_change(events: &mut PeerEvents) -> Option<PeerEvent> {
+    loop {
+        match next_event(events).await {
+            Some(PeerEvent::Joined { .. }) => continue,
+            event => return event,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_watchers_hear_about_peers() {
+    let (config, _dir) = start_relay().await;
+    let alice_client = Client::try_new(config.clone()).await.unwrap();
+    let bob_client = Client::try_new(config.clone()).await.unwrap();
+
+    alice_client
+        .register("alice", "hunter2", None)
+        .await
+        .unwrap();
+    alice_client.register("bob", "hunter2", None).await.unwrap();
+    let alice = alice_client
+        .login("alice", "hunter2", None, None)
+        .await
+        .unwrap();
+    let bob = bob_client
+        .login("bob", "hunter2", None, None)
+        .await
+        .unwrap();
+
+    let _alice_tunnel = alice_client.upgrade_conn(&alice.token).await.unwrap();
+    let mut events = alice_client.watch_peers(&alice.token).await.unwrap();
+
+    let bob_tunnel = bob_client.upgrade_conn(&bob.token).await.unwrap();
+    let joined = PeerEvent::Joined {
+        username: "bob".to_string(),
+        address: bob.address,
+    };
+    assert_eq!(next_event(&mut events).await, Some(joined));
+
+    // a later watcher starts with everyone already there, except itself
+    let mut bobs_events = bob_client.watch_peers(&bob.token).await.unwrap();
+    let alice_joined = PeerEvent::Joined {
+        username: "alice".to_string(),
+        address: alice.address,
+    };
+    assert_eq!(next_event(&mut bobs_events).await, Some(alice_joined));
+
+    bob_client.logout(&bob.token).await.unwrap();
+    let left = PeerEvent::Left {
+        username: "bob".to_string(),
+        address: bob.address,
+    };
+    assert_eq!(next_event(&mut events).await, Some(left));
+    // bob's session is over, and so is watching with it
+    assert_eq!(next_event(&mut bobs_events).await, None);
+    drop(bob_tunnel);
+}
+
+#[tokio::test]
+async fn test_watching_needs_a_session() {
+    let (config, _dir) = start_relay().await;
+    let client = Client::try_new(config).await.unwrap();
+
+    let res = client.watch_peers("not a token").await;
+    assert!(matches!(res, Err(error::Error::InvalidToken)));
+}
+
+/// Routes are added in the background, so keep asking until `count` peers are listed
+async fn list_until(client: &Client, token: &str, count: usize) -> Vec<PeerInfo> {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let peers = client.list_peers(token).await.unwrap();
+            if peers.len() == count {
+                return peers;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("peers did not show up in time")
+}
+
+#[rstest]
+#[case::online(300, PeerState::Online)]
+#[case::idle(0, PeerState::Idle)]
+#[tokio::test]
+async fn test_list_peers(#[case] peer_idle_secs: u64, #[case] state: PeerState) {
+    let (config, _dir) = start_relay_with(|config| config.peer_idle_secs = peer_idle_secs).await;
+    let alice_client = Client::try_new(config.clone()).await.unwrap();
+    let bob_client = Client::try_new(config.clone()).await.unwrap();
+
+    alice_client
+        .register("alice", "hunter2", None)
+        .await
+        .unwrap();
+    alice_client.register("bob", "hunter2", None).await.unwrap();
+    let alice = alice_client
+        .login("alice", "hunter2", None, None)
+        .await
+        .unwrap();
+    let bob = bob_client
+        .login("bob", "hunter2", None, None)
+        .await
+        .unwrap();
+    let _alice_tunnel = alice_client.upgrade_conn(&alice.token).await.unwrap();
+    let _bob_tunnel = bob_client.upgrade_conn(&bob.token).await.unwrap();
+
+    let peers = list_until(&alice_client, &alice.token, 2).await;
+    let listed: Vec<_> = peers
+        .iter()
+        .map(|peer| (peer.username.as_str(), peer.address, peer.state))
+        .collect();
+    assert!(listed.contains(&("alice", alice.address, state)));
+    assert!(listed.contains(&("bob", bob.address, state)));
+    assert!(peers.iter().all(|peer| peer.connected_secs < 60));
+}
+
+#[tokio::test]
+async fn test_list_peers_needs_a_session() {
+    let (config, _dir) = start_relay().await;
+    let client = Client::try_new(config).await.unwrap();
+
+    let res = client.list_peers("not a token").await;
+    assert!(matches!(res, Err(error::Error::InvalidToken)));
+}
+
+#[tokio::test]
+async fn test_disconnect_releases_lease_after_grace() {
+    let (config, _dir) = start_relay_with(|config| config.lease_grace_secs = 0).await;
+    let client = Client::try_new(config).await.unwrap();
+
+    client.register("heidi", "hunter2", None).await.unwrap();
+    client.register("ivan", "hunter2", None).await.unwrap();
+    let heidi = client.login("heidi", "hunter2", None, None).await.unwrap();
+    drop(client.upgrade_conn(&heidi.token).await.unwrap());
+
+    let mut token = heidi.token.clone();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match client.refresh(&token).await {
+                Ok(resp) => token = resp.token,
+                Err(error::Error::InvalidToken) => break,
+                Err(error) => panic!("{error}"),
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let ivan = client.login("ivan", "hunter2", None, None).await.unwrap();
+    assert_eq!(ivan.address, heidi.address);
+}
+
+/// A bare UDP datagram from `source` to `destination`
+fn udp_packet(source: Ipv4Addr, destination: Ipv4Addr) -> Vec<u8> {
+    let builder =
+        etherparse::PacketBuilder::ipv4(source.octets(), destination.octets(), 64).udp(4000, 4000);
+
+    let payload = b"hello";
+    let mut packet = Vec::with_capacity(builder.size(payload.len()));
+    builder.write(&mut packet, payload).unwrap();
+    packet
+}
+
+/// Routes are added in the background, so keep sending `packet` until `to` receives something.
+/// Returns the first packet received.
+async fn send_until_received(
+    from: &mut TunnelSender,
+    packet: &[u8],
+    to: &mut TunnelReceiver,
+) -> Vec<u8> {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            from.send(packet).await.unwrap();
+            tokio::select! {
+                res = to.recv() => break res.unwrap().unwrap().to_vec(),
+                _ = tokio::time::sleep(Duration::from_millis(100)) => (),
+            }
+        }
+    })
+    .await
+    .unwrap()
+}
+
+/// Receives until nothing arrives for a while, returning everything received
+async fn drain(to: &mut TunnelReceiver) -> Vec<Vec<u8>> {
+    let mut received = Vec::new();
+
+    while let Ok(res) = tokio::time::timeout(Duration::from_millis(200), to.recv()).await {
+        match res.unwrap() {
+            Some(packet) => received.push(packet.to_vec()),
+            None => break,
+        }
+    }
+
+    received
+}
+
+/// Whether `to` stays quiet for half a second
+async fn is_silent(to: &mut TunnelReceiver) -> bool {
+    tokio::time::timeout(Duration::from_millis(500), to.recv())
+        .await
+        .is_err()
+}
+
+#[tokio::test]
+async fn test_joining_a_network_takes_an_invite() {
+    let (config, _dir) = start_relay().await;
+    let client = Client::try_new(config).await.unwrap();
+
+    for name in ["alice", "bob"] {
+        client.register(name, "hunter2", None).await.unwrap();
+    }
+    let alice = client.login("alice", "hunter2", None, None).await.unwrap();
+    let bob = client.login("bob", "hunter2", None, None).await.unwrap();
+    client
+        .create_network(&alice.token, "lan", None)
+        .await
+        .unwrap();
+
+    let res = client.join_network(&bob.token, "lan").await;
+    assert!(matches!(res, Err(error::Error::NotInvited)));
+    let res = client.invite_to_network(&bob.token, "lan", "alice").await;
+    assert!(matches!(res, Err(error::Error::NotTheOwner)));
+
+    client
+        .invite_to_network(&alice.token, "lan", "bob")
+        .await
+        .unwrap();
+    client.join_network(&bob.token, "lan").await.unwrap();
+    client
+        .login("bob", "hunter2", Some("lan"), None)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_packets_stay_in_their_network() {
+    let (config, _dir) = start_relay().await;
+    let client = Client::try_new(config.clone()).await.unwrap();
+
+    for name in ["alice", "bob", "carol"] {
+        client.register(name, "hunter2", None).await.unwrap();
+    }
+
+    // alice and bob end up with the same address, but in different networks
+    let alice = client.login("alice", "hunter2", None, None).await.unwrap();
+    client
+        .create_network(&alice.token, "lan", None)
+        .await
+        .unwrap();
+    client.logout(&alice.token).await.unwrap();
+    let alice = client
+        .login("alice", "hunter2", Some("lan"), None)
+        .await
+        .unwrap();
+    let bob = client.login("bob", "hunter2", None, None).await.unwrap();
+    let carol = client.login("carol", "hunter2", None, None).await.unwrap();
+    assert_eq!(alice.address, bob.address);
+
+    let mut tunnels = Vec::new();
+    for login in [&alice, &bob, &carol] {
+        let daemon = Client::try_new(config.clone()).await.unwrap();
+        tunnels.push(daemon.upgrade_conn(&login.token).await.unwrap().split());
+    }
+    let [(mut alice_recv, _alice_send), (mut bob_recv, _bob_send), (_carol_recv, mut carol_send)] =
+        <[_; 3]>::try_from(tunnels).unwrap();
+
+    let packet = udp_packet(carol.address, bob.address);
+    let received = send_until_received(&mut carol_send, &packet, &mut bob_recv).await;
+    assert_eq!(received, packet);
+
+    assert!(
+        is_silent(&mut alice_recv).await,
+        "packet crossed into another network"
+    );
+}
+
+#[tokio::test]
+async fn test_spoofed_packets_are_dropped() {
+    let (config, networks, _dir) = start_watched_relay(|_| ()).await;
+    let client = Client::try_new(config.clone()).await.unwrap();
+
+    let mut peers = Vec::new();
+    for name in ["alice", "bob", "carol"] {
+        client.register(name, "hunter2", None).await.unwrap();
+        let resp = client.login(name, "hunter2", None, None).await.unwrap();
+        // every peer is a daemon of its own, with a connection of its own
+        let daemon = Client::try_new(config.clone()).await.unwrap();
+        let tunnel = daemon.upgrade_conn(&resp.token).await.unwrap();
+        peers.push((resp, tunnel.split()));
+    }
+    let [(alice, (_alice_recv, mut alice_send)), (bob, (mut bob_recv, _bob_send)), (carol, _)] =
+        <[_; 3]>::try_from(peers).unwrap();
+
+    // wait for the routes to be in place, so the spoofed packet is not lost for lack of one
+    let packet = udp_packet(alice.address, bob.address);
+    send_until_received(&mut alice_send, &packet, &mut bob_recv).await;
+    drain(&mut bob_recv).await;
+
+    let spoofed = udp_packet(carol.address, bob.address);
+    alice_send.send(&spoofed).await.unwrap();
+    assert!(is_silent(&mut bob_recv).await, "spoofed packet was routed");
+
+    let stats = networks.peer_stats().await;
+    let alice_stats = stats.iter().find(|peer| peer.ip == alice.address).unwrap();
+    assert_eq!(alice_stats.spoofed, 1);
+}
+
+#[rstest]
+#[case::answered(10, true)]
+#[case::over_the_limit(0, false)]
+#[tokio::test]
+async fn test_unknown_destinations_are_unreachable(#[case] burst: u32, #[case] answered: bool) {
+    let (config, _dir) = start_relay_with(|config| {
+        config.limits.unreachable_per_sec = 1;
+        config.limits.unreachable_burst = burst;
+    })
+    .await;
+    let client = Client::try_new(config).await.unwrap();
+
+    client.register("alice", "hunter2", None).await.unwrap();
+    let alice = client.login("alice", "hunter2", None, None).await.unwrap();
+    let (mut alice_recv, mut alice_send) = client.upgrade_conn(&alice.token).await.unwrap().split();
+
+    let nowhere = Ipv4Addr::new(100, 64, 0, 99);
+    let packet = udp_packet(alice.address, nowhere);
+    alice_send.send(&packet).await.unwrap();
+
+    let res = tokio::time::timeout(Duration::from_millis(500), alice_recv.recv()).await;
+    let Ok(res) = res else {
+        return assert!(!answered, "no host unreachable in time");
+    };
+    assert!(answered, "host unreachable past the limit");
+
+    let reply = res.unwrap().unwrap();
+    let reply = etherparse::SlicedPacket::from_ip(&reply).unwrap();
+    let Some(etherparse::NetSlice::Ipv4(ip)) = reply.net else {
+        panic!("reply is not ipv4");
+    };
+    // errors come from the relay's own address
+    assert_eq!(Some(ip.header().source_addr()), alice.gateway);
+
+    let Some(etherparse::TransportSlice::Icmpv4(icmp)) = reply.transport else {
+        panic!("reply is not icmp");
+    };
+    assert_eq!(
+        icmp.icmp_type(),
+        etherparse::Icmpv4Type::DestinationUnreachable(
+            etherparse::icmpv4::DestUnreachableHeader::Host
+        )
+    );
+    assert_eq!(icmp.payload(), packet);
+}
+
+#[tokio::test]
+async fn test_gateway_answers_ping() {
+    let (config, _dir) = start_relay().await;
+    let client = Client::try_new(config).await.unwrap();
+
+    client.register("alice", "hunter2", None).await.unwrap();
+    let alice = client.login("alice", "hunter2", None, None).await.unwrap();
+    let gateway = alice.gateway.unwrap();
+    let (mut alice_recv, mut alice_send) = client.upgrade_conn(&alice.token).await.unwrap().split();
+
+    let builder = etherparse::PacketBuilder::ipv4(alice.address.octets(), gateway.octets(), 64)
+        .icmpv4_echo_request(1, 1);
+    let mut ping = Vec::new();
+    builder.write(&mut ping, b"ping").unwrap();
+
+    let reply = send_until_received(&mut alice_send, &ping, &mut alice_recv).await;
+    let reply = etherparse::SlicedPacket::from_ip(&reply).unwrap();
+    let Some(etherparse::NetSlice::Ipv4(ip)) = reply.net else {
+        panic!("reply is not ipv4");
+    };
+    assert_eq!(ip.header().source_addr(), gateway);
+    assert_eq!(ip.header().destination_addr(), alice.address);
+
+    let Some(etherparse::TransportSlice::Icmpv4(icmp)) = reply.transport else {
+        panic!("reply is not icmp");
+    };
+    assert!(matches!(
+        icmp.icmp_type(),
+        etherparse::Icmpv4Type::EchoReply(echo) if echo.id == 1 && echo.seq == 1
+    ));
+    assert_eq!(icmp.payload(), b"ping");
+
+    // anything but a ping goes nowhere
+    alice_send
+        .send(&udp_packet(alice.address, gateway))
+        .await
+        .unwrap();
+    assert!(is_silent(&mut alice_recv).await);
+}
+
+#[tokio::test]
+async fn test_broadcasts_reach_the_whole_network() {
+    let (config, _dir) = start_relay().await;
+    let client = Client::try_new(config.clone()).await.unwrap();
+
+    let mut peers = Vec::new();
+    for name in ["alice", "bob", "carol"] {
+        client.register(name, "hunter2", None).await.unwrap();
+        let resp = client.login(name, "hunter2", None, None).await.unwrap();
+        // every peer is a daemon of its own, with a connection of its own
+        let daemon = Client::try_new(config.clone()).await.unwrap();
+        let tunnel = daemon.upgrade_conn(&resp.token).await.unwrap();
+        peers.push((resp, tunnel.split()));
+    }
+
+    client.register("dave", "hunter2", None).await.unwrap();
+    let dave = client.login("dave", "hunter2", None, None).await.unwrap();
+    client
+        .create_network(&dave.token, "lan", None)
+        .await
+        .unwrap();
+    let dave = client
+        .login("dave", "hunter2", Some("lan"), None)
+        .await
+        .unwrap();
+    let daemon = Client::try_new(config.clone()).await.unwrap();
+    let (mut dave_recv, _dave_send) = daemon.upgrade_conn(&dave.token).await.unwrap().split();
+
+    // erin is in the network, but does not want its broadcasts
+    client.register("erin", "hunter2", None).await.unwrap();
+    let erin = client.login("erin", "hunter2", None, None).await.unwrap();
+    let daemon = Client::try_new(ClientConfig {
+        broadcast: false,
+        ..config
+    })
+    .await
+    .unwrap();
+    let (mut erin_recv, _erin_send) = daemon.upgrade_conn(&erin.token).await.unwrap().split();
+
+    let [(alice, (mut alice_recv, mut alice_send)), (_, (mut bob_recv, _bob_send)), (_, (mut carol_recv, _carol_send))] =
+        <[_; 3]>::try_from(peers).unwrap();
+    let subnet = Config::default().subnet;
+
+    // erin's route is in place, so missing out on broadcasts is down to her alone
+    let packet = udp_packet(alice.address, erin.address);
+    send_until_received(&mut alice_send, &packet, &mut erin_recv).await;
+    drain(&mut erin_recv).await;
+
+    for destination in [
+        Ipv4Addr::BROADCAST,
+        subnet.broadcast(),
+        Ipv4Addr::new(224, 0, 0, 251),
+    ] {
+        let packet = udp_packet(alice.address, destination);
+        let received = send_until_received(&mut alice_send, &packet, &mut bob_recv).await;
+        assert_eq!(received, packet);
+
+        // every retry reached carol as well
+        let received = drain(&mut carol_recv).await;
+        assert!(!received.is_empty());
+        assert!(received.iter().all(|received| *received == packet));
+        drain(&mut bob_recv).await;
+    }
+
+    assert!(
+        is_silent(&mut alice_recv).await,
+        "broadcast echoed back to its sender"
+    );
+    assert!(
+        is_silent(&mut dave_recv).await,
+        "broadcast crossed into another network"
+    );
+    assert!(
+        is_silent(&mut erin_recv).await,
+        "broadcast reached a peer that did not ask for them"
+    );
+}
+
+#[rstest]
+#[case::relay_without(false, true)]
+#[case::client_without(true, false)]
+#[tokio::test]
+async fn test_streams_carry_packets_without_datagrams(
+    #[case] relay_datagrams: bool,
+    #[case] client_datagrams: bool,
+) {
+    let (config, _dir) = start_relay_with(|config| config.datagrams = relay_datagrams).await;
+    let config = ClientConfig {
+        datagrams: client_datagrams,
+        ..config
+    };
+    let client = Client::try_new(config.clone()).await.unwrap();
+
+    let mut peers = Vec::new();
+    for name in ["alice", "bob"] {
+        client.register(name, "hunter2", None).await.unwrap();
+        let resp = client.login(name, "hunter2", None, None).await.unwrap();
+        // every peer is a daemon of its own, with a connection of its own
+        let daemon = Client::try_new(config.clone()).await.unwrap();
+        let tunnel = daemon.upgrade_conn(&resp.token).await.unwrap();
+        peers.push((resp, tunnel.split()));
+    }
+
+    let [(alice, (_alice_recv, mut alice_send)), (bob, (mut bob_recv, _bob_send))] =
+        <[_; 2]>::try_from(peers).unwrap();
+
+    let packet = udp_packet(alice.address, bob.address);
+    let received = send_until_received(&mut alice_send, &packet, &mut bob_recv).await;
+    assert_eq!(received, packet);
+
+    // retries queued up on the stream come out one packet at a time, not glued together
+    let received = drain(&mut bob_recv).await;
+    assert!(received.iter().all(|received| *received == packet));
+}
+
+#[tokio::test]
+async fn test_admin_socket_manages_the_relay() {
+    let (config, dir) = start_relay().await;
+    let client = Client::try_new(config.clone()).await.unwrap();
+
+    let mut peers = Vec::new();
+    for name in ["alice", "bob", "carol"] {
+        client.register(name, "hunter2", None).await.unwrap();
+        let resp = client.login(name, "hunter2", None, None).await.unwrap();
+        let daemon = Client::try_new(config.clone()).await.unwrap();
+        let tunnel = daemon.upgrade_conn(&resp.token).await.unwrap();
+        peers.push((resp, daemon, tunnel.split()));
+    }
+    let [(alice, alice_daemon, (_alice_recv, mut alice_send)), (bob, _bob_daemon, (mut bob_recv, _bob_send)), (carol, _carol_daemon, (mut carol_recv, _carol_send))] =
+        <[_; 3]>::try_from(peers).unwrap();
+    let mut events = alice_daemon.watch_peers(&alice.token).await.unwrap();
+    list_until(&client, &alice.token, 3).await;
+
+    let packet = udp_packet(alice.address, bob.address);
+    send_until_received(&mut alice_send, &packet, &mut bob_recv).await;
+
+    let mut admin = AdminClient::connect(&dir.path().join("admin.sock"))
+        .await
+        .unwrap();
+    let routes = admin.routes().await.unwrap();
+    let usernames: Vec<_> = routes.iter().map(|route| route.username.as_str()).collect();
+    assert_eq!(usernames, ["alice", "bob", "carol"]);
+    assert!(routes[0].received_packets > 0 && routes[0].received_bytes > 0);
+    assert!(routes[1].sent_packets > 0 && routes[1].sent_bytes > 0);
+
+    // whoever is watching hears about the move, the session itself stays
+    let to = Ipv4Addr::new(100, 64, 0, 42);
+    let moved = admin.reassign("bob", None, to).await.unwrap();
+    assert_eq!(moved.len(), 1);
+    let event = next_change(&mut events).await;
+    let expected = PeerEvent::Moved {
+        username: "bob".to_string(),
+        from: bob.address,
+        to,
+    };
+    assert_eq!(event, Some(expected));
+    client.refresh(&bob.token).await.unwrap();
+
+    // kicking ends the session, and the tunnel with it
+    assert_eq!(admin.kick("carol").await.unwrap().len(), 1);
+    let res = tokio::time::timeout(Duration::from_secs(5), carol_recv.recv()).await;
+    assert!(matches!(res, Ok(Err(_) | Ok(None))));
+    let left = PeerEvent::Left {
+        username: "carol".to_string(),
+        address: carol.address,
+    };
+    assert_eq!(next_event(&mut events).await, Some(left));
+    let res = client.refresh(&carol.token).await;
+    assert!(matches!(res, Err(error::Error::InvalidToken)));
+
+    let res = admin.kick("mallory").await;
+    assert!(matches!(res, Err(error::Error::AdminRefused(_))));
+}
+
+#[tokio::test]
+async fn test_reassigned_peers_stay_reachable() {
+    let (config, dir) = start_relay_with(|config| config.lease_grace_secs = 1).await;
+    let client = Client::try_new(config.clone()).await.unwrap();
+
+    let mut peers = Vec::new();
+    for name in ["alice", "bob"] {
+        client.register(name, "hunter2", None).await.unwrap();
+        let resp = client.login(name, "hunter2", None, None).await.unwrap();
+        let daemon = Client::try_new(config.clone()).await.unwrap();
+        let tunnel = daemon.upgrade_conn(&resp.token).await.unwrap();
+        let events = daemon.watch_peers(&resp.token).await.unwrap();
+        peers.push((resp, daemon, tunnel.split(), events));
+    }
+    let [(alice, _alice_daemon, (mut alice_recv, mut alice_send), mut alice_events), (bob, bob_daemon, (mut bob_recv, mut bob_send), mut bob_events)] =
+        <[_; 2]>::try_from(peers).unwrap();
+    list_until(&client, &alice.token, 2).await;
+
+    // the peer that moved hears about it too, that is how it learns its new address
+    let mut admin = AdminClient::connect(&dir.path().join("admin.sock"))
+        .await
+        .unwrap();
+    let to = Ipv4Addr::new(100, 64, 0, 42);
+    admin.reassign("bob", None, to).await.unwrap();
+    let moved = PeerEvent::Moved {
+        username: "bob".to_string(),
+        from: bob.address,
+        to,
+    };
+    assert_eq!(next_change(&mut alice_events).await, Some(moved.clone()));
+    assert_eq!(next_change(&mut bob_events).await, Some(moved));
+
+    // well past the grace period, bob is still there at his new address
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let packet = udp_packet(alice.address, to);
+    send_until_received(&mut alice_send, &packet, &mut bob_recv).await;
+    let packet = udp_packet(to, alice.address);
+    send_until_received(&mut bob_send, &packet, &mut alice_recv).await;
+    client.refresh(&bob.token).await.unwrap();
+    let peers = list_until(&client, &alice.token, 2).await;
+    assert!(peers.iter().any(|peer| peer.address == to));
+
+    // and when he does go, he leaves from there
+    drop((bob_daemon, bob_recv, bob_send, bob_events));
+    let left = PeerEvent::Left {
+        username: "bob".to_string(),
+        address: to,
+    };
+    assert_eq!(next_change(&mut alice_events).await, Some(left));
+}
+
+/// Sends a bare HTTP request for `path`, returning the whole response
+async fn http_get(addr: SocketAddr, path: &str) -> String {
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_metrics_are_served() {
+    let (config, mut server, _dir) = start_server(|config| {
+        config.metrics_addr = Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+    })
+    .await;
+    let metrics_addr = server.metrics_addr().unwrap();
+    tokio::spawn(async move { server.accept().await });
+    let client = Client::try_new(config.clone()).await.unwrap();
+
+    let mut peers = Vec::new();
+    for name in ["alice", "bob"] {
+        client.register(name, "hunter2", None).await.unwrap();
+        let resp = client.login(name, "hunter2", None, None).await.unwrap();
+        let daemon = Client::try_new(config.clone()).await.unwrap();
+        let tunnel = daemon.upgrade_conn(&resp.token).await.unwrap();
+        peers.push((resp, tunnel.split()));
+    }
+    let res = client.login("alice", "wrong", None, None).await;
+    assert!(matches!(res, Err(error::Error::InvalidCredentials)));
+    let [(alice, (_alice_recv, mut alice_send)), (bob, (mut bob_recv, _bob_send))] =
+        <[_; 2]>::try_from(peers).unwrap();
+
+    // packets from a peer are handled in order, so once bob has his, the first ones are counted
+    let nowhere = Ipv4Addr::new(100, 64, 0, 99);
+    alice_send
+        .send(&udp_packet(alice.address, nowhere))
+        .await
+        .unwrap();
+    let gateway = alice.gateway.unwrap();
+    alice_send
+        .send(&udp_packet(alice.address, gateway))
+        .await
+        .unwrap();
+    let packet = udp_packet(alice.address, bob.address);
+    send_until_received(&mut alice_send, &packet, &mut bob_recv).await;
+
+    let response = http_get(metrics_addr, "/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    for line in [
+        "lanshare_logins_total 2".to_string(),
+        "lanshare_failed_logins_total 1".to_string(),
+        "lanshare_dropped_packets_total{reason=\"no_route\"} 1".to_string(),
+        "lanshare_dropped_packets_total{reason=\"gateway\"} 1".to_string(),
+        "lanshare_peers{network=\"default\"} 2".to_string(),
+        format!(
+            "lanshare_peer_sent_packets_total{{network=\"default\",address=\"{}\",username=\"bob\"}}",
+            bob.address
+        ),
+    ] {
+        assert!(response.contains(&line), "{line} missing from {response}");
+    }
+    assert!(response.contains("lanshare_packet_routing_seconds_count"));
+
+    let response = http_get(metrics_addr, "/").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}